@@ -0,0 +1,101 @@
+use crate::{config::Captcha, utils::get_specific_error};
+use axum::response::Response;
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+
+// Cloudflare Turnstile, hCaptcha answers the same form at
+// https://api.hcaptcha.com/siteverify
+pub const DEFAULT_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+#[derive(Deserialize)]
+struct SiteverifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+fn verdict(response: SiteverifyResponse) -> Result<(), String> {
+    if response.success {
+        Ok(())
+    } else if response.error_codes.is_empty() {
+        Err("captcha verification failed".to_string())
+    } else {
+        Err(format!(
+            "captcha verification failed: {}",
+            response.error_codes.join(", ")
+        ))
+    }
+}
+
+// Checks the captcha token of a subscribe request when captcha.enabled is
+// set, 400 for a missing or refused token and 500 when the provider is down
+pub async fn check(
+    client: &reqwest::Client,
+    conf: Option<&Captcha>,
+    token: Option<&str>,
+) -> Result<(), Response> {
+    let Some(conf) = conf.filter(|conf| conf.enabled) else {
+        return Ok(());
+    };
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return Err(get_specific_error(
+            StatusCode::BAD_REQUEST,
+            "captcha_token is required".to_string(),
+        ));
+    };
+
+    let url = conf.verify_url.as_deref().unwrap_or(DEFAULT_VERIFY_URL);
+    let response = client
+        .post(url)
+        .form(&[("secret", conf.secret.as_str()), ("response", token)])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let body = match response {
+        Ok(response) => response.json::<SiteverifyResponse>().await,
+        Err(e) => Err(e),
+    };
+    match body {
+        Ok(body) => verdict(body).map_err(|e| get_specific_error(StatusCode::BAD_REQUEST, e)),
+        Err(e) => Err(get_specific_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to verify the captcha: {}", e),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod captcha_tests {
+    use super::{check, verdict, SiteverifyResponse};
+    use crate::config::Captcha;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_verdict() {
+        let parse = |body: &str| serde_json::from_str::<SiteverifyResponse>(body).unwrap();
+        assert_eq!(verdict(parse(r#"{"success": true}"#)), Ok(()));
+        assert_eq!(
+            verdict(parse(
+                r#"{"success": false, "error-codes": ["invalid-input-response"]}"#
+            )),
+            Err("captcha verification failed: invalid-input-response".to_string())
+        );
+        assert!(verdict(parse(r#"{"success": false}"#)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_only_when_enabled() {
+        let mut conf = Captcha {
+            enabled: false,
+            secret: "secret".to_string(),
+            verify_url: None,
+        };
+        let client = reqwest::Client::new();
+        assert!(check(&client, None, None).await.is_ok());
+        assert!(check(&client, Some(&conf), None).await.is_ok());
+
+        conf.enabled = true;
+        let response = check(&client, Some(&conf), Some("")).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,18 +1,33 @@
 use serde::{self, Deserialize};
 use std::env;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 
-pub_struct!(Clone, Deserialize; Server { port: u16 });
+pub_struct!(Clone, Deserialize; Server {
+    host: Option<String>,
+    port: u16,
+    shutdown_timeout_secs: Option<u64>,
+    max_body_bytes: Option<usize>,
+    allowed_origins: Option<Vec<String>>,
+    requests_per_minute: Option<u32>,
+    rate_limit_clients: Option<usize>,
+    request_timeout_secs: Option<u64>,
+    maintenance: Option<bool>,
+    maintenance_retry_after_secs: Option<u64>,
+});
 
 pub_struct!(Clone, Deserialize; Database {
     name: String,
     connection_string: String,
+    connect_attempts: Option<u32>,
+    connect_delay_ms: Option<u64>,
 });
 
 pub_struct!(Clone, Deserialize; Email {
     base_url : String,
     api_key: String,
     ar_group_id : String,
+    queue_subscribers: Option<bool>,
 });
 
 pub_struct!(Clone, Deserialize; WatchtowerTypes {
@@ -27,15 +42,214 @@ pub_struct!(Clone, Deserialize; Watchtower {
     app_id: String,
     token: String,
     types: WatchtowerTypes,
+    format: Option<String>,
+    min_level: Option<String>,
+});
+
+pub_struct!(Clone, Deserialize; Admin {
+    token: String,
+    runs_collection: Option<String>,
+});
+
+pub_struct!(Clone, Deserialize; Starknet {
+    rpc_url: String,
 });
 
+pub_struct!(Clone, Deserialize; Tax {
+    states: Vec<String>,
+    optional: Option<bool>,
+});
+
+pub_struct!(Clone, Deserialize; Newsletter {
+    confirm_url: String,
+    confirm_group_id: String,
+    token_ttl_hours: Option<u64>,
+});
+
+pub_struct!(Clone, Deserialize; Unsubscribe {
+    secret: String,
+});
+
+pub_struct!(Clone, Deserialize; SaleActions {
+    trigger_url: String,
+    trigger_token: String,
+});
+
+pub_struct!(Clone, Deserialize; EmailEvents {
+    signing_key: String,
+    max_age_secs: Option<u64>,
+});
+
+pub_struct!(Clone, Deserialize; Captcha {
+    enabled: bool,
+    secret: String,
+    verify_url: Option<String>,
+});
+
+// Loaded from the toml file given as first argument (config.toml by default),
+// then every APP__<SECTION>__<KEY> environment variable overrides the key it
+// names, e.g. APP__EMAIL__API_KEY or APP__SERVER__PORT=8081, so secrets
+// can be injected without writing them to the file
 pub_struct!(Clone, Deserialize;  Config {
     server: Server,
     database: Database,
     watchtower: Watchtower,
     email: Email,
+    admin: Admin,
+    starknet: Starknet,
+    tax: Option<Tax>,
+    newsletter: Option<Newsletter>,
+    unsubscribe: Option<Unsubscribe>,
+    captcha: Option<Captcha>,
+    email_events: Option<EmailEvents>,
+    sale_actions: Option<SaleActions>,
 });
 
+impl Server {
+    // Address the server listens on, every interface unless server.host is set
+    pub fn bind_addr(&self) -> Result<SocketAddr, String> {
+        let host = self.host.as_deref().unwrap_or("0.0.0.0");
+        host.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .map_err(|_| format!("server.host must be an IP address, got \"{}\"", host))
+    }
+}
+
+// Collects the settings that would only fail once the service is running
+impl Config {
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.server.bind_addr() {
+            problems.push(e);
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must be set".to_string());
+        }
+        if self.server.max_body_bytes == Some(0) {
+            problems.push("server.max_body_bytes must be at least 1".to_string());
+        }
+        if self.server.requests_per_minute == Some(0) {
+            problems.push("server.requests_per_minute must be at least 1".to_string());
+        }
+        if self.server.rate_limit_clients == Some(0) {
+            problems.push("server.rate_limit_clients must be at least 1".to_string());
+        }
+        if self.server.request_timeout_secs == Some(0) {
+            problems.push("server.request_timeout_secs must be at least 1".to_string());
+        }
+        if self.server.maintenance_retry_after_secs == Some(0) {
+            problems.push("server.maintenance_retry_after_secs must be at least 1".to_string());
+        }
+
+        check_not_empty(&mut problems, "database.name", &self.database.name);
+        if self.database.connect_attempts == Some(0) {
+            problems.push("database.connect_attempts must be at least 1".to_string());
+        }
+        check_not_empty(
+            &mut problems,
+            "database.connection_string",
+            &self.database.connection_string,
+        );
+
+        check_url(&mut problems, "email.base_url", &self.email.base_url);
+        check_not_empty(&mut problems, "email.api_key", &self.email.api_key);
+        check_not_empty(&mut problems, "email.ar_group_id", &self.email.ar_group_id);
+        check_url(&mut problems, "starknet.rpc_url", &self.starknet.rpc_url);
+
+        if let Some(newsletter) = &self.newsletter {
+            check_url(
+                &mut problems,
+                "newsletter.confirm_url",
+                &newsletter.confirm_url,
+            );
+            check_not_empty(
+                &mut problems,
+                "newsletter.confirm_group_id",
+                &newsletter.confirm_group_id,
+            );
+            if newsletter.token_ttl_hours == Some(0) {
+                problems.push("newsletter.token_ttl_hours must be at least 1".to_string());
+            }
+        }
+        if let Some(unsubscribe) = &self.unsubscribe {
+            check_not_empty(&mut problems, "unsubscribe.secret", &unsubscribe.secret);
+        }
+        if let Some(sale_actions) = &self.sale_actions {
+            check_url(
+                &mut problems,
+                "sale_actions.trigger_url",
+                &sale_actions.trigger_url,
+            );
+            check_not_empty(
+                &mut problems,
+                "sale_actions.trigger_token",
+                &sale_actions.trigger_token,
+            );
+        }
+        if let Some(email_events) = &self.email_events {
+            check_not_empty(
+                &mut problems,
+                "email_events.signing_key",
+                &email_events.signing_key,
+            );
+            if email_events.max_age_secs == Some(0) {
+                problems.push("email_events.max_age_secs must be at least 1".to_string());
+            }
+        }
+        if let Some(captcha) = self.captcha.as_ref().filter(|captcha| captcha.enabled) {
+            check_not_empty(&mut problems, "captcha.secret", &captcha.secret);
+            if let Some(verify_url) = &captcha.verify_url {
+                check_url(&mut problems, "captcha.verify_url", verify_url);
+            }
+        }
+
+        if self.watchtower.enabled {
+            check_url(
+                &mut problems,
+                "watchtower.endpoint",
+                &self.watchtower.endpoint,
+            );
+        }
+        if let Some(format) = self.watchtower.format.as_deref() {
+            if format != "text" && format != "json" {
+                problems.push(format!(
+                    "watchtower.format must be text or json, got \"{}\"",
+                    format
+                ));
+            }
+        }
+        if let Some(level) = self.watchtower.min_level.as_deref() {
+            if !crate::logger::LEVELS.contains(&level) {
+                problems.push(format!(
+                    "watchtower.min_level must be one of {}, got \"{}\"",
+                    crate::logger::LEVELS.join(", "),
+                    level
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+fn check_not_empty(problems: &mut Vec<String>, key: &str, value: &str) {
+    if value.trim().is_empty() {
+        problems.push(format!("{} is required", key));
+    }
+}
+
+fn check_url(problems: &mut Vec<String>, key: &str, value: &str) {
+    match reqwest::Url::parse(value) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+        Ok(_) => problems.push(format!("{} must be an http(s) url, got \"{}\"", key, value)),
+        Err(e) => problems.push(format!("{} is not a valid url ({}): \"{}\"", key, e, value)),
+    }
+}
+
 pub fn load() -> Config {
     let args: Vec<String> = env::args().collect();
     let config_path = if args.len() <= 1 {
@@ -48,10 +262,177 @@ pub fn load() -> Config {
         panic!("error: unable to read file with path \"{}\"", config_path);
     }
 
-    match toml::from_str(file_contents.unwrap().as_str()) {
+    let mut value: toml::Value = match toml::from_str(file_contents.unwrap().as_str()) {
+        Ok(value) => value,
+        Err(err) => {
+            panic!("error: unable to parse config. {}", err);
+        }
+    };
+    if let Err(err) = apply_env_overrides(&mut value, env::vars()) {
+        panic!("error: invalid config override. {}", err);
+    }
+    match value.try_into() {
         Ok(loaded) => loaded,
         Err(err) => {
             panic!("error: unable to deserialize config. {}", err);
         }
     }
 }
+
+const ENV_PREFIX: &str = "APP__";
+
+// Sets the keys named by the APP__ variables, a value keeps the type of the
+// key it replaces and keys missing from the file are read as toml values,
+// falling back to strings
+fn apply_env_overrides(
+    config: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), String> {
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = path.split("__").map(|key| key.to_lowercase()).collect();
+        if keys.iter().any(|key| key.is_empty()) {
+            return Err(format!("{}: empty key", name));
+        }
+
+        let (last, sections) = keys.split_last().unwrap();
+        let mut table = config
+            .as_table_mut()
+            .ok_or_else(|| "config is not a table".to_string())?;
+        for section in sections {
+            table = table
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| format!("{}: {} is not a section", name, section))?;
+        }
+        let value =
+            parse_env_value(table.get(last), &raw).map_err(|e| format!("{}: {}", name, e))?;
+        table.insert(last.clone(), value);
+    }
+    Ok(())
+}
+
+fn parse_env_value(current: Option<&toml::Value>, raw: &str) -> Result<toml::Value, String> {
+    use toml::Value;
+    let inline = || {
+        toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+    };
+    match current {
+        Some(Value::String(_)) => Ok(Value::String(raw.to_string())),
+        Some(Value::Integer(_)) => raw
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("expected an integer, got \"{}\"", raw)),
+        Some(Value::Float(_)) => raw
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| format!("expected a number, got \"{}\"", raw)),
+        Some(Value::Boolean(_)) => raw
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("expected true or false, got \"{}\"", raw)),
+        Some(_) => inline().ok_or_else(|| format!("expected a toml value, got \"{}\"", raw)),
+        None => Ok(inline().unwrap_or_else(|| Value::String(raw.to_string()))),
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::{apply_env_overrides, Config};
+
+    fn template() -> Config {
+        toml::from_str(include_str!("../config.template.toml")).unwrap()
+    }
+
+    #[test]
+    fn test_template_is_valid() {
+        assert_eq!(template().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut conf = template();
+        conf.server.port = 0;
+        conf.email.base_url = String::new();
+        conf.starknet.rpc_url = "ftp://node.example".to_string();
+        let problems = conf.validate().unwrap_err();
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], "server.port must be set");
+        assert!(problems[1].starts_with("email.base_url is not a valid url"));
+        assert_eq!(
+            problems[2],
+            "starknet.rpc_url must be an http(s) url, got \"ftp://node.example\""
+        );
+    }
+
+    #[test]
+    fn test_bind_addr() {
+        let mut conf = template();
+        assert_eq!(conf.server.bind_addr().unwrap().to_string(), "0.0.0.0:8080");
+        conf.server.host = Some("127.0.0.1".to_string());
+        assert_eq!(
+            conf.server.bind_addr().unwrap().to_string(),
+            "127.0.0.1:8080"
+        );
+        conf.server.host = Some("::1".to_string());
+        assert_eq!(conf.server.bind_addr().unwrap().to_string(), "[::1]:8080");
+        conf.server.host = Some("localhost".to_string());
+        assert_eq!(
+            conf.validate().unwrap_err(),
+            vec!["server.host must be an IP address, got \"localhost\"".to_string()]
+        );
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut value =
+            toml::from_str::<toml::Value>(include_str!("../config.template.toml")).unwrap();
+        apply_env_overrides(
+            &mut value,
+            vars(&[
+                ("APP__EMAIL__API_KEY", "env-key"),
+                ("APP__DATABASE__CONNECTION_STRING", "mongodb://db:27017"),
+                ("APP__WATCHTOWER__ENABLED", "false"),
+                ("APP__WATCHTOWER__TYPES__INFO", "mainnet/info"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+        let conf: Config = value.try_into().unwrap();
+        assert_eq!(conf.email.api_key, "env-key");
+        assert_eq!(conf.database.connection_string, "mongodb://db:27017");
+        assert!(!conf.watchtower.enabled);
+        assert_eq!(conf.watchtower.types.info, "mainnet/info");
+    }
+
+    #[test]
+    fn test_env_overrides_keep_types() {
+        let mut value =
+            toml::from_str::<toml::Value>(include_str!("../config.template.toml")).unwrap();
+        // a numeric secret stays a string
+        apply_env_overrides(&mut value, vars(&[("APP__EMAIL__API_KEY", "12345")])).unwrap();
+        assert_eq!(value["email"]["api_key"].as_str(), Some("12345"));
+
+        let err = apply_env_overrides(&mut value, vars(&[("APP__WATCHTOWER__ENABLED", "yes")]))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "APP__WATCHTOWER__ENABLED: expected true or false, got \"yes\""
+        );
+        let err =
+            apply_env_overrides(&mut value, vars(&[("APP__EMAIL__API_KEY__X", "1")])).unwrap_err();
+        assert_eq!(err, "APP__EMAIL__API_KEY__X: api_key is not a section");
+    }
+}
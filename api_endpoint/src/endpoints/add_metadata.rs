@@ -1,62 +1,541 @@
 use std::sync::Arc;
 
 use crate::{
+    config::Tax,
+    extract::ValidJson,
     models::AppState,
-    utils::{get_error, get_specific_error},
+    utils::{
+        check_email_length, compute_meta_hash, fetch_public_key, get_error, is_valid_domain,
+        meta_hash_hex,
+    },
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use email_address::EmailAddress;
+use mongodb::{
+    bson::{doc, Document},
+    error::{ErrorKind, WriteFailure},
+    options::UpdateOptions,
 };
-use axum::{extract::State, response::IntoResponse, Json};
 use reqwest::StatusCode;
 use serde_derive::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use starknet::core::{
+    crypto::{ecdsa_verify, Signature},
+    types::FieldElement,
+};
 
+// Body of /add_metadata, payer and signature authenticate the request and are
+// not stored
 #[derive(Serialize, Deserialize)]
-pub struct AddMetadata {
+pub struct AddMetadataRequest {
     meta_hash: String,
     email: String,
     tax_state: String,
     salt: String,
+    // domain the metadata is submitted for, optional for older clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    #[serde(skip_serializing)]
+    payer: FieldElement,
+    #[serde(skip_serializing)]
+    signature: (FieldElement, FieldElement),
+}
+
+// Checks that (r, s) signs the meta_hash with the given public key
+fn verify_meta_hash_signature(
+    public_key: &FieldElement,
+    meta_hash: &str,
+    signature: &(FieldElement, FieldElement),
+) -> bool {
+    let Ok(message) = FieldElement::from_hex_be(meta_hash) else {
+        return false;
+    };
+    let (r, s) = *signature;
+    ecdsa_verify(public_key, &message, &Signature { r, s }).unwrap_or(false)
+}
+
+// tax_state must be one of the configured region codes, empty and "none" are
+// accepted when the tax state is optional
+fn check_tax_state(tax_state: &str, conf: Option<&Tax>) -> Result<(), String> {
+    let Some(conf) = conf else {
+        return Ok(());
+    };
+    if tax_state.is_empty() || tax_state.eq_ignore_ascii_case("none") {
+        return if conf.optional.unwrap_or(false) {
+            Ok(())
+        } else {
+            Err("tax_state is required".to_string())
+        };
+    }
+    if conf
+        .states
+        .iter()
+        .any(|state| state.eq_ignore_ascii_case(tax_state))
+    {
+        Ok(())
+    } else {
+        Err(format!("unknown tax_state: {}", tax_state))
+    }
+}
+
+// 64 bits, a shorter salt lets the meta_hash of a known email be brute forced
+pub const MIN_SALT_HEX_DIGITS: usize = 16;
+
+// The salt is hashed as sent, it must be a hex string of at least
+// MIN_SALT_HEX_DIGITS digits, with or without the 0x prefix
+fn check_salt(salt: &str) -> Result<(), String> {
+    if salt.is_empty() {
+        return Err("salt is required".to_string());
+    }
+    let digits = salt
+        .strip_prefix("0x")
+        .or_else(|| salt.strip_prefix("0X"))
+        .unwrap_or(salt);
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("salt must be a hex string".to_string());
+    }
+    if digits.len() < MIN_SALT_HEX_DIGITS {
+        return Err(format!(
+            "salt must have at least {} hex digits",
+            MIN_SALT_HEX_DIGITS
+        ));
+    }
+    Ok(())
 }
 
-fn compute_metadata_hash(email: &str, tax_state: &str, salt: &str) -> String {
-    let separator = "|";
-    let data = format!("{}{}{}{}{}", email, separator, tax_state.replace("|", ""), separator, salt);
+// A salt stored with another email would let the two submissions be linked
+async fn check_salt_unused(state: &AppState, query: &AddMetadataRequest) -> Result<(), Rejection> {
+    let reused = state
+        .db
+        .collection::<Document>("metadata")
+        .find_one(
+            doc! { "salt": &query.salt, "email": { "$ne": &query.email } },
+            None,
+        )
+        .await
+        .map_err(|err| {
+            Rejection::Status(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to check the salt: {}", err),
+            )
+        })?;
+    match reused {
+        Some(_) => Err(Rejection::Status(
+            StatusCode::BAD_REQUEST,
+            "salt already used".to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Upsert keyed on meta_hash so a retried submission leaves the stored
+// metadata untouched instead of inserting it twice
+fn metadata_upsert(document: Document) -> (Document, Document) {
+    let filter = doc! { "meta_hash": document.get_str("meta_hash").unwrap_or_default() };
+    (filter, doc! { "$setOnInsert": document })
+}
 
-    let mut hasher = Sha256::new();
-    hasher.update(data.as_bytes());
-    let result = hasher.finalize();
-    let hash_hex = hex::encode(result);
+pub const DUPLICATE_KEY: i32 = 11000;
 
-    // Truncate the last two characters (8 bits) to make it a 248-bit hash so it fits in a felt
-    let truncated_hash_hex = &hash_hex[0..hash_hex.len() - 2];
-    truncated_hash_hex.to_string()
+// Two concurrent upserts of the same meta_hash may both try to insert, the
+// unique index rejects the second one
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
 }
 
+// Answered with 201 when the metadata is inserted and 200 when it was
+// already stored, a retried submission is not an error
 #[derive(Serialize)]
 pub struct Output {
-    success: bool,
+    meta_hash: String,
+    stored: bool,
+}
+
+// Body of every refused submission
+#[derive(Serialize)]
+pub struct ErrorOutput {
+    error: String,
+}
+
+// Why a submission is refused, with the status answered for it
+pub enum Rejection {
+    InvalidEmail(String),
+    Status(StatusCode, String),
+}
+
+impl Rejection {
+    pub fn message(&self) -> String {
+        match self {
+            Rejection::InvalidEmail(email) => format!("invalid email address: {}", email),
+            Rejection::Status(_, message) => message.clone(),
+        }
+    }
+
+    pub fn is_invalid_email(&self) -> bool {
+        matches!(self, Rejection::InvalidEmail(_))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Rejection::InvalidEmail(_) => StatusCode::BAD_REQUEST,
+            Rejection::Status(code, _) => *code,
+        }
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let body = ErrorOutput {
+            error: self.message(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl AddMetadataRequest {
+    pub fn meta_hash(&self) -> &str {
+        &self.meta_hash
+    }
+}
+
+// Validates a submission and puts its email and meta_hash in the stored form
+pub async fn check_submission(
+    state: &AppState,
+    query: &mut AddMetadataRequest,
+) -> Result<(), Rejection> {
+    // The trimmed email is the one hashed, checked and stored
+    query.email = query.email.trim().to_string();
+    check_email_length(&query.email)
+        .map_err(|err| Rejection::Status(StatusCode::BAD_REQUEST, err))?;
+    if !EmailAddress::is_valid(&query.email) {
+        return Err(Rejection::InvalidEmail(query.email.clone()));
+    }
+
+    if let Some(domain) = &query.domain {
+        if !is_valid_domain(domain) {
+            return Err(Rejection::Status(
+                StatusCode::BAD_REQUEST,
+                format!("invalid domain \"{}\"", domain),
+            ));
+        }
+    }
+
+    check_tax_state(&query.tax_state, state.conf.tax.as_ref())
+        .map_err(|err| Rejection::Status(StatusCode::BAD_REQUEST, err))?;
+    check_salt(&query.salt).map_err(|err| Rejection::Status(StatusCode::BAD_REQUEST, err))?;
+
+    // The hash is recomputed so only the owner of the metadata can submit it
+    let computed_meta_hash = compute_meta_hash(&query.email, &query.tax_state, &query.salt);
+    if FieldElement::from_hex_be(&query.meta_hash).ok() != Some(computed_meta_hash) {
+        return Err(Rejection::Status(
+            StatusCode::BAD_REQUEST,
+            "unable to verify hash".to_string(),
+        ));
+    }
+    // Stored in the form of the indexer whatever the client sent
+    query.meta_hash = meta_hash_hex(computed_meta_hash);
+    check_salt_unused(state, query).await?;
+
+    let public_key = fetch_public_key(&state.http, &state.conf.starknet.rpc_url, query.payer)
+        .await
+        .map_err(|err| {
+            Rejection::Status(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read the payer public key: {}", err),
+            )
+        })?;
+    if !verify_meta_hash_signature(&public_key, &query.meta_hash, &query.signature) {
+        return Err(Rejection::Status(
+            StatusCode::UNAUTHORIZED,
+            "invalid signature".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn metadata_document(query: &AddMetadataRequest) -> Result<Document, String> {
+    match mongodb::bson::to_bson(query) {
+        Ok(mongodb::bson::Bson::Document(document)) => Ok(document),
+        _ => Err("Failed to create BSON document".to_string()),
+    }
 }
 
 pub async fn handler(
     State(state): State<Arc<AppState>>,
-    Json(query): Json<AddMetadata>,
+    ValidJson(mut query): ValidJson<AddMetadataRequest>,
 ) -> impl IntoResponse {
-    let computed_meta_hash = compute_metadata_hash(&query.email, &query.tax_state, &query.salt);
-    if computed_meta_hash != query.meta_hash {
-        return get_specific_error(StatusCode::BAD_REQUEST, "unable to verify hash".to_string());
+    if let Err(rejection) = check_submission(&state, &mut query).await {
+        return rejection.into_response();
     }
 
     let metadata_collection = state.db.collection::<mongodb::bson::Document>("metadata");
+    let document = match metadata_document(&query) {
+        Ok(document) => document,
+        Err(err) => return get_error(err),
+    };
+    let (filter, update) = metadata_upsert(document);
+    let options = UpdateOptions::builder().upsert(true).build();
+    let status = match metadata_collection
+        .update_one(filter, update, options)
+        .await
+    {
+        Ok(result) if result.upserted_id.is_some() => {
+            state.metrics.metadata_added_total.inc();
+            StatusCode::CREATED
+        }
+        // Retried submission, the metadata is already stored
+        Ok(_) => StatusCode::OK,
+        Err(err) if is_duplicate_key(&err) => StatusCode::OK,
+        Err(err) => return get_error(format!("Failed to insert document: {}", err)),
+    };
 
-    let bson_doc = mongodb::bson::to_bson(&query).expect("Failed to serialize to BSON");
+    let output = Output {
+        meta_hash: query.meta_hash,
+        stored: true,
+    };
+    (status, Json(output)).into_response()
+}
 
-    if let mongodb::bson::Bson::Document(document) = bson_doc {
-        match metadata_collection.insert_one(document, None).await {
-            Ok(_) => (),
-            Err(err) => return get_error(format!("Failed to insert document: {}", err)),
-        }
-    } else {
-        return get_error("Failed to create BSON document".to_string());
+#[cfg(test)]
+mod add_metadata_tests {
+    use super::{
+        check_salt, check_tax_state, metadata_upsert, verify_meta_hash_signature,
+        AddMetadataRequest, Rejection,
+    };
+    use crate::{
+        config::Tax,
+        extract::ValidJson,
+        utils::{compute_meta_hash, meta_hash_hex},
+    };
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        response::IntoResponse,
+        routing::post,
+        Router,
+    };
+    use mongodb::bson::doc;
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+    use starknet::{
+        core::{crypto::ecdsa_sign, types::FieldElement},
+        signers::SigningKey,
+    };
+    use tower::ServiceExt;
+
+    fn compute_metadata_hash(email: &str, tax_state: &str, salt: &str) -> String {
+        meta_hash_hex(compute_meta_hash(email, tax_state, salt))
+    }
+
+    fn sign(private_key: FieldElement, meta_hash: &str) -> (FieldElement, FieldElement) {
+        let message = FieldElement::from_hex_be(meta_hash).unwrap();
+        let signature = ecdsa_sign(&private_key, &message).unwrap();
+        (signature.r, signature.s)
+    }
+
+    #[test]
+    fn test_valid_signature() {
+        let private_key = FieldElement::from(0x1234_5678_u64);
+        let public_key = SigningKey::from_secret_scalar(private_key)
+            .verifying_key()
+            .scalar();
+        let meta_hash = compute_metadata_hash("alice@example.com", "FR", "0x1");
+
+        let signature = sign(private_key, &meta_hash);
+        assert!(verify_meta_hash_signature(
+            &public_key,
+            &meta_hash,
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_tampered_signature() {
+        let private_key = FieldElement::from(0x1234_5678_u64);
+        let public_key = SigningKey::from_secret_scalar(private_key)
+            .verifying_key()
+            .scalar();
+        let meta_hash = compute_metadata_hash("alice@example.com", "FR", "0x1");
+        let other_hash = compute_metadata_hash("mallory@example.com", "FR", "0x1");
+
+        // signature over another hash
+        let signature = sign(private_key, &other_hash);
+        assert!(!verify_meta_hash_signature(
+            &public_key,
+            &meta_hash,
+            &signature
+        ));
+
+        // altered s
+        let (r, s) = sign(private_key, &meta_hash);
+        assert!(!verify_meta_hash_signature(
+            &public_key,
+            &meta_hash,
+            &(r, s + FieldElement::ONE)
+        ));
+
+        // signed by another key
+        let signature = sign(FieldElement::from(0x42_u64), &meta_hash);
+        assert!(!verify_meta_hash_signature(
+            &public_key,
+            &meta_hash,
+            &signature
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_submission_is_an_upsert() {
+        let submission = || {
+            doc! {
+                "meta_hash": "abc",
+                "email": "alice@example.com",
+                "tax_state": "FR",
+                "salt": "0x1",
+            }
+        };
+
+        let first = metadata_upsert(submission());
+        let retry = metadata_upsert(submission());
+        assert_eq!(first, retry);
+        assert_eq!(first.0, doc! { "meta_hash": "abc" });
+        // the document is only written when no metadata matches the hash
+        assert_eq!(first.1, doc! { "$setOnInsert": submission() });
+    }
+
+    #[test]
+    fn test_check_tax_state() {
+        let mut tax = Tax {
+            states: vec!["FR".to_string(), "US-CA".to_string()],
+            optional: None,
+        };
+        assert!(check_tax_state("FR", Some(&tax)).is_ok());
+        assert!(check_tax_state("us-ca", Some(&tax)).is_ok());
+        assert!(check_tax_state("Frnace", Some(&tax)).is_err());
+        assert!(check_tax_state("", Some(&tax)).is_err());
+        assert!(check_tax_state("none", Some(&tax)).is_err());
+
+        tax.optional = Some(true);
+        assert!(check_tax_state("", Some(&tax)).is_ok());
+        assert!(check_tax_state("None", Some(&tax)).is_ok());
+        assert!(check_tax_state("Frnace", Some(&tax)).is_err());
+
+        // without a [tax] section anything goes
+        assert!(check_tax_state("Frnace", None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rejection_body() {
+        let response = Rejection::Status(StatusCode::UNAUTHORIZED, "invalid signature".to_string())
+            .into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "error": "invalid signature" }));
+
+        let response = Rejection::InvalidEmail("nope".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn request_body() -> Value {
+        json!({
+            "meta_hash": "0x1",
+            "email": "alice@example.com",
+            "tax_state": "FR",
+            "salt": "0x1",
+            "payer": "0x2",
+            "signature": ["0x3", "0x4"],
+        })
+    }
+
+    // Status and error of a body parsed into an AddMetadataRequest
+    async fn parse(body: Value) -> (StatusCode, Value) {
+        let app = Router::new().route(
+            "/add_metadata",
+            post(|ValidJson(_): ValidJson<AddMetadataRequest>| async { StatusCode::OK }),
+        );
+        let request = Request::post("/add_metadata")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_request_accepted() {
+        assert_eq!(parse(request_body()).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_request_missing_email() {
+        let mut body = request_body();
+        body.as_object_mut().unwrap().remove("email");
+        let (status, error) = parse(body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .contains("missing field `email`"));
     }
 
-    (StatusCode::OK, Json(Output { success: true })).into_response()
+    #[tokio::test]
+    async fn test_request_missing_salt() {
+        let mut body = request_body();
+        body.as_object_mut().unwrap().remove("salt");
+        let (status, error) = parse(body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .contains("missing field `salt`"));
+    }
+
+    #[tokio::test]
+    async fn test_request_wrong_types() {
+        let mut body = request_body();
+        body["tax_state"] = json!(5);
+        let (status, error) = parse(body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].as_str().unwrap().contains("invalid type"));
+
+        let mut body = request_body();
+        body["signature"] = json!("0x3");
+        assert_eq!(parse(body).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_salt_accepted() {
+        assert_eq!(check_salt("0x5fd0e2c9a81b4d77"), Ok(()));
+        assert_eq!(check_salt("5FD0E2C9A81B4D77E0"), Ok(()));
+    }
+
+    #[test]
+    fn test_salt_empty() {
+        assert_eq!(check_salt(""), Err("salt is required".to_string()));
+    }
+
+    #[test]
+    fn test_salt_too_short() {
+        let expected = Err("salt must have at least 16 hex digits".to_string());
+        assert_eq!(check_salt("0x1"), expected);
+        assert_eq!(check_salt("0x"), expected);
+        assert_eq!(check_salt("5fd0e2c9a81b4d7"), expected);
+    }
+
+    #[test]
+    fn test_salt_not_hex() {
+        let expected = Err("salt must be a hex string".to_string());
+        assert_eq!(check_salt("0x5fd0e2c9a81b4d7z"), expected);
+        assert_eq!(check_salt(" 0x5fd0e2c9a81b4d77"), expected);
+        assert_eq!(check_salt("correct horse battery staple"), expected);
+    }
 }
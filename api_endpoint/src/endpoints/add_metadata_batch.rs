@@ -0,0 +1,197 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    endpoints::add_metadata::{
+        check_submission, metadata_document, AddMetadataRequest, Rejection, DUPLICATE_KEY,
+    },
+    extract::ValidJson,
+    models::AppState,
+    utils::{get_error, get_specific_error},
+};
+use axum::{extract::State, response::IntoResponse, Json};
+use mongodb::{
+    bson::Document,
+    error::{BulkWriteFailure, ErrorKind},
+    options::InsertManyOptions,
+};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+// A cart holds a handful of domains, larger batches are refused
+const MAX_BATCH_ITEMS: usize = 50;
+
+#[derive(Deserialize)]
+pub struct AddMetadataBatch {
+    items: Vec<AddMetadataRequest>,
+    // Inserts the valid items instead of refusing the whole batch
+    partial: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ItemResult {
+    meta_hash: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+    results: Vec<ItemResult>,
+}
+
+impl ItemResult {
+    fn ok(meta_hash: &str) -> Self {
+        ItemResult {
+            meta_hash: meta_hash.to_string(),
+            success: true,
+            error: None,
+        }
+    }
+
+    fn failed(meta_hash: &str, error: String) -> Self {
+        ItemResult {
+            meta_hash: meta_hash.to_string(),
+            success: false,
+            error: Some(error),
+        }
+    }
+}
+
+// Failed inserts of an unordered insert_many, keyed by their index in the
+// documents, a duplicate meta_hash is already stored and is not a failure
+fn insert_failures(failure: &BulkWriteFailure) -> HashMap<usize, String> {
+    failure
+        .write_errors
+        .iter()
+        .flatten()
+        .filter(|e| e.code != DUPLICATE_KEY)
+        .map(|e| (e.index, e.message.clone()))
+        .collect()
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    ValidJson(mut query): ValidJson<AddMetadataBatch>,
+) -> impl IntoResponse {
+    if query.items.is_empty() || query.items.len() > MAX_BATCH_ITEMS {
+        return get_specific_error(
+            StatusCode::BAD_REQUEST,
+            format!("a batch holds between 1 and {} items", MAX_BATCH_ITEMS),
+        );
+    }
+    let partial = query.partial.unwrap_or(false);
+
+    // Every item is validated before anything is written
+    let mut results = Vec::with_capacity(query.items.len());
+    let mut rejections = Vec::with_capacity(query.items.len());
+    for item in query.items.iter_mut() {
+        rejections.push(check_submission(&state, item).await.err());
+    }
+    if !partial && rejections.iter().flatten().any(Rejection::is_invalid_email) {
+        let results: Vec<ItemResult> = query
+            .items
+            .iter()
+            .zip(&rejections)
+            .map(|(item, rejection)| match rejection {
+                Some(rejection) => ItemResult::failed(item.meta_hash(), rejection.message()),
+                None => ItemResult::failed(item.meta_hash(), "batch rejected".to_string()),
+            })
+            .collect();
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(Output {
+                success: false,
+                results,
+            }),
+        )
+            .into_response();
+    }
+    if !partial {
+        if let Some(rejection) = rejections.iter_mut().find_map(Option::take) {
+            return rejection.into_response();
+        }
+    }
+
+    let mut documents: Vec<Document> = Vec::new();
+    // Index in the batch of each document
+    let mut positions: Vec<usize> = Vec::new();
+    for (position, (item, rejection)) in query.items.iter().zip(&rejections).enumerate() {
+        let error = match rejection {
+            Some(rejection) => Some(rejection.message()),
+            None => match metadata_document(item) {
+                Ok(document) => {
+                    documents.push(document);
+                    positions.push(position);
+                    None
+                }
+                Err(err) => Some(err),
+            },
+        };
+        results.push(match error {
+            Some(error) => ItemResult::failed(item.meta_hash(), error),
+            None => ItemResult::ok(item.meta_hash()),
+        });
+    }
+
+    if !documents.is_empty() {
+        let metadata_collection = state.db.collection::<Document>("metadata");
+        // Unordered so one failed insert does not stop the following ones
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let failures = match metadata_collection.insert_many(documents, options).await {
+            Ok(result) => {
+                state
+                    .metrics
+                    .metadata_added_total
+                    .inc_by(result.inserted_ids.len() as u64);
+                HashMap::new()
+            }
+            Err(err) => match err.kind.as_ref() {
+                ErrorKind::BulkWrite(failure) => {
+                    let failed = failure.write_errors.as_ref().map_or(0, Vec::len);
+                    state
+                        .metrics
+                        .metadata_added_total
+                        .inc_by((positions.len() - failed) as u64);
+                    insert_failures(failure)
+                }
+                _ => return get_error(format!("Failed to insert documents: {}", err)),
+            },
+        };
+        for (index, error) in failures {
+            let result = &mut results[positions[index]];
+            result.success = false;
+            result.error = Some(format!("Failed to insert document: {}", error));
+        }
+    }
+
+    let success = results.iter().all(|result| result.success);
+    (StatusCode::OK, Json(Output { success, results })).into_response()
+}
+
+#[cfg(test)]
+mod add_metadata_batch_tests {
+    use super::{ItemResult, Output};
+
+    #[test]
+    fn test_output_reports_errors_only() {
+        let output = Output {
+            success: false,
+            results: vec![
+                ItemResult::ok("01"),
+                ItemResult::failed("02", "invalid signature".to_string()),
+            ],
+        };
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::json!({
+                "success": false,
+                "results": [
+                    { "meta_hash": "01", "success": true },
+                    { "meta_hash": "02", "success": false, "error": "invalid signature" },
+                ],
+            })
+        );
+    }
+}
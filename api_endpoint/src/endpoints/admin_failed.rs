@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+    Collection,
+};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct FailedQuery {
+    limit: Option<i64>,
+    offset: Option<u64>,
+    skipped: Option<bool>,
+}
+
+// Entry of the `failed_sales` collection, one per sale the pipeline gave up on
+#[derive(Serialize, Deserialize)]
+pub struct FailedSaleDoc {
+    meta_hash: String,
+    attempts: i64,
+    last_error: Option<String>,
+    last_attempt: Option<i64>,
+}
+
+// Entry of the `processed` collection which was marked without an email being sent
+#[derive(Serialize, Deserialize)]
+pub struct SkippedSaleDoc {
+    meta_hash: String,
+    outcome: String,
+    reason: Option<String>,
+}
+
+// One page of a list endpoint, `next` is the offset of the following page and
+// is null on the last one
+#[derive(Serialize)]
+pub struct Output<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    pub next: Option<u64>,
+}
+
+fn next_offset(offset: u64, returned: usize, total: u64) -> Option<u64> {
+    let next = offset + returned as u64;
+    (returned > 0 && next < total).then_some(next)
+}
+
+// limit and offset of a list query, the page size is capped at MAX_LIMIT
+pub fn page(limit: Option<i64>, offset: Option<u64>) -> (i64, u64) {
+    (
+        limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        offset.unwrap_or(0),
+    )
+}
+
+pub async fn list<T>(
+    collection: Collection<T>,
+    filter: Document,
+    sort: Document,
+    limit: i64,
+    offset: u64,
+) -> Result<Output<T>, mongodb::error::Error>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    let total = collection.count_documents(filter.clone(), None).await?;
+    let options = FindOptions::builder()
+        .sort(sort)
+        .skip(offset)
+        .limit(limit)
+        .build();
+    let items: Vec<T> = collection
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await?;
+    let next = next_offset(offset, items.len(), total);
+    Ok(Output { items, total, next })
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<FailedQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+
+    let (limit, offset) = page(query.limit, query.offset);
+
+    if query.skipped.unwrap_or(false) {
+        let collection = state.db.collection::<SkippedSaleDoc>("processed");
+        match list(
+            collection,
+            doc! { "outcome": "skipped" },
+            doc! { "_id": -1 },
+            limit,
+            offset,
+        )
+        .await
+        {
+            Ok(output) => (StatusCode::OK, Json(output)).into_response(),
+            Err(err) => get_error(format!("Failed to list skipped sales: {}", err)),
+        }
+    } else {
+        let collection = state.db.collection::<FailedSaleDoc>("failed_sales");
+        match list(
+            collection,
+            doc! {},
+            doc! { "last_attempt": -1 },
+            limit,
+            offset,
+        )
+        .await
+        {
+            Ok(output) => (StatusCode::OK, Json(output)).into_response(),
+            Err(err) => get_error(format!("Failed to list failed sales: {}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod admin_failed_tests {
+    use super::{next_offset, page, MAX_LIMIT};
+
+    #[test]
+    fn test_page_caps_the_limit() {
+        assert_eq!(page(None, None), (50, 0));
+        assert_eq!(page(Some(1_000_000), Some(20)), (MAX_LIMIT, 20));
+        assert_eq!(page(Some(0), None), (1, 0));
+        assert_eq!(page(Some(-5), None), (1, 0));
+    }
+
+    #[test]
+    fn test_next_offset() {
+        assert_eq!(next_offset(0, 50, 120), Some(50));
+        assert_eq!(next_offset(100, 20, 120), None);
+        assert_eq!(next_offset(0, 0, 0), None);
+        // offset past the end
+        assert_eq!(next_offset(500, 0, 120), None);
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use crate::{
+    extract::ValidJson,
+    models::AppState,
+    utils::{get_specific_error, is_admin},
+};
+use axum::{extract::State, http::HeaderMap, response::IntoResponse, Json};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct MaintenanceQuery {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    maintenance: bool,
+}
+
+// Turns maintenance mode on or off without a restart, it is the one write left
+// open while it is on
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ValidJson(query): ValidJson<MaintenanceQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+    state.maintenance.set(query.enabled, &state.logger);
+
+    (
+        StatusCode::OK,
+        Json(Output {
+            maintenance: state.maintenance.is_enabled(),
+        }),
+    )
+        .into_response()
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use super::admin_failed::{list, page, Output};
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, DateTime};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct RunsQuery {
+    limit: Option<i64>,
+    offset: Option<u64>,
+}
+
+// Run summary saved by sale_actions at the end of every run
+#[derive(Deserialize)]
+pub struct RunDoc {
+    started_at: DateTime,
+    ended_at: DateTime,
+    duration_ms: i64,
+    pipelines: Vec<String>,
+    dry_run: bool,
+    read: i64,
+    sent: i64,
+    failed: i64,
+    skipped: i64,
+    // missing from the runs saved before it was counted
+    #[serde(default)]
+    invalid_email: i64,
+}
+
+// Same as RunDoc with the dates as millisecond timestamps
+#[derive(Serialize)]
+pub struct RunOutput {
+    started_at: i64,
+    ended_at: i64,
+    duration_ms: i64,
+    pipelines: Vec<String>,
+    dry_run: bool,
+    read: i64,
+    sent: i64,
+    failed: i64,
+    skipped: i64,
+    invalid_email: i64,
+}
+
+impl From<RunDoc> for RunOutput {
+    fn from(run: RunDoc) -> Self {
+        RunOutput {
+            started_at: run.started_at.timestamp_millis(),
+            ended_at: run.ended_at.timestamp_millis(),
+            duration_ms: run.duration_ms,
+            pipelines: run.pipelines,
+            dry_run: run.dry_run,
+            read: run.read,
+            sent: run.sent,
+            failed: run.failed,
+            skipped: run.skipped,
+            invalid_email: run.invalid_email,
+        }
+    }
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<RunsQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+
+    let (limit, offset) = page(query.limit, query.offset);
+    let collection = state.db.collection::<RunDoc>(
+        state
+            .conf
+            .admin
+            .runs_collection
+            .as_deref()
+            .unwrap_or("processing_runs"),
+    );
+
+    match list(
+        collection,
+        doc! {},
+        doc! { "started_at": -1 },
+        limit,
+        offset,
+    )
+    .await
+    {
+        Ok(output) => (
+            StatusCode::OK,
+            Json(Output {
+                items: output.items.into_iter().map(RunOutput::from).collect(),
+                total: output.total,
+                next: output.next,
+            }),
+        )
+            .into_response(),
+        Err(err) => get_error(format!("Failed to list processing runs: {}", err)),
+    }
+}
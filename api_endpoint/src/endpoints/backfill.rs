@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use crate::{
+    extract::ValidJson,
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{extract::State, http::HeaderMap, response::IntoResponse, Json};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Both bounds are required, in seconds, sale_actions caps the width of the range
+#[derive(Deserialize, Serialize)]
+pub struct BackfillQuery {
+    from: i64,
+    to: i64,
+}
+
+fn backfill_url(trigger_url: &str) -> String {
+    format!("{}/backfill", trigger_url.trim_end_matches('/'))
+}
+
+// Sends the sales of a date range again, processed or not, e.g. after a
+// provider outage. Runs on the sale_actions trigger server like the redrive
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ValidJson(query): ValidJson<BackfillQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+    let Some(sale_actions) = &state.conf.sale_actions else {
+        return get_specific_error(StatusCode::NOT_FOUND, "backfill is disabled".to_string());
+    };
+    if query.from > query.to {
+        return get_specific_error(
+            StatusCode::BAD_REQUEST,
+            "from must not be after to".to_string(),
+        );
+    }
+
+    let response = state
+        .http
+        .post(backfill_url(&sale_actions.trigger_url))
+        .header(
+            "Authorization",
+            format!("Bearer {}", sale_actions.trigger_token),
+        )
+        .json(&query)
+        .send()
+        .await;
+    match response {
+        Ok(res) if res.status().is_success() => match res.json::<Value>().await {
+            Ok(summary) => (StatusCode::OK, Json(summary)).into_response(),
+            Err(err) => get_error(format!("Invalid backfill summary: {}", err)),
+        },
+        // the range was refused, e.g. wider than processing.max_backfill_days
+        Ok(res) if res.status() == StatusCode::BAD_REQUEST => {
+            let reason = res.text().await.unwrap_or_default();
+            get_specific_error(StatusCode::BAD_REQUEST, reason)
+        }
+        Ok(res) if res.status() == StatusCode::CONFLICT => get_specific_error(
+            StatusCode::CONFLICT,
+            "a run is already in progress".to_string(),
+        ),
+        Ok(res) => get_error(format!(
+            "sale_actions refused the backfill: {}",
+            res.status()
+        )),
+        Err(err) => get_error(format!("Failed to reach sale_actions: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod backfill_tests {
+    use super::{backfill_url, BackfillQuery};
+
+    #[test]
+    fn test_backfill_url() {
+        assert_eq!(
+            backfill_url("http://localhost:8090/"),
+            "http://localhost:8090/backfill"
+        );
+    }
+
+    #[test]
+    fn test_range_is_required() {
+        assert!(serde_json::from_str::<BackfillQuery>("{}").is_err());
+        assert!(serde_json::from_str::<BackfillQuery>(r#"{"from": 1}"#).is_err());
+        let query: BackfillQuery = serde_json::from_str(r#"{"from": 1, "to": 2}"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&query).unwrap(),
+            r#"{"from":1,"to":2}"#
+        );
+    }
+}
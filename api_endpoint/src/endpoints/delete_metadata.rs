@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin, redact_email},
+};
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, Document};
+use reqwest::StatusCode;
+use serde_derive::Serialize;
+
+// Collections holding data of a sale, metadata goes last so an erasure that
+// stops midway is found again when retried
+const COLLECTIONS: [&str; 3] = ["failed_emails", "processed", "metadata"];
+
+#[derive(Serialize)]
+pub struct Output {
+    meta_hash: String,
+    deleted: Vec<Deleted>,
+}
+
+#[derive(Serialize)]
+pub struct Deleted {
+    collection: &'static str,
+    count: u64,
+}
+
+// Erases everything stored for a meta_hash, e.g. for a GDPR deletion request
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(meta_hash): Path<String>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+
+    let filter = doc! { "meta_hash": &meta_hash };
+    let email = match state
+        .db
+        .collection::<Document>("metadata")
+        .find_one(filter.clone(), None)
+        .await
+    {
+        Ok(metadata) => metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get_str("email").ok())
+            .map(redact_email),
+        Err(err) => return get_error(format!("Failed to read metadata: {}", err)),
+    };
+
+    let mut deleted = Vec::new();
+    for collection in COLLECTIONS {
+        match state
+            .db
+            .collection::<Document>(collection)
+            .delete_many(filter.clone(), None)
+            .await
+        {
+            Ok(result) => deleted.push(Deleted {
+                collection,
+                count: result.deleted_count,
+            }),
+            Err(err) => {
+                return get_error(format!(
+                    "Failed to delete from {}, retry to finish the erasure: {}",
+                    collection, err
+                ))
+            }
+        }
+    }
+
+    state.logger.info(format!(
+        "erased data of {} ({}): {}",
+        meta_hash,
+        email.as_deref().unwrap_or("no metadata"),
+        deleted
+            .iter()
+            .map(|d| format!("{} {}", d.count, d.collection))
+            .collect::<Vec<String>>()
+            .join(", ")
+    ));
+
+    (StatusCode::OK, Json(Output { meta_hash, deleted })).into_response()
+}
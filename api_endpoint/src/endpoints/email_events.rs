@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use crate::{
+    extract::ValidJson,
+    models::AppState,
+    utils::{get_error, get_specific_error, redact_email},
+};
+use axum::{extract::State, response::IntoResponse, Json};
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    options::UpdateOptions,
+    Database,
+};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// Addresses sale_actions no longer emails
+pub const SUPPRESSED_COLLECTION: &str = "suppressed_emails";
+
+const DEFAULT_MAX_AGE_SECS: i64 = 300;
+
+#[derive(Deserialize)]
+pub struct EventSignature {
+    timestamp: String,
+    token: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+pub struct EventData {
+    event: String,
+    severity: Option<String>,
+    recipient: String,
+}
+
+// Mailgun webhook payload, only the fields needed for the suppression
+#[derive(Deserialize)]
+pub struct EmailEvent {
+    signature: EventSignature,
+    #[serde(rename = "event-data")]
+    event_data: EventData,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+    suppressed: bool,
+}
+
+// Adds the lowercased address to suppressed_emails, the reason of an address
+// already there is replaced
+pub async fn suppress(
+    db: &Database,
+    email: &str,
+    reason: &str,
+) -> Result<(), mongodb::error::Error> {
+    let options = UpdateOptions::builder().upsert(true).build();
+    db.collection::<Document>(SUPPRESSED_COLLECTION)
+        .update_one(
+            doc! { "email": email.trim().to_lowercase() },
+            doc! { "$set": { "reason": reason, "suppressed_at": DateTime::now() } },
+            options,
+        )
+        .await
+        .map(|_| ())
+}
+
+// The signature is the HMAC of timestamp and token with the webhook signing
+// key, old timestamps are refused so a captured payload cannot be replayed
+fn verify_signature(
+    signing_key: &str,
+    signature: &EventSignature,
+    now: i64,
+    max_age_secs: i64,
+) -> Result<(), String> {
+    let invalid = || "invalid signature".to_string();
+    let timestamp: i64 = signature.timestamp.parse().map_err(|_| invalid())?;
+    if (now - timestamp).abs() > max_age_secs {
+        return Err("signature expired".to_string());
+    }
+    let expected = hex::decode(&signature.signature).map_err(|_| invalid())?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(signature.timestamp.as_bytes());
+    mac.update(signature.token.as_bytes());
+    mac.verify_slice(&expected).map_err(|_| invalid())
+}
+
+// Hard bounces and spam complaints suppress the address, soft bounces are
+// retried by the provider itself
+fn suppression_reason(event: &EventData) -> Option<&'static str> {
+    match (event.event.as_str(), event.severity.as_deref()) {
+        ("failed", Some("permanent")) => Some("bounce"),
+        ("complained", _) => Some("complaint"),
+        _ => None,
+    }
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    ValidJson(query): ValidJson<EmailEvent>,
+) -> impl IntoResponse {
+    let Some(email_events) = &state.conf.email_events else {
+        return get_specific_error(
+            StatusCode::NOT_FOUND,
+            "email events are disabled".to_string(),
+        );
+    };
+    let max_age_secs = email_events
+        .max_age_secs
+        .map_or(DEFAULT_MAX_AGE_SECS, |secs| secs as i64);
+    if let Err(err) = verify_signature(
+        &email_events.signing_key,
+        &query.signature,
+        DateTime::now().timestamp_millis() / 1000,
+        max_age_secs,
+    ) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, err);
+    }
+
+    let Some(reason) = suppression_reason(&query.event_data) else {
+        return (
+            StatusCode::OK,
+            Json(Output {
+                success: true,
+                suppressed: false,
+            }),
+        )
+            .into_response();
+    };
+    let email = query.event_data.recipient.trim().to_lowercase();
+    if let Err(err) = suppress(&state.db, &email, reason).await {
+        return get_error(format!("Failed to record the suppression: {}", err));
+    }
+    state.logger.info(format!(
+        "suppressed {} after a {}",
+        redact_email(&email),
+        reason
+    ));
+
+    (
+        StatusCode::OK,
+        Json(Output {
+            success: true,
+            suppressed: true,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod email_events_tests {
+    use super::{suppression_reason, verify_signature, EventData, EventSignature};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn signed(key: &str, timestamp: i64) -> EventSignature {
+        let timestamp = timestamp.to_string();
+        let token = "a8ce0edb2dd8301dee6c2405235584e45aa91d1e9f979f3de0".to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(token.as_bytes());
+        EventSignature {
+            signature: hex::encode(mac.finalize().into_bytes()),
+            timestamp,
+            token,
+        }
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        assert_eq!(
+            verify_signature("key", &signed("key", NOW), NOW, 300),
+            Ok(())
+        );
+        assert!(verify_signature("other key", &signed("key", NOW), NOW, 300).is_err());
+        assert!(verify_signature("key", &signed("key", NOW - 301), NOW, 300).is_err());
+
+        let mut tampered = signed("key", NOW);
+        tampered.token = "another token".to_string();
+        assert!(verify_signature("key", &tampered, NOW, 300).is_err());
+    }
+
+    #[test]
+    fn test_suppression_reason() {
+        let event = |event: &str, severity: Option<&str>| EventData {
+            event: event.to_string(),
+            severity: severity.map(String::from),
+            recipient: "alice@example.com".to_string(),
+        };
+        assert_eq!(
+            suppression_reason(&event("failed", Some("permanent"))),
+            Some("bounce")
+        );
+        assert_eq!(
+            suppression_reason(&event("failed", Some("temporary"))),
+            None
+        );
+        assert_eq!(
+            suppression_reason(&event("complained", None)),
+            Some("complaint")
+        );
+        assert_eq!(suppression_reason(&event("delivered", None)), None);
+    }
+}
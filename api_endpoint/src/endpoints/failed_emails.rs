@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use super::admin_failed::{list, page, Output};
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, DateTime};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct FailedEmailsQuery {
+    limit: Option<i64>,
+    offset: Option<u64>,
+}
+
+// Email the provider rejected for good, parked by sale_actions until an
+// operator re-drives it
+#[derive(Deserialize)]
+pub struct FailedEmailDoc {
+    meta_hash: String,
+    recipient: String,
+    status: Option<i32>,
+    body: String,
+    timestamp: DateTime,
+}
+
+// Same as FailedEmailDoc with the timestamp in milliseconds
+#[derive(Serialize)]
+pub struct FailedEmailOutput {
+    meta_hash: String,
+    recipient: String,
+    status: Option<i32>,
+    body: String,
+    timestamp: i64,
+}
+
+impl From<FailedEmailDoc> for FailedEmailOutput {
+    fn from(email: FailedEmailDoc) -> Self {
+        FailedEmailOutput {
+            meta_hash: email.meta_hash,
+            recipient: email.recipient,
+            status: email.status,
+            body: email.body,
+            timestamp: email.timestamp.timestamp_millis(),
+        }
+    }
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<FailedEmailsQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+
+    let (limit, offset) = page(query.limit, query.offset);
+    let collection = state.db.collection::<FailedEmailDoc>("failed_emails");
+
+    match list(collection, doc! {}, doc! { "timestamp": -1 }, limit, offset).await {
+        Ok(output) => (
+            StatusCode::OK,
+            Json(Output {
+                items: output
+                    .items
+                    .into_iter()
+                    .map(FailedEmailOutput::from)
+                    .collect(),
+                total: output.total,
+                next: output.next,
+            }),
+        )
+            .into_response(),
+        Err(err) => get_error(format!("Failed to list failed emails: {}", err)),
+    }
+}
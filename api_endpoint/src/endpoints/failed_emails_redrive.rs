@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{extract::State, http::HeaderMap, response::IntoResponse, Json};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct RedriveQuery {
+    // every entry of failed_emails when missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta_hashes: Option<Vec<String>>,
+}
+
+fn redrive_url(trigger_url: &str) -> String {
+    format!("{}/redrive", trigger_url.trim_end_matches('/'))
+}
+
+// Sending belongs to sale_actions, the redrive runs on its trigger server and
+// its {redriven, succeeded, still_failing} summary is relayed as is
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    query: Option<Json<RedriveQuery>>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+    let Some(sale_actions) = &state.conf.sale_actions else {
+        return get_specific_error(StatusCode::NOT_FOUND, "redrive is disabled".to_string());
+    };
+    let query = query.map(|Json(query)| query).unwrap_or_default();
+
+    let response = state
+        .http
+        .post(redrive_url(&sale_actions.trigger_url))
+        .header(
+            "Authorization",
+            format!("Bearer {}", sale_actions.trigger_token),
+        )
+        .json(&query)
+        .send()
+        .await;
+    match response {
+        Ok(res) if res.status().is_success() => match res.json::<Value>().await {
+            Ok(summary) => (StatusCode::OK, Json(summary)).into_response(),
+            Err(err) => get_error(format!("Invalid redrive summary: {}", err)),
+        },
+        Ok(res) if res.status() == StatusCode::CONFLICT => get_specific_error(
+            StatusCode::CONFLICT,
+            "a run is already in progress".to_string(),
+        ),
+        Ok(res) => get_error(format!(
+            "sale_actions refused the redrive: {}",
+            res.status()
+        )),
+        Err(err) => get_error(format!("Failed to reach sale_actions: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod failed_emails_redrive_tests {
+    use super::{redrive_url, RedriveQuery};
+
+    #[test]
+    fn test_redrive_url() {
+        assert_eq!(
+            redrive_url("http://localhost:8090"),
+            "http://localhost:8090/redrive"
+        );
+        assert_eq!(
+            redrive_url("http://localhost:8090/"),
+            "http://localhost:8090/redrive"
+        );
+    }
+
+    #[test]
+    fn test_query_forwarded_as_is() {
+        let all = RedriveQuery::default();
+        assert_eq!(serde_json::to_string(&all).unwrap(), "{}");
+        let some: RedriveQuery = serde_json::from_str(r#"{"meta_hashes": ["abc"]}"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&some).unwrap(),
+            r#"{"meta_hashes":["abc"]}"#
+        );
+    }
+}
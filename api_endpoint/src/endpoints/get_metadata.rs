@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, Document};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct MetadataDoc {
+    email: String,
+    tax_state: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    meta_hash: String,
+    email: String,
+    tax_state: String,
+    processed: bool,
+}
+
+// Stored metadata of a sale, admin only as it exposes the email
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(meta_hash): Path<String>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+
+    let metadata = match state
+        .db
+        .collection::<MetadataDoc>("metadata")
+        .find_one(doc! { "meta_hash": &meta_hash }, None)
+        .await
+    {
+        Ok(Some(metadata)) => metadata,
+        Ok(None) => {
+            return get_specific_error(StatusCode::NOT_FOUND, "metadata not found".to_string())
+        }
+        Err(err) => return get_error(format!("Failed to read metadata: {}", err)),
+    };
+
+    let processed = match state
+        .db
+        .collection::<Document>("processed")
+        .count_documents(doc! { "meta_hash": &meta_hash }, None)
+        .await
+    {
+        Ok(count) => count > 0,
+        Err(err) => return get_error(format!("Failed to read processed sales: {}", err)),
+    };
+
+    (
+        StatusCode::OK,
+        Json(Output {
+            meta_hash,
+            email: metadata.email,
+            tax_state: metadata.tax_state,
+            processed,
+        }),
+    )
+        .into_response()
+}
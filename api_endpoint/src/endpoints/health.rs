@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use crate::models::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use mongodb::bson::doc;
+use serde_json::json;
+
+// Readiness probe, only healthy while the database answers a ping
+pub async fn handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match state.db.run_command(doc! { "ping": 1 }, None).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "db": "connected" })),
+        ),
+        Err(e) => {
+            state
+                .logger
+                .warning(format!("health check: database ping failed: {}", e));
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "degraded" })),
+            )
+        }
+    }
+}
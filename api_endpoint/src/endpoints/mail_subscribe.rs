@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
 use crate::{
+    captcha,
+    extract::ValidJson,
     models::AppState,
-    utils::{get_error, to_hex},
+    utils::{get_error, is_honeypot_filled, to_hex},
 };
 use axum::{extract::State, response::IntoResponse, Json};
 use reqwest::StatusCode;
@@ -13,6 +15,8 @@ use starknet::core::types::FieldElement;
 pub struct MailSubscribeQuery {
     tx_hash: FieldElement,
     groups: Vec<String>,
+    hp: Option<String>,
+    captcha_token: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -36,8 +40,21 @@ pub struct Output {
 
 pub async fn handler(
     State(state): State<Arc<AppState>>,
-    Json(query): Json<MailSubscribeQuery>,
+    ValidJson(query): ValidJson<MailSubscribeQuery>,
 ) -> impl IntoResponse {
+    if is_honeypot_filled(query.hp.as_deref()) {
+        return (StatusCode::OK, Json(Output { success: true })).into_response();
+    }
+    if let Err(response) = captcha::check(
+        &state.http,
+        state.conf.captcha.as_ref(),
+        query.captcha_token.as_deref(),
+    )
+    .await
+    {
+        return response;
+    }
+
     let emails_collection = state
         .db
         .collection::<mongodb::bson::Document>("email_groups");
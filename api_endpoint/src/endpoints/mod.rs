@@ -1,3 +1,21 @@
 pub mod add_metadata;
+pub mod add_metadata_batch;
+pub mod admin_failed;
+pub mod admin_maintenance;
+pub mod admin_runs;
+pub mod backfill;
+pub mod delete_metadata;
+pub mod email_events;
+pub mod failed_emails;
+pub mod failed_emails_redrive;
+pub mod get_metadata;
+pub mod health;
 pub mod mail_subscribe;
+pub mod newsletter_confirm;
 pub mod newsletter_subscribe;
+pub mod sponsor_summary;
+pub mod stats;
+pub mod suppress;
+pub mod suppressed;
+pub mod unsubscribe;
+pub mod version;
@@ -0,0 +1,116 @@
+use std::{sync::Arc, time::Duration};
+
+use super::newsletter_subscribe::{add_subscriber, PENDING_COLLECTION};
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error},
+};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, DateTime};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+pub const DEFAULT_TOKEN_TTL_HOURS: u64 = 48;
+
+#[derive(Deserialize)]
+pub struct ConfirmQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PendingDoc {
+    email: String,
+    address: Option<String>,
+    created_at: DateTime,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+}
+
+// The TTL index only sweeps expired tokens every minute or so
+fn is_expired(created_at: DateTime, ttl: Duration, now: DateTime) -> bool {
+    now.timestamp_millis() - created_at.timestamp_millis() > ttl.as_millis() as i64
+}
+
+// Target of the link emailed by newsletter_subscribe, promotes the pending
+// subscriber to a confirmed one
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ConfirmQuery>,
+) -> impl IntoResponse {
+    let Some(newsletter) = &state.conf.newsletter else {
+        return get_specific_error(
+            StatusCode::NOT_FOUND,
+            "double opt-in is disabled".to_string(),
+        );
+    };
+
+    let pending = match state
+        .db
+        .collection::<PendingDoc>(PENDING_COLLECTION)
+        .find_one_and_delete(doc! { "token": &query.token }, None)
+        .await
+    {
+        Ok(Some(pending)) => pending,
+        Ok(None) => {
+            return get_specific_error(
+                StatusCode::BAD_REQUEST,
+                "invalid or expired token".to_string(),
+            )
+        }
+        Err(err) => return get_error(format!("Failed to read pending subscriber: {}", err)),
+    };
+    let ttl = Duration::from_secs(
+        newsletter
+            .token_ttl_hours
+            .unwrap_or(DEFAULT_TOKEN_TTL_HOURS)
+            * 3600,
+    );
+    if is_expired(pending.created_at, ttl, DateTime::now()) {
+        return get_specific_error(
+            StatusCode::BAD_REQUEST,
+            "invalid or expired token".to_string(),
+        );
+    }
+
+    // Opening the link of an earlier subscription again is not an error
+    match state
+        .db
+        .collection::<mongodb::bson::Document>("newsletter")
+        .find_one(doc! { "email": &pending.email }, None)
+        .await
+    {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            if let Err(err) = add_subscriber(&state, pending.email, pending.address).await {
+                return get_error(err);
+            }
+        }
+        Err(err) => return get_error(format!("Failed to read subscribers: {}", err)),
+    }
+
+    (StatusCode::OK, Json(Output { success: true })).into_response()
+}
+
+#[cfg(test)]
+mod newsletter_confirm_tests {
+    use super::is_expired;
+    use mongodb::bson::DateTime;
+    use std::time::Duration;
+
+    #[test]
+    fn test_is_expired() {
+        let created_at = DateTime::from_millis(1_700_000_000_000);
+        let ttl = Duration::from_secs(3600);
+        let later = |secs: i64| DateTime::from_millis(1_700_000_000_000 + secs * 1000);
+        assert!(!is_expired(created_at, ttl, later(0)));
+        assert!(!is_expired(created_at, ttl, later(3600)));
+        assert!(is_expired(created_at, ttl, later(3601)));
+    }
+}
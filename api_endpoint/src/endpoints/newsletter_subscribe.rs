@@ -1,14 +1,27 @@
 use std::sync::Arc;
 
-use crate::{models::AppState, utils::get_error};
+use crate::{
+    captcha,
+    extract::ValidJson,
+    models::AppState,
+    utils::{check_email_length, get_error, get_specific_error, is_honeypot_filled},
+};
 use axum::{extract::State, response::IntoResponse, Json};
+use mongodb::bson::{doc, DateTime, Document};
+use rand::Rng;
 use reqwest::StatusCode;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+// Subscribers waiting for the confirmation link to be opened
+pub const PENDING_COLLECTION: &str = "newsletter_pending";
 
 #[derive(Serialize, Deserialize)]
 pub struct AddNewsletterQuery {
     email: String,
     address: Option<String>,
+    hp: Option<String>,
+    captcha_token: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,8 +38,24 @@ pub struct Output {
 
 pub async fn handler(
     State(state): State<Arc<AppState>>,
-    Json(query): Json<AddNewsletterQuery>,
+    ValidJson(query): ValidJson<AddNewsletterQuery>,
 ) -> impl IntoResponse {
+    if is_honeypot_filled(query.hp.as_deref()) {
+        return (StatusCode::OK, Json(Output { success: true })).into_response();
+    }
+    if let Err(response) = captcha::check(
+        &state.http,
+        state.conf.captcha.as_ref(),
+        query.captcha_token.as_deref(),
+    )
+    .await
+    {
+        return response;
+    }
+    if let Err(err) = check_email_length(&query.email) {
+        return get_specific_error(StatusCode::BAD_REQUEST, err);
+    }
+
     let collection = state.db.collection::<mongodb::bson::Document>("newsletter");
 
     // Check if email already exists
@@ -40,29 +69,118 @@ pub async fn handler(
         return get_error("Email already exists".to_string());
     }
 
-    // Mailerlite API
-    let base_url = state.conf.email.base_url.clone();
-    let api_key = state.conf.email.api_key.clone();
-    let ar_group_id = state.conf.email.ar_group_id.clone();
+    let Some(newsletter) = &state.conf.newsletter else {
+        if let Err(err) = add_subscriber(&state, query.email, query.address).await {
+            return get_error(err);
+        }
+        return (StatusCode::OK, Json(Output { success: true })).into_response();
+    };
 
-    let url = format!("{}/subscribers", base_url);
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&url)
+    // Double opt-in, the subscriber is only added once the emailed link is opened
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let pending = doc! {
+        "token": &token,
+        "email": &query.email,
+        "address": query.address.clone(),
+        "created_at": DateTime::now(),
+    };
+    if let Err(err) = state
+        .db
+        .collection::<Document>(PENDING_COLLECTION)
+        .insert_one(pending, None)
+        .await
+    {
+        return get_error(format!("Failed to store pending subscriber: {}", err));
+    }
+
+    let confirm_link = format!("{}?token={}", newsletter.confirm_url, token);
+    let response = state
+        .http
+        .post(format!("{}/subscribers", state.conf.email.base_url))
         .header("content-type", "application/json")
         .header("accept", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&serde_json::json!({ "email": query.email, "groups": [ar_group_id] }))
+        .header(
+            "Authorization",
+            format!("Bearer {}", state.conf.email.api_key),
+        )
+        .json(&json!({
+            "email": query.email,
+            "fields": { "confirm_link": confirm_link },
+            "groups": [newsletter.confirm_group_id],
+        }))
         .send()
         .await;
+    match response {
+        Ok(res) if res.status().is_success() => state.metrics.emails_sent_total.inc(),
+        Ok(res) => {
+            state.metrics.emails_failed_total.inc();
+            return get_error(format!(
+                "Mailerlite refused the confirmation email: {}",
+                res.status()
+            ));
+        }
+        Err(err) => {
+            state.metrics.emails_failed_total.inc();
+            return get_error(format!("Failed to send request to Mailerlite: {}", err));
+        }
+    }
 
-    if let Err(err) = response {
-        return get_error(format!("Failed to send request to Mailerlite: {}", err));
+    (StatusCode::OK, Json(Output { success: true })).into_response()
+}
+
+// Adds a confirmed subscriber to the provider group and records it
+pub async fn add_subscriber(
+    state: &AppState,
+    email: String,
+    address: Option<String>,
+) -> Result<(), String> {
+    let collection = state.db.collection::<mongodb::bson::Document>("newsletter");
+    let ar_group_id = state.conf.email.ar_group_id.clone();
+    if state.conf.email.queue_subscribers.unwrap_or(false) {
+        // sale_actions pushes the queued subscribers to Mailerlite in batches
+        let queued = mongodb::bson::doc! {
+            "email": &email,
+            "groups": [ar_group_id],
+            "synced": false,
+            "attempts": 0,
+        };
+        if let Err(err) = state
+            .db
+            .collection::<mongodb::bson::Document>("subscriber_queue")
+            .insert_one(queued, None)
+            .await
+        {
+            return Err(format!("Failed to queue subscriber: {}", err));
+        }
+    } else {
+        // Mailerlite API
+        let base_url = state.conf.email.base_url.clone();
+        let api_key = state.conf.email.api_key.clone();
+
+        let url = format!("{}/subscribers", base_url);
+        let response = state
+            .http
+            .post(&url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .json(&serde_json::json!({ "email": email, "groups": [ar_group_id] }))
+            .send()
+            .await;
+
+        match response {
+            Ok(res) if res.status().is_success() => state.metrics.emails_sent_total.inc(),
+            Ok(_) => state.metrics.emails_failed_total.inc(),
+            Err(err) => {
+                state.metrics.emails_failed_total.inc();
+                return Err(format!("Failed to send request to Mailerlite: {}", err));
+            }
+        }
     }
 
     let bson_doc = mongodb::bson::to_bson(&AddNewsletterRecord {
-        email: query.email,
-        address: query.address,
+        email,
+        address,
         source: "newsletter_subscription".to_string(),
     })
     .expect("Failed to serialize to BSON");
@@ -70,11 +188,11 @@ pub async fn handler(
     if let mongodb::bson::Bson::Document(document) = bson_doc {
         match collection.insert_one(document, None).await {
             Ok(_) => (),
-            Err(err) => return get_error(format!("Failed to insert document: {}", err)),
+            Err(err) => return Err(format!("Failed to insert document: {}", err)),
         }
     } else {
-        return get_error("Failed to create BSON document".to_string());
+        return Err("Failed to create BSON document".to_string());
     }
 
-    (StatusCode::OK, Json(Output { success: true })).into_response()
+    Ok(())
 }
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin, timestamp_range, to_hex},
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use starknet::core::types::FieldElement;
+
+#[derive(Deserialize)]
+pub struct SummaryQuery {
+    // sale timestamps in seconds, both bounds included
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    sponsor: String,
+    sales: i64,
+    commission: f64,
+}
+
+// The indexer may store an address with or without its leading zeros
+fn address_forms(address: FieldElement) -> Vec<String> {
+    vec![
+        to_hex(address),
+        format!("0x{}", hex::encode(address.to_bytes_be())),
+    ]
+}
+
+// Sales of the sponsor in the range grouped into a count and the sum of
+// price * sponsor_comm, a missing sponsor_comm earns nothing
+fn summary_pipeline(address: FieldElement, range: Option<Document>) -> Vec<Document> {
+    let mut filter = doc! { "sponsor": { "$in": address_forms(address) } };
+    if let Some(range) = range {
+        filter.insert("timestamp", range);
+    }
+    vec![
+        doc! { "$match": filter },
+        doc! {
+            "$group": {
+                "_id": Bson::Null,
+                "sales": { "$sum": 1 },
+                "commission": {
+                    "$sum": {
+                        "$multiply": [
+                            { "$toDouble": { "$ifNull": ["$price", 0] } },
+                            { "$toDouble": { "$ifNull": ["$sponsor_comm", 0] } },
+                        ]
+                    }
+                },
+            }
+        },
+    ]
+}
+
+pub fn number(document: &Document, key: &str) -> f64 {
+    match document.get(key) {
+        Some(Bson::Double(value)) => *value,
+        Some(Bson::Int32(value)) => *value as f64,
+        Some(Bson::Int64(value)) => *value as f64,
+        _ => 0.0,
+    }
+}
+
+// Commission owed to a sponsor, admin only
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+    Query(query): Query<SummaryQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+    let Ok(sponsor) = FieldElement::from_hex_be(&address) else {
+        return get_specific_error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid sponsor address: {}", address),
+        );
+    };
+    let range = match timestamp_range(query.from, query.to) {
+        Ok(range) => range,
+        Err(err) => return get_specific_error(StatusCode::BAD_REQUEST, err),
+    };
+
+    let pipeline = summary_pipeline(sponsor, range);
+    let groups: Vec<Document> = match state
+        .db
+        .collection::<Document>("sales")
+        .aggregate(pipeline, None)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(groups) => groups,
+            Err(err) => return get_error(format!("Failed to read the sales: {}", err)),
+        },
+        Err(err) => return get_error(format!("Failed to aggregate the sales: {}", err)),
+    };
+    // no group at all when the sponsor has no sale in the range
+    let (sales, commission) = groups.first().map_or((0, 0.0), |group| {
+        (number(group, "sales") as i64, number(group, "commission"))
+    });
+
+    (
+        StatusCode::OK,
+        Json(Output {
+            sponsor: to_hex(sponsor),
+            sales,
+            commission,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod sponsor_summary_tests {
+    use super::{address_forms, summary_pipeline};
+    use mongodb::bson::doc;
+    use starknet::core::types::FieldElement;
+
+    #[test]
+    fn test_address_forms() {
+        assert_eq!(
+            address_forms(FieldElement::from(0xab_u64)),
+            vec!["0xab".to_string(), format!("0x{}ab", "0".repeat(62))]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_range() {
+        let sponsor = FieldElement::from(0xab_u64);
+        let pipeline = summary_pipeline(sponsor, Some(doc! { "$gte": 10_i64 }));
+        assert_eq!(
+            pipeline[0],
+            doc! {
+                "$match": {
+                    "sponsor": { "$in": address_forms(sponsor) },
+                    "timestamp": { "$gte": 10_i64 },
+                }
+            }
+        );
+        let unbounded = summary_pipeline(sponsor, None);
+        let filter = unbounded[0].get_document("$match").unwrap();
+        assert!(filter.get("timestamp").is_none());
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use super::sponsor_summary::number;
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin, timestamp_range},
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    // sale timestamps in seconds, both bounds included
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    sales: i64,
+    revenue: f64,
+    auto_renew: i64,
+    pending_email: i64,
+}
+
+// Counts every figure in a single pass over the sales of the range, a sale
+// with metadata is pending until it has an entry in processed
+fn stats_pipeline(range: Option<Document>) -> Vec<Document> {
+    let mut pipeline = Vec::new();
+    if let Some(range) = range {
+        pipeline.push(doc! { "$match": { "timestamp": range } });
+    }
+    pipeline.push(doc! {
+        "$lookup": {
+            "from": "processed",
+            "let": { "meta_hash": "$meta_hash" },
+            "pipeline": [
+                { "$match": { "$expr": { "$eq": ["$meta_hash", "$$meta_hash"] } } },
+                { "$limit": 1 },
+                { "$project": { "_id": 1 } },
+            ],
+            "as": "processed_doc",
+        }
+    });
+    pipeline.push(doc! {
+        "$group": {
+            "_id": Bson::Null,
+            "sales": { "$sum": 1 },
+            "revenue": { "$sum": { "$toDouble": { "$ifNull": ["$price", 0] } } },
+            "auto_renew": { "$sum": { "$cond": [{ "$eq": ["$auto", true] }, 1, 0] } },
+            "pending_email": {
+                "$sum": {
+                    "$cond": [
+                        {
+                            "$and": [
+                                { "$gt": [{ "$ifNull": ["$meta_hash", ""] }, ""] },
+                                { "$eq": [{ "$size": "$processed_doc" }, 0] },
+                            ]
+                        },
+                        1,
+                        0,
+                    ]
+                }
+            },
+        }
+    });
+    pipeline
+}
+
+// Sales volume at a glance, admin only
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<StatsQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+    let range = match timestamp_range(query.from, query.to) {
+        Ok(range) => range,
+        Err(err) => return get_specific_error(StatusCode::BAD_REQUEST, err),
+    };
+
+    let groups: Vec<Document> = match state
+        .db
+        .collection::<Document>("sales")
+        .aggregate(stats_pipeline(range), None)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(groups) => groups,
+            Err(err) => return get_error(format!("Failed to read the sales: {}", err)),
+        },
+        Err(err) => return get_error(format!("Failed to aggregate the sales: {}", err)),
+    };
+    // no group at all when there is no sale in the range
+    let empty = Document::new();
+    let group = groups.first().unwrap_or(&empty);
+
+    (
+        StatusCode::OK,
+        Json(Output {
+            sales: number(group, "sales") as i64,
+            revenue: number(group, "revenue"),
+            auto_renew: number(group, "auto_renew") as i64,
+            pending_email: number(group, "pending_email") as i64,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::stats_pipeline;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_pipeline_range() {
+        let pipeline = stats_pipeline(Some(doc! { "$gte": 10_i64 }));
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(
+            pipeline[0],
+            doc! { "$match": { "timestamp": { "$gte": 10_i64 } } }
+        );
+        // every sale is counted without a range
+        let unbounded = stats_pipeline(None);
+        assert_eq!(unbounded.len(), 2);
+        assert!(unbounded[0].contains_key("$lookup"));
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use super::email_events::suppress;
+use crate::{
+    extract::ValidJson,
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin, redact_email},
+};
+use axum::{extract::State, http::HeaderMap, response::IntoResponse, Json};
+use email_address::EmailAddress;
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct SuppressQuery {
+    email: String,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+}
+
+// Blocks an address by hand, sale_actions skips it from its next run
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ValidJson(query): ValidJson<SuppressQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+    let email = query.email.trim();
+    if !EmailAddress::is_valid(email) {
+        return get_specific_error(StatusCode::BAD_REQUEST, "invalid email".to_string());
+    }
+    let reason = query.reason.as_deref().unwrap_or("manual");
+    if let Err(err) = suppress(&state.db, email, reason).await {
+        return get_error(format!("Failed to record the suppression: {}", err));
+    }
+    state.logger.info(format!(
+        "suppressed {} by hand: {}",
+        redact_email(email),
+        reason
+    ));
+
+    (StatusCode::OK, Json(Output { success: true })).into_response()
+}
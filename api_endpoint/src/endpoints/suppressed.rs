@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use super::{
+    admin_failed::{list, page, Output},
+    email_events::SUPPRESSED_COLLECTION,
+};
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, is_admin},
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, DateTime};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct SuppressedQuery {
+    limit: Option<i64>,
+    offset: Option<u64>,
+}
+
+// Address sale_actions no longer emails, added by /email_events or /suppress
+#[derive(Deserialize)]
+pub struct SuppressedDoc {
+    email: String,
+    reason: String,
+    suppressed_at: DateTime,
+}
+
+// Same as SuppressedDoc with the date in milliseconds
+#[derive(Serialize)]
+pub struct SuppressedOutput {
+    email: String,
+    reason: String,
+    suppressed_at: i64,
+}
+
+impl From<SuppressedDoc> for SuppressedOutput {
+    fn from(suppressed: SuppressedDoc) -> Self {
+        SuppressedOutput {
+            email: suppressed.email,
+            reason: suppressed.reason,
+            suppressed_at: suppressed.suppressed_at.timestamp_millis(),
+        }
+    }
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<SuppressedQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&headers, &state.conf.admin.token) {
+        return get_specific_error(StatusCode::UNAUTHORIZED, "invalid admin token".to_string());
+    }
+
+    let (limit, offset) = page(query.limit, query.offset);
+    let collection = state.db.collection::<SuppressedDoc>(SUPPRESSED_COLLECTION);
+
+    match list(
+        collection,
+        doc! {},
+        doc! { "suppressed_at": -1 },
+        limit,
+        offset,
+    )
+    .await
+    {
+        Ok(output) => (
+            StatusCode::OK,
+            Json(Output {
+                items: output
+                    .items
+                    .into_iter()
+                    .map(SuppressedOutput::from)
+                    .collect(),
+                total: output.total,
+                next: output.next,
+            }),
+        )
+            .into_response(),
+        Err(err) => get_error(format!("Failed to list suppressed emails: {}", err)),
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+
+use crate::{
+    models::AppState,
+    utils::{get_error, get_specific_error, redact_email},
+};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::UpdateOptions,
+};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+
+#[derive(Deserialize)]
+pub struct UnsubscribeQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+}
+
+fn mac(secret: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+// Email of a token signed with the secret that has not expired yet. The
+// tokens of the unsubscribe links are minted by sale_actions for every email:
+// hex email, expiry in unix seconds and the HMAC of both, so nothing is stored
+pub fn verify_token(secret: &str, token: &str, now: i64) -> Result<String, String> {
+    let invalid = || "invalid unsubscribe token".to_string();
+    let (payload, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+    let signature = hex::decode(signature).map_err(|_| invalid())?;
+    mac(secret, payload)
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+
+    let (email, expires_at) = payload.split_once('.').ok_or_else(invalid)?;
+    let expires_at: i64 = expires_at.parse().map_err(|_| invalid())?;
+    if now > expires_at {
+        return Err("unsubscribe token expired".to_string());
+    }
+    let email = hex::decode(email).map_err(|_| invalid())?;
+    String::from_utf8(email).map_err(|_| invalid())
+}
+
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> impl IntoResponse {
+    let Some(unsubscribe) = &state.conf.unsubscribe else {
+        return get_specific_error(StatusCode::NOT_FOUND, "unsubscribe is disabled".to_string());
+    };
+    let email = match verify_token(
+        &unsubscribe.secret,
+        &query.token,
+        DateTime::now().timestamp_millis() / 1000,
+    ) {
+        Ok(email) => email,
+        Err(err) => return get_specific_error(StatusCode::BAD_REQUEST, err),
+    };
+
+    // Mailerlite stops emailing the subscriber in every group
+    let response = state
+        .http
+        .post(format!("{}/subscribers", state.conf.email.base_url))
+        .header("content-type", "application/json")
+        .header("accept", "application/json")
+        .header(
+            "Authorization",
+            format!("Bearer {}", state.conf.email.api_key),
+        )
+        .json(&json!({ "email": email, "status": "unsubscribed" }))
+        .send()
+        .await;
+    match response {
+        Ok(res) if res.status().is_success() => (),
+        Ok(res) => {
+            return get_error(format!(
+                "Mailerlite refused to unsubscribe: {}",
+                res.status()
+            ))
+        }
+        Err(err) => return get_error(format!("Failed to send request to Mailerlite: {}", err)),
+    }
+
+    let options = UpdateOptions::builder().upsert(true).build();
+    if let Err(err) = state
+        .db
+        .collection::<mongodb::bson::Document>("newsletter")
+        .update_one(
+            doc! { "email": &email },
+            doc! { "$set": { "unsubscribed": true, "unsubscribed_at": DateTime::now() } },
+            options,
+        )
+        .await
+    {
+        return get_error(format!("Failed to record the unsubscription: {}", err));
+    }
+    state
+        .logger
+        .info(format!("unsubscribed {}", redact_email(&email)));
+
+    (StatusCode::OK, Json(Output { success: true })).into_response()
+}
+
+#[cfg(test)]
+mod unsubscribe_tests {
+    use super::{mac, verify_token};
+    use hmac::Mac;
+
+    const NOW: i64 = 1_700_000_000;
+
+    // Same tokens as the ones of sale_actions
+    fn sign_token(secret: &str, email: &str, expires_at: i64) -> String {
+        let payload = format!("{}.{}", hex::encode(email), expires_at);
+        let signature = hex::encode(mac(secret, &payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    #[test]
+    fn test_token_of_sale_actions() {
+        let token = "616c696365406578616d706c652e636f6d.1700000060.\
+                     1d68d9d4938a7d9f2a1b615760d23efa12aa2595d76e6fec15cba12cb98344d6";
+        assert_eq!(
+            verify_token("secret", token, NOW),
+            Ok("alice@example.com".to_string())
+        );
+        assert_eq!(sign_token("secret", "alice@example.com", NOW + 60), token);
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let token = sign_token("secret", "alice@example.com", NOW + 60);
+        assert_eq!(
+            verify_token("secret", &token, NOW),
+            Ok("alice@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let token = sign_token("secret", "alice@example.com", NOW - 1);
+        assert!(verify_token("secret", &token, NOW).is_err());
+    }
+
+    #[test]
+    fn test_tampered_token() {
+        let token = sign_token("secret", "alice@example.com", NOW + 60);
+        assert!(verify_token("other secret", &token, NOW).is_err());
+
+        // another email or a later expiry with the original signature
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let forged = format!(
+            "{}.{}.{}",
+            hex::encode("bob@example.com"),
+            NOW + 60,
+            signature
+        );
+        assert!(verify_token("secret", &forged, NOW).is_err());
+        let extended = token.replace(&(NOW + 60).to_string(), &(NOW + 6000).to_string());
+        assert!(verify_token("secret", &extended, NOW).is_err());
+
+        assert!(verify_token("secret", "garbage", NOW).is_err());
+    }
+}
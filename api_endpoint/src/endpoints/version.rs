@@ -0,0 +1,39 @@
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use serde_derive::Serialize;
+
+// Set when building, e.g. by the Dockerfile build args, "unknown" otherwise
+const GIT_SHA: Option<&str> = option_env!("GIT_SHA");
+const BUILD_TIME: Option<&str> = option_env!("BUILD_TIME");
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Output {
+    version: &'static str,
+    git_sha: &'static str,
+    build_time: &'static str,
+}
+
+pub fn build_info() -> Output {
+    Output {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: GIT_SHA.unwrap_or("unknown"),
+        build_time: BUILD_TIME.unwrap_or("unknown"),
+    }
+}
+
+// Build that is live, for the deployment tooling. / stays the human answer
+pub async fn handler() -> impl IntoResponse {
+    (StatusCode::OK, Json(build_info()))
+}
+
+#[cfg(test)]
+mod version_tests {
+    use super::build_info;
+
+    #[test]
+    fn test_build_info() {
+        let info = serde_json::to_value(build_info()).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!info["git_sha"].as_str().unwrap().is_empty());
+        assert!(!info["build_time"].as_str().unwrap().is_empty());
+    }
+}
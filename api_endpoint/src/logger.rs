@@ -2,15 +2,60 @@ use chrono::Utc;
 use reqwest;
 use serde_derive::Serialize;
 use std::borrow::Cow;
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer, Registry,
+};
 
 use crate::config::Watchtower;
 
+// Logs waiting to be posted to watchtower, the next ones are dropped when
+// the queue is full
+const QUEUE_CAPACITY: usize = 1024;
+// Logs taken from the queue at once by the flush task
+const FLUSH_BATCH: usize = 64;
+
+// Levels accepted by watchtower.min_level, from the most verbose
+pub const LEVELS: [&str; 4] = ["local", "info", "warning", "severe"];
+
+fn level_rank(level: &str) -> usize {
+    LEVELS.iter().position(|&known| known == level).unwrap_or(0)
+}
+
 // Logger structure
 pub struct Logger {
-    enabled: bool,
+    json: bool,
+    min_level: usize,
     config: Arc<Watchtower>,
-    client: Arc<reqwest::Client>,
+    queue: Option<mpsc::Sender<Queued>>,
+    dropped: Arc<AtomicU64>,
+}
+
+struct Entry {
+    log_type: LogType,
+    message: String,
+    timestamp: i64,
+}
+
+enum Queued {
+    Log(Entry),
+    // answered once every log queued before it is posted
+    Flush(oneshot::Sender<()>),
 }
 
 // Enum for log types
@@ -21,6 +66,76 @@ pub enum LogType {
     Severe,
 }
 
+// Console line for a log, a single-line JSON object when watchtower.format
+// is "json" so aggregators can parse it
+fn format_line(json: bool, level: &str, correlation_id: Option<&str>, message: &str) -> String {
+    if json {
+        serde_json::json!({
+            "level": level,
+            "message": message,
+            "timestamp": Utc::now().timestamp_millis(),
+            "service": env!("CARGO_PKG_NAME"),
+            "correlation_id": correlation_id,
+        })
+        .to_string()
+    } else {
+        match correlation_id {
+            Some(id) => format!("{} [{}]: {}", level.to_uppercase(), id, message),
+            None => format!("{}: {}", level.to_uppercase(), message),
+        }
+    }
+}
+
+// Id of a request or a run, given to its span as the correlation_id field
+struct CorrelationId(String);
+
+struct CorrelationVisitor(Option<String>);
+
+impl Visit for CorrelationVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "correlation_id" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "correlation_id" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+// Keeps the correlation_id of the spans so the Logger can print it
+pub struct CorrelationLayer;
+
+impl<S> Layer<S> for CorrelationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = CorrelationVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(correlation_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(CorrelationId(correlation_id));
+        }
+    }
+}
+
+// Correlation id of the innermost current span carrying one
+pub fn current_correlation_id() -> Option<String> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+            let correlation_id = span.scope().find_map(|span| {
+                span.extensions()
+                    .get::<CorrelationId>()
+                    .map(|correlation_id| correlation_id.0.clone())
+            });
+            correlation_id
+        })
+        .flatten()
+}
+
 #[derive(Serialize)]
 struct LogData<'a> {
     token: &'a str,
@@ -35,45 +150,139 @@ struct LogPayload<'a> {
     timestamp: i64,
 }
 
+async fn post_log(client: &reqwest::Client, config: &Watchtower, entry: Entry) {
+    let data = LogData {
+        token: &config.token,
+        log: LogPayload {
+            app_id: &config.app_id,
+            r#type: match entry.log_type {
+                LogType::Info => &config.types.info,
+                LogType::Warning => &config.types.warning,
+                LogType::Severe => &config.types.severe,
+            },
+            message: Cow::Owned(entry.message),
+            timestamp: entry.timestamp,
+        },
+    };
+
+    let response = client.post(&config.endpoint).json(&data).send().await;
+
+    match response {
+        Ok(res) if res.status().is_success() => (),
+        Ok(res) => eprintln!(
+            "Failed to post log: {:?}",
+            res.text().await.unwrap_or_default()
+        ),
+        Err(err) => eprintln!("Failed to post log: {:?}", err),
+    }
+}
+
+// Posts the queued logs one after the other so they reach watchtower in the
+// order they were emitted
+async fn flush_loop(
+    config: Arc<Watchtower>,
+    mut queue: mpsc::Receiver<Queued>,
+    dropped: Arc<AtomicU64>,
+) {
+    let client = reqwest::Client::new();
+    while let Some(first) = queue.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < FLUSH_BATCH {
+            match queue.try_recv() {
+                Ok(queued) => batch.push(queued),
+                Err(_) => break,
+            }
+        }
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            let entry = Entry {
+                log_type: LogType::Warning,
+                message: format!("{} logs dropped, the watchtower queue was full", lost),
+                timestamp: Utc::now().timestamp_millis(),
+            };
+            post_log(&client, &config, entry).await;
+        }
+        for queued in batch {
+            match queued {
+                Queued::Log(entry) => post_log(&client, &config, entry).await,
+                Queued::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+}
+
 impl Logger {
     pub fn new(config: &Watchtower) -> Self {
-        env_logger::init();
+        // ignore the error raised if a logger was already initialized
+        let _ = env_logger::try_init();
+        let _ = tracing::subscriber::set_global_default(Registry::default().with(CorrelationLayer));
+        let config = Arc::new(config.clone());
+        let dropped = Arc::new(AtomicU64::new(0));
+        // a logger built outside of a runtime only prints
+        let queue = match Handle::try_current() {
+            Ok(runtime) if config.enabled => {
+                let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+                runtime.spawn(flush_loop(config.clone(), receiver, dropped.clone()));
+                Some(sender)
+            }
+            _ => None,
+        };
         Logger {
-            enabled: config.enabled,
-            config: Arc::new(config.clone()),
-            client: Arc::new(reqwest::Client::new()),
+            json: config.format.as_deref() == Some("json"),
+            // everything is emitted unless a level is set
+            min_level: config.min_level.as_deref().map_or(0, level_rank),
+            config,
+            queue,
+            dropped,
         }
     }
 
-    async fn post_log(&self, log_type: LogType, message: Cow<'static, str>) {
-        let config = Arc::clone(&self.config);
-        let client = Arc::clone(&self.client);
-
-        let message_owned = message.into_owned();
+    fn is_enabled(&self, level: &str) -> bool {
+        level_rank(level) >= self.min_level
+    }
 
-        let data = LogData {
-            token: &config.token,
-            log: LogPayload {
-                app_id: &config.app_id,
-                r#type: match log_type {
-                    LogType::Info => &config.types.info,
-                    LogType::Warning => &config.types.warning,
-                    LogType::Severe => &config.types.severe,
-                },
-                message: Cow::Owned(message_owned),
-                timestamp: Utc::now().timestamp_millis(),
-            },
+    // Prints the log and queues it for watchtower, never waits on the queue
+    fn emit(&self, log_type: LogType, message: Cow<'static, str>) {
+        let correlation_id = current_correlation_id();
+        let level = match log_type {
+            LogType::Info => "info",
+            LogType::Warning => "warning",
+            LogType::Severe => "severe",
         };
+        if !self.is_enabled(level) {
+            return;
+        }
+        println!(
+            "{}",
+            format_line(self.json, level, correlation_id.as_deref(), &message)
+        );
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let message = match correlation_id {
+            Some(id) => format!("[{}] {}", id, message),
+            None => message.into_owned(),
+        };
+        let entry = Entry {
+            log_type,
+            message,
+            timestamp: Utc::now().timestamp_millis(),
+        };
+        if queue.try_send(Queued::Log(entry)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-        let response = client.post(&config.endpoint).json(&data).send().await;
-
-        match response {
-            Ok(res) if res.status().is_success() => (),
-            Ok(res) => eprintln!(
-                "Failed to post log: {:?}",
-                res.text().await.unwrap_or_default()
-            ),
-            Err(err) => eprintln!("Failed to post log: {:?}", err),
+    // Waits until the logs queued so far are posted, before exiting
+    pub async fn flush(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let (done, flushed) = oneshot::channel();
+        if queue.send(Queued::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
         }
     }
 
@@ -81,60 +290,42 @@ impl Logger {
     where
         S: Into<Cow<'static, str>> + std::fmt::Display + Send + 'static,
     {
-        println!("INFO: {}", &message);
-        if self.config.enabled {
-            self.post_log(LogType::Info, message.into()).await;
-        }
+        self.emit(LogType::Info, message.into());
     }
 
     pub async fn async_warning<S>(&self, message: S)
     where
         S: Into<Cow<'static, str>> + std::fmt::Display + Send + 'static,
     {
-        println!("WARNING: {}", &message);
-        if self.config.enabled {
-            self.post_log(LogType::Warning, message.into()).await;
-        }
+        self.emit(LogType::Warning, message.into());
     }
 
     pub async fn async_severe<S>(&self, message: S)
     where
         S: Into<Cow<'static, str>> + std::fmt::Display + Send + 'static,
     {
-        println!("SEVERE: {}", &message);
-        if self.config.enabled {
-            self.post_log(LogType::Severe, message.into()).await;
-        }
+        self.emit(LogType::Severe, message.into());
     }
 
     pub fn info<S>(&self, message: S)
     where
         S: Into<Cow<'static, str>> + std::fmt::Display + Send + 'static,
     {
-        let logger_clone = self.clone();
-        tokio::spawn(async move {
-            logger_clone.async_info(message).await;
-        });
+        self.emit(LogType::Info, message.into());
     }
 
     pub fn warning<S>(&self, message: S)
     where
         S: Into<Cow<'static, str>> + std::fmt::Display + Send + 'static,
     {
-        let logger_clone = self.clone();
-        tokio::spawn(async move {
-            logger_clone.async_warning(message).await;
-        });
+        self.emit(LogType::Warning, message.into());
     }
 
     pub fn severe<S>(&self, message: S)
     where
         S: Into<Cow<'static, str>> + std::fmt::Display + Send + 'static,
     {
-        let logger_clone = self.clone();
-        tokio::spawn(async move {
-            logger_clone.async_severe(message).await;
-        });
+        self.emit(LogType::Severe, message.into());
     }
 
     #[allow(dead_code)]
@@ -142,16 +333,127 @@ impl Logger {
     where
         S: Into<Cow<'static, str>> + std::fmt::Display,
     {
-        println!("{}", &message);
+        if !self.is_enabled("local") {
+            return;
+        }
+        if self.json {
+            let correlation_id = current_correlation_id();
+            let message = message.to_string();
+            println!(
+                "{}",
+                format_line(true, "local", correlation_id.as_deref(), &message)
+            );
+        } else {
+            println!("{}", &message);
+        }
     }
 }
 
 impl Clone for Logger {
     fn clone(&self) -> Self {
         Logger {
-            enabled: self.enabled,
+            json: self.json,
+            min_level: self.min_level,
             config: Arc::clone(&self.config),
-            client: Arc::clone(&self.client),
+            queue: self.queue.clone(),
+            dropped: Arc::clone(&self.dropped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod logger_tests {
+    use super::{current_correlation_id, format_line, CorrelationLayer, Logger, Queued, LEVELS};
+    use crate::config::Config;
+    use std::sync::atomic::Ordering;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn test_text_format() {
+        assert_eq!(format_line(false, "info", None, "started"), "INFO: started");
+        assert_eq!(
+            format_line(false, "info", Some("abc"), "started"),
+            "INFO [abc]: started"
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let line = format_line(true, "severe", Some("abc"), "unable to connect");
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "severe");
+        assert_eq!(value["message"], "unable to connect");
+        assert_eq!(value["service"], env!("CARGO_PKG_NAME"));
+        assert_eq!(value["correlation_id"], "abc");
+        assert!(value["timestamp"].is_i64());
+    }
+
+    #[test]
+    fn test_correlation_id_of_current_span() {
+        let subscriber = Registry::default().with(CorrelationLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(current_correlation_id(), None);
+            let run = tracing::info_span!("run", correlation_id = %"run-1");
+            let _run = run.enter();
+            assert_eq!(current_correlation_id(), Some("run-1".to_string()));
+            // nested spans without an id keep the one of their parent
+            let batch = tracing::info_span!("batch", size = 10);
+            let _batch = batch.enter();
+            assert_eq!(current_correlation_id(), Some("run-1".to_string()));
+        });
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_instead_of_waiting() {
+        let mut conf: Config = toml::from_str(include_str!("../config.template.toml")).unwrap();
+        conf.watchtower.enabled = false;
+        let logger = Logger::new(&conf.watchtower);
+        let (sender, mut receiver) = mpsc::channel(2);
+        let logger = Logger {
+            queue: Some(sender),
+            ..logger
+        };
+        logger.info("first");
+        logger.severe("second");
+        logger.info("third");
+        assert_eq!(logger.dropped.load(Ordering::Relaxed), 1);
+
+        let mut messages = Vec::new();
+        while let Ok(Queued::Log(entry)) = receiver.try_recv() {
+            messages.push(entry.message);
+        }
+        assert_eq!(messages, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_min_level() {
+        let mut conf: Config = toml::from_str(include_str!("../config.template.toml")).unwrap();
+        conf.watchtower.enabled = false;
+        assert!(LEVELS
+            .iter()
+            .all(|&level| Logger::new(&conf.watchtower).is_enabled(level)));
+
+        conf.watchtower.min_level = Some("warning".to_string());
+        let logger = Logger::new(&conf.watchtower);
+        assert!(!logger.is_enabled("local"));
+        assert!(!logger.is_enabled("info"));
+        assert!(logger.is_enabled("warning"));
+        assert!(logger.is_enabled("severe"));
+
+        // filtered logs never reach the queue
+        let (sender, mut receiver) = mpsc::channel(4);
+        let logger = Logger {
+            queue: Some(sender),
+            ..logger
+        };
+        logger.info("skipped");
+        logger.severe("kept");
+        match receiver.try_recv() {
+            Ok(Queued::Log(entry)) => assert_eq!(entry.message, "kept"),
+            _ => panic!("expected the severe log"),
         }
+        assert!(receiver.try_recv().is_err());
     }
 }
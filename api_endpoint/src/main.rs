@@ -1,25 +1,68 @@
 #[macro_use]
 mod utils;
+mod captcha;
 mod config;
 mod endpoints;
+mod extract;
 mod logger;
+mod maintenance;
+mod metrics;
 mod models;
+mod rate_limit;
+mod request_id;
 use axum::{
-    http::StatusCode,
+    extract::State,
+    http::{header, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Json, Router,
 };
 use logger::Logger;
-use mongodb::{bson::doc, options::ClientOptions, Client};
+use mongodb::{
+    bson::{doc, Document},
+    options::{ClientOptions, IndexOptions},
+    Client, Database, IndexModel,
+};
+use serde_json::json;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
+use tokio::{
+    signal,
+    sync::oneshot,
+    time::{sleep, Duration},
+};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::CompressionLayer,
+    cors::{Any, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    timeout::TimeoutLayer,
+};
+
+const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024;
 
 #[tokio::main]
 async fn main() {
     let conf = config::load();
+    if let Err(problems) = conf.validate() {
+        for problem in problems {
+            eprintln!("error: invalid config: {}", problem);
+        }
+        std::process::exit(1);
+    }
     let logger = Logger::new(&conf.watchtower);
-    logger.info(format!("starting v{} of api_endpoint", env!("CARGO_PKG_VERSION")));
+    run(conf, &logger).await;
+    // the last logs usually explain why it stopped
+    logger.flush().await;
+}
+
+// Serves until shutdown or until a startup step fails
+async fn run(conf: config::Config, logger: &Logger) {
+    logger.info(format!(
+        "starting v{} of api_endpoint",
+        env!("CARGO_PKG_VERSION")
+    ));
     let client_options = ClientOptions::parse(&conf.database.connection_string)
         .await
         .unwrap();
@@ -29,37 +72,365 @@ async fn main() {
         db: Client::with_options(client_options)
             .unwrap()
             .database(&conf.database.name),
+        metrics: metrics::Metrics::new(),
+        rate_limiter: rate_limit::IpRateLimiter::new(
+            conf.server
+                .requests_per_minute
+                .unwrap_or(rate_limit::DEFAULT_REQUESTS_PER_MINUTE),
+            conf.server
+                .rate_limit_clients
+                .unwrap_or(rate_limit::DEFAULT_TRACKED_CLIENTS),
+        ),
+        maintenance: Arc::new(maintenance::Maintenance::new(
+            conf.server.maintenance.unwrap_or(false),
+            conf.server
+                .maintenance_retry_after_secs
+                .unwrap_or(maintenance::DEFAULT_RETRY_AFTER_SECS),
+        )),
+        http: utils::http_client(),
     });
-    if shared_state
+    if shared_state.maintenance.is_enabled() {
+        logger.warning("maintenance mode: on, writes are refused");
+    }
+    if !wait_for_database(&shared_state.db, &conf.database, logger).await {
+        return;
+    }
+    logger.info("database: connected");
+
+    // add_metadata upserts on meta_hash, the index settles concurrent retries
+    let meta_hash_index = IndexModel::builder()
+        .keys(doc! { "meta_hash": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    if let Err(e) = shared_state
         .db
-        .run_command(doc! {"ping": 1}, None)
+        .collection::<Document>("metadata")
+        .create_index(meta_hash_index, None)
         .await
-        .is_err()
     {
-        logger.severe("unable to connect to database");
-        return;
-    } else {
-        logger.info("database: connected")
+        logger.warning(format!(
+            "unable to create the metadata meta_hash index: {}",
+            e
+        ));
     }
 
-    let cors = CorsLayer::new().allow_headers(Any).allow_origin(Any);
-    let app = Router::new()
-        .route("/", get(root))
+    // add_metadata looks up a salt reused for another email
+    let salt_index = IndexModel::builder().keys(doc! { "salt": 1 }).build();
+    if let Err(e) = shared_state
+        .db
+        .collection::<Document>("metadata")
+        .create_index(salt_index, None)
+        .await
+    {
+        logger.warning(format!("unable to create the metadata salt index: {}", e));
+    }
+
+    // email_events upserts on the address
+    let suppressed_index = IndexModel::builder()
+        .keys(doc! { "email": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    if let Err(e) = shared_state
+        .db
+        .collection::<Document>(endpoints::email_events::SUPPRESSED_COLLECTION)
+        .create_index(suppressed_index, None)
+        .await
+    {
+        logger.warning(format!(
+            "unable to create the suppressed_emails email index: {}",
+            e
+        ));
+    }
+
+    // Unconfirmed newsletter subscriptions expire with their token
+    if let Some(newsletter) = &conf.newsletter {
+        let ttl_hours = newsletter
+            .token_ttl_hours
+            .unwrap_or(endpoints::newsletter_confirm::DEFAULT_TOKEN_TTL_HOURS);
+        let expiry_index = IndexModel::builder()
+            .keys(doc! { "created_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(ttl_hours * 3600))
+                    .build(),
+            )
+            .build();
+        if let Err(e) = shared_state
+            .db
+            .collection::<Document>(endpoints::newsletter_subscribe::PENDING_COLLECTION)
+            .create_index(expiry_index, None)
+            .await
+        {
+            logger.warning(format!(
+                "unable to create the newsletter_pending expiry index: {}",
+                e
+            ));
+        }
+    }
+
+    let app = match app(shared_state) {
+        Ok(app) => app,
+        Err(e) => {
+            logger.severe(e);
+            return;
+        }
+    };
+
+    let addr = match conf.server.bind_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            logger.severe(e);
+            return;
+        }
+    };
+    logger.info(format!("listening on http://{}", addr));
+    let (draining, drain_started) = oneshot::channel();
+    let shutdown_logger = logger.clone();
+    let server = axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            shutdown_logger.info("shutting down gracefully");
+            let _ = draining.send(());
+        });
+
+    // Requests still running after the timeout are dropped
+    let drain_timeout = Duration::from_secs(conf.server.shutdown_timeout_secs.unwrap_or(30));
+    tokio::select! {
+        result = server => {
+            if let Err(e) = result {
+                logger.severe(format!("server error: {}", e));
+            }
+        }
+        _ = async {
+            if drain_started.await.is_ok() {
+                sleep(drain_timeout).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        } => {
+            logger.warning(format!(
+                "requests still in flight after {}s, exiting",
+                drain_timeout.as_secs()
+            ));
+        }
+    }
+}
+
+// Every route of the API behind the layers it is served with
+fn app(state: Arc<models::AppState>) -> Result<Router, String> {
+    let conf = state.conf.clone();
+    let logger = state.logger.clone();
+    // Unauthenticated writes, rate limited per client IP
+    let public = Router::new()
         .route("/add_metadata", post(endpoints::add_metadata::handler))
+        .route(
+            "/add_metadata/batch",
+            post(endpoints::add_metadata_batch::handler),
+        )
         .route("/mail_subscribe", post(endpoints::mail_subscribe::handler))
         .route(
             "/newsletter_subscribe",
             post(endpoints::newsletter_subscribe::handler),
         )
-        .with_state(shared_state)
-        .layer(cors);
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_by_ip,
+        ));
+    // Request shapes may only change under a new version prefix
+    let v1 = Router::new()
+        .merge(public)
+        // Links opened from the emails, not limited since many recipients
+        // may share the IP of a NAT
+        .route(
+            "/newsletter_confirm",
+            get(endpoints::newsletter_confirm::handler),
+        )
+        .route("/unsubscribe", get(endpoints::unsubscribe::handler))
+        .route("/admin/failed", get(endpoints::admin_failed::handler))
+        .route("/admin/runs", get(endpoints::admin_runs::handler))
+        .route("/backfill", post(endpoints::backfill::handler))
+        // Provider webhooks, authenticated by their signature
+        .route("/email_events", post(endpoints::email_events::handler))
+        .route("/failed_emails", get(endpoints::failed_emails::handler))
+        .route(
+            "/failed_emails/redrive",
+            post(endpoints::failed_emails_redrive::handler),
+        )
+        .route(
+            "/metadata/:meta_hash",
+            get(endpoints::get_metadata::handler).delete(endpoints::delete_metadata::handler),
+        )
+        .route(
+            "/sponsors/:address/summary",
+            get(endpoints::sponsor_summary::handler),
+        )
+        .route("/stats", get(endpoints::stats::handler))
+        .route("/suppress", post(endpoints::suppress::handler))
+        .route("/suppressed", get(endpoints::suppressed::handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.maintenance.clone(),
+            maintenance::reject_writes,
+        ))
+        // Added after the maintenance layer so it stays reachable
+        .route(
+            "/admin/maintenance",
+            post(endpoints::admin_maintenance::handler),
+        );
+    let routes = Router::new()
+        .route("/", get(root))
+        .route("/health", get(endpoints::health::handler))
+        .route("/metrics", get(metrics::handler))
+        .route("/version", get(endpoints::version::handler))
+        .nest("/v1", v1.clone())
+        .merge(v1.route_layer(middleware::from_fn_with_state(
+            logger.clone(),
+            deprecated_alias,
+        )))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_latency,
+        ))
+        .with_state(state);
+    with_layers(routes, &conf.server, &logger)
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], conf.server.port));
-    logger.info(format!("listening on http://0.0.0.0:{}", conf.server.port,));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+// Layers every request goes through, around the routes
+fn with_layers(routes: Router, conf: &config::Server, logger: &Logger) -> Result<Router, String> {
+    Ok(routes
+        .layer(RequestBodyLimitLayer::new(
+            conf.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+        ))
+        // A handler stuck on a degraded database does not hold its connection
+        .layer(TimeoutLayer::new(Duration::from_secs(
+            conf.request_timeout_secs.unwrap_or(30),
+        )))
+        // Inside track_request so the panic is logged with the request id
+        .layer(CatchPanicLayer::custom({
+            let logger = logger.clone();
+            move |panic| panic_response(&logger, panic)
+        }))
+        .layer(middleware::from_fn(request_id::track_request))
+        // gzip or br when the client accepts it, small bodies are sent as is
+        .layer(CompressionLayer::new())
+        .layer(cors_layer(conf)?))
+}
+
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Wait after the failed ping `attempt` (starting at 1), doubling each time
+fn connect_delay(base_ms: u64, attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis(base_ms.saturating_mul(factor)).min(MAX_CONNECT_DELAY)
+}
+
+// Pings the database until it answers, it may come up after the service
+async fn wait_for_database(db: &Database, conf: &config::Database, logger: &Logger) -> bool {
+    let attempts = conf.connect_attempts.unwrap_or(5).max(1);
+    let base_ms = conf.connect_delay_ms.unwrap_or(1000);
+    for attempt in 1..=attempts {
+        match db.run_command(doc! {"ping": 1}, None).await {
+            Ok(_) => return true,
+            Err(e) if attempt < attempts => {
+                let delay = connect_delay(base_ms, attempt);
+                logger.warning(format!(
+                    "database ping failed (attempt {}/{}): {}, retrying in {}ms",
+                    attempt,
+                    attempts,
+                    e,
+                    delay.as_millis()
+                ));
+                sleep(delay).await;
+            }
+            Err(e) => logger.severe(format!(
+                "unable to connect to database after {} attempts: {}",
+                attempts, e
+            )),
+        }
+    }
+    false
+}
+
+// A panicking handler answers 500 instead of dropping the connection
+fn panic_response(logger: &Logger, panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let message = if let Some(message) = panic.downcast_ref::<String>() {
+        message.as_str()
+    } else if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else {
+        "unknown panic"
+    };
+    logger.severe(format!("handler panicked: {}", message));
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": "internal server error" })),
+    )
+        .into_response()
+}
+
+// The unversioned paths stay until every client has moved to /v1, their
+// answers point to the /v1 path and each use is logged to follow the migration
+async fn deprecated_alias<B>(
+    State(logger): State<Logger>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&format!("</v1{}>; rel=\"successor-version\"", path)) {
+        headers.insert(header::LINK, link);
+    }
+    logger.info(format!(
+        "deprecated path {} used instead of /v1{}",
+        path, path
+    ));
+    response
+}
+
+// Browsers may only call the API from server.allowed_origins, any origin is
+// allowed while the list is empty
+fn cors_layer(conf: &config::Server) -> Result<CorsLayer, String> {
+    let cors = CorsLayer::new()
+        .allow_headers(Any)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .expose_headers([HeaderName::from_static(request_id::REQUEST_ID_HEADER)]);
+    let origins = conf
+        .allowed_origins
+        .iter()
+        .flatten()
+        .map(|origin| {
+            HeaderValue::from_str(origin)
+                .map_err(|_| format!("invalid server.allowed_origins entry \"{}\"", origin))
+        })
+        .collect::<Result<Vec<HeaderValue>, String>>()?;
+    Ok(if origins.is_empty() {
+        cors.allow_origin(Any)
+    } else {
+        cors.allow_origin(origins)
+    })
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 async fn root() -> (StatusCode, String) {
@@ -68,3 +439,270 @@ async fn root() -> (StatusCode, String) {
         format!("server v{}", env!("CARGO_PKG_VERSION")),
     )
 }
+
+#[cfg(test)]
+mod main_tests {
+    use super::{
+        app, connect_delay, cors_layer, deprecated_alias, with_layers, DEFAULT_MAX_BODY_BYTES,
+    };
+    use crate::{
+        config::{Config, Server},
+        logger::Logger,
+        maintenance::Maintenance,
+        metrics::Metrics,
+        models::AppState,
+        rate_limit::IpRateLimiter,
+        request_id, utils,
+    };
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{header, Method, Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use mongodb::Client;
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::time::Duration;
+    use tower::ServiceExt;
+
+    fn test_config() -> Config {
+        let mut conf: Config = toml::from_str(include_str!("../config.template.toml")).unwrap();
+        conf.watchtower.enabled = false;
+        conf
+    }
+
+    // Nothing listens on the database port, a handler querying it hangs until
+    // the server selection times out
+    async fn test_state(conf: Config) -> Arc<AppState> {
+        let db = Client::with_uri_str("mongodb://127.0.0.1:1")
+            .await
+            .unwrap()
+            .database("test");
+        Arc::new(AppState {
+            logger: Logger::new(&conf.watchtower),
+            rate_limiter: IpRateLimiter::new(conf.server.requests_per_minute.unwrap_or(1000), 10),
+            conf,
+            db,
+            metrics: Metrics::new(),
+            maintenance: Arc::new(Maintenance::new(false, 60)),
+            http: utils::http_client(),
+        })
+    }
+
+    // The address the server attaches to the requests it accepts
+    fn from_client(mut request: Request<Body>) -> Request<Body> {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        request
+    }
+
+    async fn post_json(len: usize) -> StatusCode {
+        let mut conf = test_config();
+        conf.server.max_body_bytes = None;
+        let app = app(test_state(conf).await).unwrap();
+        // a JSON string of exactly len bytes
+        let body = format!("\"{}\"", "a".repeat(len - 2));
+        let request = Request::post("/v1/add_metadata")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        app.oneshot(from_client(request)).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_spares_email_links() {
+        let mut conf = test_config();
+        conf.server.requests_per_minute = Some(1);
+        let app = app(test_state(conf).await).unwrap();
+        let link = |uri: &str| from_client(Request::get(uri).body(Body::empty()).unwrap());
+        for _ in 0..3 {
+            let response = app
+                .clone()
+                .oneshot(link("/v1/unsubscribe?token=invalid"))
+                .await
+                .unwrap();
+            assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let post = || {
+            from_client(
+                Request::post("/v1/add_metadata")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+        };
+        let response = app.clone().oneshot(post()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app.oneshot(post()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_body_within_limit() {
+        // read by add_metadata, which refuses a JSON string
+        assert_eq!(
+            post_json(DEFAULT_MAX_BODY_BYTES).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_is_rejected() {
+        assert_eq!(
+            post_json(DEFAULT_MAX_BODY_BYTES + 1).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    fn server(allowed_origins: Vec<&str>) -> Server {
+        Server {
+            host: None,
+            port: 8080,
+            shutdown_timeout_secs: None,
+            max_body_bytes: None,
+            allowed_origins: Some(allowed_origins.into_iter().map(String::from).collect()),
+            requests_per_minute: None,
+            rate_limit_clients: None,
+            request_timeout_secs: None,
+            maintenance: None,
+            maintenance_retry_after_secs: None,
+        }
+    }
+
+    async fn preflight(allowed_origins: Vec<&str>, origin: &str) -> Option<String> {
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(cors_layer(&server(allowed_origins)).unwrap());
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_cors_allow_list() {
+        let allowed = vec!["https://app.starknet.id"];
+        assert_eq!(
+            preflight(allowed.clone(), "https://app.starknet.id").await,
+            Some("https://app.starknet.id".to_string())
+        );
+        assert_eq!(preflight(allowed, "https://evil.example").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_cors_any_origin_when_empty() {
+        assert_eq!(
+            preflight(vec![], "https://evil.example").await,
+            Some("*".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cors_invalid_origin() {
+        assert!(cors_layer(&server(vec!["https://app\n.starknet.id"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_panic_answers_500() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let routes = Router::new().route(
+            "/panic",
+            get(|| async {
+                let missing: Option<&str> = None;
+                missing.unwrap()
+            }),
+        );
+        let app = with_layers(routes, &conf.server, &logger).unwrap();
+        let request = Request::get("/panic").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response
+            .headers()
+            .contains_key(request_id::REQUEST_ID_HEADER));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "internal server error" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_times_out() {
+        let mut conf = test_config();
+        conf.server.request_timeout_secs = Some(1);
+        let app = app(test_state(conf).await).unwrap();
+        // the database ping of /health waits on the unreachable database
+        let request = Request::get("/health").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_compressed() {
+        // the metrics export is large enough to be compressed
+        let app = app(test_state(test_config()).await).unwrap();
+        let request = |encoding: Option<&str>| {
+            let mut request = Request::get("/metrics");
+            if let Some(encoding) = encoding {
+                request = request.header(header::ACCEPT_ENCODING, encoding);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        for encoding in ["gzip", "br"] {
+            let response = app.clone().oneshot(request(Some(encoding))).await.unwrap();
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], encoding);
+        }
+        let response = app.oneshot(request(None)).await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deprecated_alias_points_to_v1() {
+        let logger = Logger::new(&test_config().watchtower);
+        let v1 = Router::new().route("/stats", get(|| async { StatusCode::OK }));
+        let app = Router::new().nest("/v1", v1.clone()).merge(v1.route_layer(
+            axum::middleware::from_fn_with_state(logger, deprecated_alias),
+        ));
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/stats").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["deprecation"], "true");
+        assert_eq!(
+            response.headers()[header::LINK],
+            "</v1/stats>; rel=\"successor-version\""
+        );
+
+        let response = app
+            .oneshot(Request::get("/v1/stats").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(response.headers().get("deprecation").is_none());
+    }
+
+    #[test]
+    fn test_connect_delay_doubles_up_to_cap() {
+        assert_eq!(connect_delay(1000, 1), Duration::from_millis(1000));
+        assert_eq!(connect_delay(1000, 2), Duration::from_millis(2000));
+        assert_eq!(connect_delay(1000, 4), Duration::from_millis(8000));
+        assert_eq!(connect_delay(1000, 6), Duration::from_secs(30));
+        assert_eq!(connect_delay(1000, 100), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::{logger::Logger, utils::get_specific_error};
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+// Set from server.maintenance at startup and through /admin/maintenance, the
+// writes are refused while it is on so migrations can run on a live service
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after_secs: u64,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool, retry_after_secs: u64) -> Self {
+        Maintenance {
+            enabled: AtomicBool::new(enabled),
+            retry_after_secs,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool, logger: &Logger) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            logger.info(format!(
+                "maintenance mode: {}",
+                if enabled {
+                    "on, writes are refused"
+                } else {
+                    "off"
+                }
+            ));
+        }
+    }
+}
+
+fn is_write(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// Answers 503 to the writes during maintenance, reads keep working
+pub async fn reject_writes<B>(
+    State(maintenance): State<Arc<Maintenance>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !maintenance.is_enabled() || !is_write(request.method()) {
+        return next.run(request).await;
+    }
+    let mut response = get_specific_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "down for maintenance".to_string(),
+    );
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(maintenance.retry_after_secs),
+    );
+    response
+}
+
+#[cfg(test)]
+mod maintenance_tests {
+    use super::{reject_writes, Maintenance};
+    use crate::{config::Config, logger::Logger};
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn call(maintenance: &Arc<Maintenance>, method: &str) -> (StatusCode, Option<String>) {
+        let app = Router::new()
+            .route(
+                "/metadata",
+                get(|| async { StatusCode::OK }).post(|| async { StatusCode::OK }),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                maintenance.clone(),
+                reject_writes,
+            ));
+        let request = Request::builder()
+            .method(method)
+            .uri("/metadata")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.status(), retry_after)
+    }
+
+    #[tokio::test]
+    async fn test_writes_refused_during_maintenance() {
+        let maintenance = Arc::new(Maintenance::new(true, 120));
+        assert_eq!(
+            call(&maintenance, "POST").await,
+            (StatusCode::SERVICE_UNAVAILABLE, Some("120".to_string()))
+        );
+        assert_eq!(call(&maintenance, "GET").await, (StatusCode::OK, None));
+    }
+
+    #[tokio::test]
+    async fn test_toggle() {
+        let mut conf: Config = toml::from_str(include_str!("../config.template.toml")).unwrap();
+        conf.watchtower.enabled = false;
+        let logger = Logger::new(&conf.watchtower);
+        let maintenance = Arc::new(Maintenance::new(false, 120));
+        assert_eq!(call(&maintenance, "POST").await.0, StatusCode::OK);
+
+        maintenance.set(true, &logger);
+        assert!(maintenance.is_enabled());
+        assert_eq!(
+            call(&maintenance, "POST").await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        maintenance.set(false, &logger);
+        assert_eq!(call(&maintenance, "POST").await.0, StatusCode::OK);
+    }
+}
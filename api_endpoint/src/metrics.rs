@@ -0,0 +1,134 @@
+use std::{sync::Arc, time::Instant};
+
+use crate::models::AppState;
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, Registry, TextEncoder};
+
+pub struct Metrics {
+    pub registry: Registry,
+    pub emails_sent_total: IntCounter,
+    pub emails_failed_total: IntCounter,
+    pub metadata_added_total: IntCounter,
+    pub handler_duration_seconds: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let emails_sent_total = IntCounter::new(
+            "emails_sent_total",
+            "Subscribers accepted by the email provider",
+        )
+        .unwrap();
+        let emails_failed_total = IntCounter::new(
+            "emails_failed_total",
+            "Subscribers the email provider could not be sent",
+        )
+        .unwrap();
+        let metadata_added_total =
+            IntCounter::new("metadata_added_total", "Sale metadata documents stored").unwrap();
+        let handler_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("handler_duration_seconds", "Latency of the HTTP handlers"),
+            &["path", "status"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(emails_sent_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(emails_failed_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metadata_added_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(handler_duration_seconds.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            emails_sent_total,
+            emails_failed_total,
+            metadata_added_total,
+            handler_duration_seconds,
+        }
+    }
+
+    pub fn render(&self) -> Result<String, String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Records the latency of every routed request, labelled with the route
+// template rather than the raw path to keep the label set bounded
+pub async fn track_latency<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .handler_duration_seconds
+        .with_label_values(&[&path, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+pub async fn handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match state.metrics.render() {
+        Ok(body) => (
+            StatusCode::OK,
+            [(
+                header::CONTENT_TYPE,
+                TextEncoder::new().format_type().to_string(),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::Metrics;
+
+    #[test]
+    fn test_render_exports_counters() {
+        let metrics = Metrics::new();
+        metrics.metadata_added_total.inc();
+        metrics
+            .handler_duration_seconds
+            .with_label_values(&["/add_metadata", "200"])
+            .observe(0.01);
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered.contains("metadata_added_total 1"));
+        assert!(rendered.contains("emails_sent_total 0"));
+        assert!(rendered
+            .contains("handler_duration_seconds_count{path=\"/add_metadata\",status=\"200\"} 1"));
+    }
+}
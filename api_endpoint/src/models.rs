@@ -1,9 +1,17 @@
 use mongodb::Database;
+use std::sync::Arc;
 
-use crate::{config::Config, logger::Logger};
+use crate::{
+    config::Config, logger::Logger, maintenance::Maintenance, metrics::Metrics,
+    rate_limit::IpRateLimiter,
+};
 
 pub_struct!(;AppState {
     conf: Config,
     logger : Logger,
     db: Database,
+    metrics: Metrics,
+    rate_limiter: IpRateLimiter,
+    maintenance: Arc<Maintenance>,
+    http: reqwest::Client,
 });
@@ -0,0 +1,95 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{Arc, Mutex},
+};
+
+use crate::{models::AppState, utils::get_specific_error};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use lru::LruCache;
+
+pub const DEFAULT_REQUESTS_PER_MINUTE: u32 = 30;
+pub const DEFAULT_TRACKED_CLIENTS: usize = 10_000;
+
+// Requests allowed per client IP, only the most recently seen clients are
+// tracked so memory stays bounded, an evicted client starts over with a full
+// quota
+pub struct IpRateLimiter {
+    quota: Quota,
+    limiters: Mutex<LruCache<IpAddr, DefaultDirectRateLimiter>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(requests_per_minute: u32, tracked_clients: usize) -> Self {
+        let requests_per_minute = NonZeroU32::new(requests_per_minute).unwrap_or(NonZeroU32::MIN);
+        IpRateLimiter {
+            quota: Quota::per_minute(requests_per_minute),
+            limiters: Mutex::new(LruCache::new(
+                NonZeroUsize::new(tracked_clients).unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let mut limiters = self.limiters.lock().unwrap();
+        limiters
+            .get_or_insert(ip, || RateLimiter::direct(self.quota))
+            .check()
+            .is_ok()
+    }
+}
+
+// Answers 429 once the client IP used up its quota
+pub async fn limit_by_ip<B>(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.rate_limiter.check(addr.ip()) {
+        return get_specific_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many requests".to_string(),
+        );
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use super::IpRateLimiter;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn test_quota_per_ip() {
+        let limiter = IpRateLimiter::new(3, 10);
+        for _ in 0..3 {
+            assert!(limiter.check(ip(1)));
+        }
+        assert!(!limiter.check(ip(1)));
+        // other clients keep their own quota
+        assert!(limiter.check(ip(2)));
+    }
+
+    #[test]
+    fn test_tracked_clients_are_bounded() {
+        let limiter = IpRateLimiter::new(1, 2);
+        assert!(limiter.check(ip(1)));
+        assert!(!limiter.check(ip(1)));
+        assert!(limiter.check(ip(2)));
+        assert!(limiter.check(ip(3)));
+        assert_eq!(limiter.limiters.lock().unwrap().len(), 2);
+        // ip(1) was evicted and starts over
+        assert!(limiter.check(ip(1)));
+    }
+}
@@ -0,0 +1,67 @@
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use mongodb::bson::oid::ObjectId;
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Runs each request in a span carrying a new request id, also returned in the
+// x-request-id header so a client report can be matched with the logs
+pub async fn track_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = ObjectId::new().to_hex();
+    let span = tracing::info_span!(
+        "request",
+        correlation_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod request_id_tests {
+    use super::{track_request, REQUEST_ID_HEADER};
+    use crate::logger::{current_correlation_id, CorrelationLayer};
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[tokio::test]
+    async fn test_request_id_header_matches_span() {
+        let _subscriber =
+            tracing::subscriber::set_default(Registry::default().with(CorrelationLayer));
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { current_correlation_id().unwrap_or_default() }),
+            )
+            .layer(middleware::from_fn(track_request));
+
+        let response = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request_id = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(request_id.len(), 24);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, request_id.as_bytes());
+    }
+}
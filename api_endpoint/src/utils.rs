@@ -1,10 +1,13 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
 
-use starknet::core::types::FieldElement;
-use std::fmt::Write;
+use mongodb::bson::Document;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use starknet::core::{types::FieldElement, utils::get_selector_from_name};
+use std::{fmt::Write, time::Duration};
 
 #[macro_export]
 macro_rules! pub_struct {
@@ -24,6 +27,181 @@ pub fn get_specific_error(code: StatusCode, error: String) -> Response {
     (code, error).into_response()
 }
 
+// Checks that the request carries the admin token as a bearer token
+pub fn is_admin(headers: &HeaderMap, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    match headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(value) => value.strip_prefix("Bearer ") == Some(token),
+        None => false,
+    }
+}
+
+// Masks the local part of an email for the logs, j***@example.com, anything
+// that is not an address is masked entirely
+pub fn redact_email(email: &str) -> String {
+    match email.trim().rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() => {
+            let first = local.chars().next().unwrap_or_default();
+            format!("{}***@{}", first, domain)
+        }
+        _ => "***".to_string(),
+    }
+}
+
+// RFC 5321 limits on the parts of an email address
+const MAX_EMAIL_LOCAL_LENGTH: usize = 64;
+const MAX_EMAIL_DOMAIN_LENGTH: usize = 255;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+pub fn check_email_length(email: &str) -> Result<(), String> {
+    if email.len() > MAX_EMAIL_LENGTH {
+        return Err(format!(
+            "email exceeds the maximum length of {} characters",
+            MAX_EMAIL_LENGTH
+        ));
+    }
+    let (local, domain) = email.rsplit_once('@').unwrap_or((email, ""));
+    if local.len() > MAX_EMAIL_LOCAL_LENGTH {
+        return Err(format!(
+            "email local part exceeds the maximum length of {} characters",
+            MAX_EMAIL_LOCAL_LENGTH
+        ));
+    }
+    if domain.len() > MAX_EMAIL_DOMAIN_LENGTH {
+        return Err(format!(
+            "email domain exceeds the maximum length of {} characters",
+            MAX_EMAIL_DOMAIN_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+// Range on the sale timestamps (seconds) of the admin summaries, both bounds
+// included, None when the range is unbounded
+pub fn timestamp_range(from: Option<i64>, to: Option<i64>) -> Result<Option<Document>, String> {
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err("from must not be after to".to_string());
+        }
+    }
+    let mut range = Document::new();
+    if let Some(from) = from {
+        range.insert("$gte", from);
+    }
+    if let Some(to) = to {
+        range.insert("$lte", to);
+    }
+    Ok(Some(range).filter(|range| !range.is_empty()))
+}
+
+// Characters of the starknet.id basic alphabet, the ones a label encodes to
+const DOMAIN_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789-";
+
+// A .stark domain, subdomains included, made of non-empty labels of the
+// basic alphabet
+pub fn is_valid_domain(domain: &str) -> bool {
+    let Some(name) = domain.strip_suffix(".stark") else {
+        return false;
+    };
+    !name.is_empty()
+        && name
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| DOMAIN_ALPHABET.contains(c)))
+}
+
+// Hidden form field only bots fill, their submissions are acknowledged and
+// dropped so they do not retry
+pub fn is_honeypot_filled(hp: Option<&str>) -> bool {
+    hp.map_or(false, |hp| !hp.trim().is_empty())
+}
+
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Client of the calls made to outside services, built once so connections are
+// pooled, the timeouts bound how long a hung service can hold a request
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("unable to build the http client")
+}
+
+// Getters exposing the signer public key, depending on the account
+// implementation (OpenZeppelin, Argent, Braavos...)
+const PUBLIC_KEY_ENTRYPOINTS: [&str; 4] =
+    ["get_public_key", "getPublicKey", "get_signer", "getSigner"];
+
+// Reads the public key of an account contract through the RPC node
+pub async fn fetch_public_key(
+    client: &reqwest::Client,
+    rpc_url: &str,
+    account: FieldElement,
+) -> Result<FieldElement, String> {
+    for entrypoint in PUBLIC_KEY_ENTRYPOINTS {
+        let selector = get_selector_from_name(entrypoint).map_err(|e| e.to_string())?;
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "starknet_call",
+            "params": {
+                "request": {
+                    "contract_address": to_hex(account),
+                    "entry_point_selector": to_hex(selector),
+                    "calldata": []
+                },
+                "block_id": "latest"
+            }
+        });
+        let response: Value = client
+            .post(rpc_url)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("unable to reach the starknet node: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("invalid response from the starknet node: {}", e))?;
+
+        // The node answers with an error when the entrypoint does not exist
+        if let Some(public_key) = response["result"][0]
+            .as_str()
+            .and_then(|value| FieldElement::from_hex_be(value).ok())
+        {
+            return Ok(public_key);
+        }
+    }
+    Err(format!(
+        "account {} does not expose a public key",
+        to_hex(account)
+    ))
+}
+
+// meta_hash committed on chain with a sale: the sha256 of email|tax_state|salt
+// truncated to its first 248 bits so it fits in a felt. A | in tax_state is
+// dropped so it cannot shift the fields. The naming contract never hashes the
+// metadata, it emits the felt the frontend passed in its sale metadata event
+// as is, so there is no Poseidon or Pedersen scheme to follow: this one is the
+// frontend's and the one of every meta_hash stored so far
+pub fn compute_meta_hash(email: &str, tax_state: &str, salt: &str) -> FieldElement {
+    let data = format!("{}|{}|{}", email, tax_state.replace('|', ""), salt);
+    let digest = Sha256::digest(data.as_bytes());
+    let mut bytes = [0u8; 32];
+    bytes[1..].copy_from_slice(&digest[..31]);
+    FieldElement::from_bytes_be(&bytes).expect("248 bits always fit in a felt")
+}
+
+// meta_hash as written by the indexer, 62 hex digits without prefix
+pub fn meta_hash_hex(meta_hash: FieldElement) -> String {
+    hex::encode(&meta_hash.to_bytes_be()[1..])
+}
+
 pub fn to_hex(felt: FieldElement) -> String {
     let bytes = felt.to_bytes_be();
 
@@ -44,7 +222,12 @@ pub fn to_hex(felt: FieldElement) -> String {
 
 #[cfg(test)]
 mod utils_tests {
-    use super::to_hex;
+    use super::{
+        check_email_length, compute_meta_hash, is_admin, is_honeypot_filled, is_valid_domain,
+        meta_hash_hex, redact_email, timestamp_range, to_hex,
+    };
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use mongodb::bson::doc;
     use starknet::core::types::FieldElement;
 
     #[test]
@@ -85,4 +268,135 @@ mod utils_tests {
         assert_eq!(to_hex(max).len(), 66);
         assert!(to_hex(max).starts_with("0x"));
     }
+
+    #[test]
+    fn test_is_admin() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_admin(&headers, "secret"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        assert!(is_admin(&headers, "secret"));
+        assert!(!is_admin(&headers, "other"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("secret"));
+        assert!(!is_admin(&headers, "secret"));
+    }
+
+    #[test]
+    fn test_is_admin_empty_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer "));
+        assert!(!is_admin(&headers, ""));
+    }
+
+    #[test]
+    fn test_compute_meta_hash_vectors() {
+        // the first 62 hex digits of sha256("email|tax_state|salt"), computed
+        // outside this crate
+        let cases = [
+            (
+                ("alice@example.com", "FR", "0x1"),
+                "c54bb7aa00a40358bf135a7cf8c0255a23e48e8f252ea861478a8c7b7dda68",
+            ),
+            (
+                ("bob@example.com", "US-CA", "0x2a"),
+                "35aca5d2aa57c2156e55ab6859e3415631b9c69ee51ff2e36c814526cab586",
+            ),
+        ];
+        for ((email, tax_state, salt), expected) in cases {
+            let meta_hash = compute_meta_hash(email, tax_state, salt);
+            assert_eq!(meta_hash_hex(meta_hash), expected);
+            assert_eq!(meta_hash, FieldElement::from_hex_be(expected).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_compute_meta_hash_ignores_separator_in_tax_state() {
+        assert_eq!(
+            compute_meta_hash("alice@example.com", "F|R", "0x1"),
+            compute_meta_hash("alice@example.com", "FR", "0x1")
+        );
+    }
+
+    #[test]
+    fn test_redact_email() {
+        assert_eq!(redact_email("john.doe@example.com"), "j***@example.com");
+        assert_eq!(redact_email("@example.com"), "***");
+        assert_eq!(redact_email("not an email"), "***");
+    }
+
+    #[test]
+    fn test_check_email_length_local_part() {
+        let at_limit = format!("{}@example.com", "a".repeat(64));
+        assert!(check_email_length(&at_limit).is_ok());
+
+        let over_limit = format!("{}@example.com", "a".repeat(65));
+        assert!(check_email_length(&over_limit).is_err());
+    }
+
+    #[test]
+    fn test_check_email_length_total() {
+        // 64 + 1 + 189 = 254 characters
+        let at_limit = format!("{}@{}.com", "a".repeat(64), "b".repeat(185));
+        assert_eq!(at_limit.len(), 254);
+        assert!(check_email_length(&at_limit).is_ok());
+
+        let over_limit = format!("{}@{}.com", "a".repeat(64), "b".repeat(186));
+        assert_eq!(over_limit.len(), 255);
+        assert!(check_email_length(&over_limit).is_err());
+    }
+
+    #[test]
+    fn test_check_email_length_regular_address() {
+        assert!(check_email_length("alice@example.com").is_ok());
+    }
+
+    #[test]
+    fn test_is_honeypot_filled() {
+        assert!(!is_honeypot_filled(None));
+        assert!(!is_honeypot_filled(Some("")));
+        assert!(!is_honeypot_filled(Some("  ")));
+        assert!(is_honeypot_filled(Some("http://spam.example")));
+    }
+
+    #[test]
+    fn test_timestamp_range() {
+        assert_eq!(timestamp_range(None, None), Ok(None));
+        assert_eq!(
+            timestamp_range(Some(10), None),
+            Ok(Some(doc! { "$gte": 10_i64 }))
+        );
+        assert_eq!(
+            timestamp_range(Some(10), Some(20)),
+            Ok(Some(doc! { "$gte": 10_i64, "$lte": 20_i64 }))
+        );
+        assert!(timestamp_range(Some(20), Some(10)).is_err());
+    }
+
+    #[test]
+    fn test_is_valid_domain() {
+        assert!(is_valid_domain("ben.stark"));
+        assert!(is_valid_domain("starknet-id42.stark"));
+        assert!(is_valid_domain("wallet.ben.stark"));
+    }
+
+    #[test]
+    fn test_is_valid_domain_missing_suffix() {
+        assert!(!is_valid_domain("ben"));
+        assert!(!is_valid_domain("ben.eth"));
+        assert!(!is_valid_domain(".stark"));
+        assert!(!is_valid_domain("ben.stark.com"));
+    }
+
+    #[test]
+    fn test_is_valid_domain_illegal_characters() {
+        assert!(!is_valid_domain("Ben.stark"));
+        assert!(!is_valid_domain("ben_1.stark"));
+        assert!(!is_valid_domain("b en.stark"));
+        assert!(!is_valid_domain("ben..stark"));
+        assert!(!is_valid_domain("bén.stark"));
+    }
 }
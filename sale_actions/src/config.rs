@@ -1,6 +1,12 @@
+use crate::processing::transport::parse_tls_version;
 use serde::{self, Deserialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
+
+// Fields of the emails set by sale_actions itself, extra fields can't use them
+const BUILT_IN_FIELDS: [&str; 5] = ["name", "expiry", "renewer", "allowance", "unsubscribe_url"];
 
 pub_struct!(Clone, Deserialize; General {
     check_delay: u64,
@@ -11,11 +17,25 @@ pub_struct!(Clone, Deserialize; Email {
     api_key: String,
     ar_group_id : String,
     batch_size : usize,
+    domain_suffix: Option<String>,
+    strip_domain_suffix: Option<bool>,
+    min_tls_version: Option<String>,
+    allowance_decimals: Option<usize>,
+    allowance_symbol: Option<String>,
+    concurrency: Option<usize>,
+    timeout_secs: Option<u64>,
+    max_per_second: Option<u32>,
+    provider: Option<String>,
+    connect_timeout_secs: Option<u64>,
+    user_agent: Option<String>,
+    extra_fields: Option<BTreeMap<String, String>>,
 });
 
 pub_struct!(Clone, Deserialize; Database {
     name: String,
     connection_string: String,
+    connect_attempts: Option<u32>,
+    connect_delay_ms: Option<u64>,
 });
 
 pub_struct!(Clone, Deserialize; WatchtowerTypes {
@@ -30,15 +50,285 @@ pub_struct!(Clone, Deserialize; Watchtower {
     app_id: String,
     token: String,
     types: WatchtowerTypes,
+    format: Option<String>,
+    min_level: Option<String>,
+    redact_pii: Option<bool>,
+});
+
+pub_struct!(Clone, Deserialize, Default; Processing {
+    dedup_in_run: Option<bool>,
+    enable_sales: Option<bool>,
+    max_sends_per_run: Option<usize>,
+    max_sends_per_recipient: Option<usize>,
+    dry_run: Option<bool>,
+    settings_collection: Option<String>,
+    progress_interval: Option<usize>,
+    email_tax_states: Option<Vec<String>>,
+    skip_tax_states: Option<Vec<String>>,
+    checkpoint: Option<String>,
+    sync_subscribers: Option<bool>,
+    max_failure_rate: Option<f64>,
+    failure_rate_min_sample: Option<usize>,
+    runs_collection: Option<String>,
+    runs_retention_days: Option<u64>,
+    batch_size: Option<usize>,
+    enable_renewals: Option<bool>,
+    max_attempts: Option<u32>,
+    claim_ttl_secs: Option<u64>,
+    max_backfill_days: Option<u64>,
+    interval_secs: Option<u64>,
+    interval_jitter_secs: Option<u64>,
+    shutdown_grace_secs: Option<u64>,
+    empty_run_alert_threshold: Option<u64>,
+});
+
+pub_struct!(Clone, Deserialize, Default; Retry {
+    max_attempts: Option<u32>,
+    base_delay_ms: Option<u64>,
+    multiplier: Option<f64>,
+});
+
+pub_struct!(Clone, Deserialize, Default; CircuitBreaker {
+    failure_threshold: Option<u32>,
+    cooldown_secs: Option<u64>,
 });
 
+pub_struct!(Clone, Deserialize; Webhooks {
+    sale_processed_url: Option<String>,
+    timeout_secs: Option<u64>,
+});
+
+pub_struct!(Clone, Deserialize; Trigger {
+    host: Option<String>,
+    port: u16,
+    token: String,
+});
+
+pub_struct!(Clone, Deserialize; Smtp {
+    host: String,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    subject: Option<String>,
+    body: Option<String>,
+    renewal_subject: Option<String>,
+    renewal_body: Option<String>,
+    templates_dir: Option<String>,
+});
+
+pub_struct!(Clone, Deserialize; Unsubscribe {
+    secret: String,
+    url: String,
+    ttl_days: Option<u64>,
+});
+
+// Loaded from the toml file given as first argument (config.toml by default),
+// then every APP__<SECTION>__<KEY> environment variable overrides the key it
+// names, e.g. APP__EMAIL__API_KEY or APP__PROCESSING__DRY_RUN=true, so secrets
+// can be injected without writing them to the file
 pub_struct!(Clone, Deserialize;  Config {
     general : General,
     email : Email,
+    smtp: Option<Smtp>,
+    trigger: Option<Trigger>,
+    webhooks: Option<Webhooks>,
+    unsubscribe: Option<Unsubscribe>,
+    #[serde(default)]
+    processing: Processing,
+    #[serde(default)]
+    retry: Retry,
+    #[serde(default)]
+    circuit_breaker: CircuitBreaker,
     database: Database,
     watchtower: Watchtower,
 });
 
+impl Trigger {
+    // Address the trigger server listens on, loopback unless trigger.host is
+    // set since it starts sends
+    pub fn bind_addr(&self) -> Result<SocketAddr, String> {
+        let host = self.host.as_deref().unwrap_or("127.0.0.1");
+        host.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .map_err(|_| format!("trigger.host must be an IP address, got \"{}\"", host))
+    }
+}
+
+// Collects the settings that would only fail once the service is running
+impl Config {
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if self.general.check_delay == 0 {
+            problems.push("general.check_delay must be at least 1 second".to_string());
+        }
+        if self.processing.interval_secs == Some(0) {
+            problems.push("processing.interval_secs must be at least 1".to_string());
+        }
+
+        check_url(&mut problems, "email.base_url", &self.email.base_url);
+        check_not_empty(&mut problems, "email.api_key", &self.email.api_key);
+        check_not_empty(&mut problems, "email.ar_group_id", &self.email.ar_group_id);
+        if self.email.batch_size == 0 {
+            problems.push("email.batch_size must be at least 1".to_string());
+        }
+        if let Some(Err(e)) = self.email.min_tls_version.as_deref().map(parse_tls_version) {
+            problems.push(e);
+        }
+        if self.email.concurrency == Some(0) {
+            problems.push("email.concurrency must be at least 1".to_string());
+        }
+        if self.email.max_per_second == Some(0) {
+            problems.push("email.max_per_second must be at least 1".to_string());
+        }
+        for key in self.email.extra_fields.iter().flatten().map(|(key, _)| key) {
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                problems.push(format!(
+                    "email.extra_fields keys must be letters, digits or _, got \"{}\"",
+                    key
+                ));
+            } else if BUILT_IN_FIELDS.contains(&key.as_str()) {
+                problems.push(format!(
+                    "email.extra_fields.{} would replace a field set by sale_actions",
+                    key
+                ));
+            }
+        }
+        match self.email.provider.as_deref().unwrap_or("rest") {
+            "rest" => (),
+            "smtp" if self.smtp.is_none() => problems
+                .push("email.provider is smtp but the [smtp] section is missing".to_string()),
+            "smtp" => (),
+            other => problems.push(format!(
+                "email.provider must be rest or smtp, got \"{}\"",
+                other
+            )),
+        }
+        if let Some(smtp) = &self.smtp {
+            check_not_empty(&mut problems, "smtp.host", &smtp.host);
+            check_not_empty(&mut problems, "smtp.from", &smtp.from);
+            if let Some(dir) = &smtp.templates_dir {
+                check_not_empty(&mut problems, "smtp.templates_dir", dir);
+            }
+        }
+        if let Some(unsubscribe) = &self.unsubscribe {
+            check_not_empty(&mut problems, "unsubscribe.secret", &unsubscribe.secret);
+            check_url(&mut problems, "unsubscribe.url", &unsubscribe.url);
+            if unsubscribe.ttl_days == Some(0) {
+                problems.push("unsubscribe.ttl_days must be at least 1".to_string());
+            }
+        }
+
+        if let Some(rate) = self.processing.max_failure_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                problems.push(format!(
+                    "processing.max_failure_rate must be between 0 and 1, got {}",
+                    rate
+                ));
+            }
+        }
+        if let Some(trigger) = &self.trigger {
+            if trigger.port == 0 {
+                problems.push("trigger.port must be set".to_string());
+            }
+            if let Err(e) = trigger.bind_addr() {
+                problems.push(e);
+            }
+            check_not_empty(&mut problems, "trigger.token", &trigger.token);
+        }
+        if self.processing.batch_size == Some(0) {
+            problems.push("processing.batch_size must be at least 1".to_string());
+        }
+        if self.processing.max_attempts == Some(0) {
+            problems.push("processing.max_attempts must be at least 1".to_string());
+        }
+        if self.processing.max_backfill_days == Some(0) {
+            problems.push("processing.max_backfill_days must be at least 1".to_string());
+        }
+        if self.processing.empty_run_alert_threshold == Some(0) {
+            problems.push("processing.empty_run_alert_threshold must be at least 1".to_string());
+        }
+        if self.processing.claim_ttl_secs == Some(0) {
+            problems.push("processing.claim_ttl_secs must be at least 1".to_string());
+        }
+        if self.retry.max_attempts == Some(0) {
+            problems.push("retry.max_attempts must be at least 1".to_string());
+        }
+        if let Some(multiplier) = self.retry.multiplier {
+            if multiplier < 1.0 {
+                problems.push(format!(
+                    "retry.multiplier must be at least 1, got {}",
+                    multiplier
+                ));
+            }
+        }
+        if let Some(url) = self
+            .webhooks
+            .as_ref()
+            .and_then(|webhooks| webhooks.sale_processed_url.as_deref())
+        {
+            check_url(&mut problems, "webhooks.sale_processed_url", url);
+        }
+        if self.circuit_breaker.failure_threshold == Some(0) {
+            problems.push("circuit_breaker.failure_threshold must be at least 1".to_string());
+        }
+
+        check_not_empty(&mut problems, "database.name", &self.database.name);
+        if self.database.connect_attempts == Some(0) {
+            problems.push("database.connect_attempts must be at least 1".to_string());
+        }
+        check_not_empty(
+            &mut problems,
+            "database.connection_string",
+            &self.database.connection_string,
+        );
+        if self.watchtower.enabled {
+            check_url(
+                &mut problems,
+                "watchtower.endpoint",
+                &self.watchtower.endpoint,
+            );
+        }
+        if let Some(format) = self.watchtower.format.as_deref() {
+            if format != "text" && format != "json" {
+                problems.push(format!(
+                    "watchtower.format must be text or json, got \"{}\"",
+                    format
+                ));
+            }
+        }
+        if let Some(level) = self.watchtower.min_level.as_deref() {
+            if !crate::logger::LEVELS.contains(&level) {
+                problems.push(format!(
+                    "watchtower.min_level must be one of {}, got \"{}\"",
+                    crate::logger::LEVELS.join(", "),
+                    level
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+fn check_not_empty(problems: &mut Vec<String>, key: &str, value: &str) {
+    if value.trim().is_empty() {
+        problems.push(format!("{} is required", key));
+    }
+}
+
+fn check_url(problems: &mut Vec<String>, key: &str, value: &str) {
+    match reqwest::Url::parse(value) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+        Ok(_) => problems.push(format!("{} must be an http(s) url, got \"{}\"", key, value)),
+        Err(e) => problems.push(format!("{} is not a valid url ({}): \"{}\"", key, e, value)),
+    }
+}
+
 pub fn load() -> Config {
     let args: Vec<String> = env::args().collect();
     let config_path = if args.len() <= 1 {
@@ -51,10 +341,216 @@ pub fn load() -> Config {
         panic!("error: unable to read file with path \"{}\"", config_path);
     }
 
-    match toml::from_str(file_contents.unwrap().as_str()) {
+    let mut value: toml::Value = match toml::from_str(file_contents.unwrap().as_str()) {
+        Ok(value) => value,
+        Err(err) => {
+            panic!("error: unable to parse config. {}", err);
+        }
+    };
+    if let Err(err) = apply_env_overrides(&mut value, env::vars()) {
+        panic!("error: invalid config override. {}", err);
+    }
+    match value.try_into() {
         Ok(loaded) => loaded,
         Err(err) => {
             panic!("error: unable to deserialize config. {}", err);
         }
     }
 }
+
+const ENV_PREFIX: &str = "APP__";
+
+// Sets the keys named by the APP__ variables, a value keeps the type of the
+// key it replaces and keys missing from the file are read as toml values,
+// falling back to strings
+fn apply_env_overrides(
+    config: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), String> {
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = path.split("__").map(|key| key.to_lowercase()).collect();
+        if keys.iter().any(|key| key.is_empty()) {
+            return Err(format!("{}: empty key", name));
+        }
+
+        let (last, sections) = keys.split_last().unwrap();
+        let mut table = config
+            .as_table_mut()
+            .ok_or_else(|| "config is not a table".to_string())?;
+        for section in sections {
+            table = table
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| format!("{}: {} is not a section", name, section))?;
+        }
+        let value =
+            parse_env_value(table.get(last), &raw).map_err(|e| format!("{}: {}", name, e))?;
+        table.insert(last.clone(), value);
+    }
+    Ok(())
+}
+
+fn parse_env_value(current: Option<&toml::Value>, raw: &str) -> Result<toml::Value, String> {
+    use toml::Value;
+    let inline = || {
+        toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+    };
+    match current {
+        Some(Value::String(_)) => Ok(Value::String(raw.to_string())),
+        Some(Value::Integer(_)) => raw
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("expected an integer, got \"{}\"", raw)),
+        Some(Value::Float(_)) => raw
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| format!("expected a number, got \"{}\"", raw)),
+        Some(Value::Boolean(_)) => raw
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("expected true or false, got \"{}\"", raw)),
+        Some(_) => inline().ok_or_else(|| format!("expected a toml value, got \"{}\"", raw)),
+        None => Ok(inline().unwrap_or_else(|| Value::String(raw.to_string()))),
+    }
+}
+
+// Template config with remote logging disabled, shared by the tests
+#[cfg(test)]
+pub fn test_config() -> Config {
+    let mut conf: Config = toml::from_str(include_str!("../config.template.toml")).unwrap();
+    conf.watchtower.enabled = false;
+    conf
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::{apply_env_overrides, test_config, Config, Trigger};
+
+    #[test]
+    fn test_template_is_valid() {
+        assert_eq!(test_config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut conf = test_config();
+        conf.email.base_url = "connect.mailerlite.com".to_string();
+        conf.email.batch_size = 0;
+        conf.database.connection_string = " ".to_string();
+        conf.email.provider = Some("smtp".to_string());
+        let problems = conf.validate().unwrap_err();
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("email.base_url is not a valid url"));
+        assert_eq!(problems[1], "email.batch_size must be at least 1");
+        assert_eq!(
+            problems[2],
+            "email.provider is smtp but the [smtp] section is missing"
+        );
+        assert_eq!(problems[3], "database.connection_string is required");
+    }
+
+    #[test]
+    fn test_trigger_bind_addr() {
+        let mut trigger = Trigger {
+            host: None,
+            port: 8090,
+            token: "secret".to_string(),
+        };
+        assert_eq!(trigger.bind_addr().unwrap().to_string(), "127.0.0.1:8090");
+
+        trigger.host = Some("0.0.0.0".to_string());
+        assert_eq!(trigger.bind_addr().unwrap().to_string(), "0.0.0.0:8090");
+
+        trigger.host = Some("localhost".to_string());
+        assert!(trigger.bind_addr().is_err());
+    }
+
+    #[test]
+    fn test_validate_min_tls_version() {
+        let mut conf = test_config();
+        conf.email.min_tls_version = Some("1.3".to_string());
+        assert!(conf.validate().is_ok());
+
+        conf.email.min_tls_version = Some("1.4".to_string());
+        assert_eq!(
+            conf.validate().unwrap_err(),
+            vec!["invalid email.min_tls_version \"1.4\", expected 1.0, 1.1, 1.2 or 1.3"]
+        );
+    }
+
+    #[test]
+    fn test_validate_extra_fields() {
+        let mut conf = test_config();
+        conf.email.extra_fields = Some(
+            [
+                ("referral_code", "starknet"),
+                ("name", "{domain}"),
+                ("a b", "c"),
+            ]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        );
+        assert_eq!(
+            conf.validate(),
+            Err(vec![
+                "email.extra_fields keys must be letters, digits or _, got \"a b\"".to_string(),
+                "email.extra_fields.name would replace a field set by sale_actions".to_string(),
+            ])
+        );
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut value =
+            toml::from_str::<toml::Value>(include_str!("../config.template.toml")).unwrap();
+        apply_env_overrides(
+            &mut value,
+            vars(&[
+                ("APP__EMAIL__API_KEY", "env-key"),
+                ("APP__DATABASE__CONNECTION_STRING", "mongodb://db:27017"),
+                ("APP__WATCHTOWER__ENABLED", "false"),
+                ("APP__WATCHTOWER__TYPES__INFO", "mainnet/info"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+        let conf: Config = value.try_into().unwrap();
+        assert_eq!(conf.email.api_key, "env-key");
+        assert_eq!(conf.database.connection_string, "mongodb://db:27017");
+        assert!(!conf.watchtower.enabled);
+        assert_eq!(conf.watchtower.types.info, "mainnet/info");
+    }
+
+    #[test]
+    fn test_env_overrides_keep_types() {
+        let mut value =
+            toml::from_str::<toml::Value>(include_str!("../config.template.toml")).unwrap();
+        // a numeric secret stays a string
+        apply_env_overrides(&mut value, vars(&[("APP__EMAIL__API_KEY", "12345")])).unwrap();
+        assert_eq!(value["email"]["api_key"].as_str(), Some("12345"));
+
+        let err = apply_env_overrides(&mut value, vars(&[("APP__WATCHTOWER__ENABLED", "yes")]))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "APP__WATCHTOWER__ENABLED: expected true or false, got \"yes\""
+        );
+        let err =
+            apply_env_overrides(&mut value, vars(&[("APP__EMAIL__API_KEY__X", "1")])).unwrap_err();
+        assert_eq!(err, "APP__EMAIL__API_KEY__X: api_key is not a section");
+    }
+}
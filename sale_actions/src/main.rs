@@ -2,16 +2,101 @@
 mod utils;
 mod config;
 mod logger;
+mod metrics;
 mod processing;
+mod runner;
+mod trigger;
 use logger::Logger;
-use mongodb::{bson::doc, options::ClientOptions, Client};
-use tokio::time::{sleep, Duration};
+use mongodb::{bson::doc, options::ClientOptions, Client, Database};
+use processing::{
+    provider::SmtpProvider,
+    transport::{build_client, EmailTransport, RateLimitedTransport, ReqwestTransport},
+};
+use runner::Runner;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+use tokio::{
+    signal,
+    time::{sleep, timeout, Duration, Instant},
+};
+
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 60;
+
+// Wait after the failed ping `attempt` (starting at 1), doubling each time
+fn connect_delay(base_ms: u64, attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis(base_ms.saturating_mul(factor)).min(MAX_CONNECT_DELAY)
+}
+
+// Wait before the next run so runs start every `interval`, plus up to `jitter`
+// picked from `seed`
+fn cycle_delay(interval: Duration, jitter: Duration, elapsed: Duration, seed: u64) -> Duration {
+    let jitter_ms = jitter.as_millis() as u64;
+    let extra = if jitter_ms == 0 {
+        0
+    } else {
+        seed % (jitter_ms + 1)
+    };
+    interval.saturating_sub(elapsed) + Duration::from_millis(extra)
+}
+
+// Random enough to spread instances apart, without pulling a rand crate
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Pings the database until it answers, it may come up after the service
+async fn wait_for_database(db: &Database, conf: &config::Database, logger: &Logger) -> bool {
+    let attempts = conf.connect_attempts.unwrap_or(5).max(1);
+    let base_ms = conf.connect_delay_ms.unwrap_or(1000);
+    for attempt in 1..=attempts {
+        match db.run_command(doc! {"ping": 1}, None).await {
+            Ok(_) => return true,
+            Err(e) if attempt < attempts => {
+                let delay = connect_delay(base_ms, attempt);
+                logger.warning(format!(
+                    "database ping failed (attempt {}/{}): {}, retrying in {}ms",
+                    attempt,
+                    attempts,
+                    e,
+                    delay.as_millis()
+                ));
+                sleep(delay).await;
+            }
+            Err(e) => logger.severe(format!(
+                "unable to connect to database after {} attempts: {}",
+                attempts, e
+            )),
+        }
+    }
+    false
+}
 
 #[tokio::main]
 async fn main() {
     let conf = config::load();
+    if let Err(problems) = conf.validate() {
+        for problem in problems {
+            eprintln!("error: invalid config: {}", problem);
+        }
+        std::process::exit(1);
+    }
     let logger = Logger::new(&conf.watchtower);
-    logger.info(format!("starting v{} of sale_actions", env!("CARGO_PKG_VERSION")));
+    run(conf, &logger).await;
+    // the last logs usually explain why it stopped
+    logger.flush().await;
+}
+
+// Runs until a startup step fails, the processing loop never returns
+async fn run(conf: config::Config, logger: &Logger) {
+    logger.info(format!(
+        "starting v{} of sale_actions",
+        env!("CARGO_PKG_VERSION")
+    ));
     let db = Client::with_options(
         ClientOptions::parse(&conf.database.connection_string)
             .await
@@ -20,16 +105,185 @@ async fn main() {
     .unwrap()
     .database(&conf.database.name);
 
-    if db.run_command(doc! {"ping": 1}, None).await.is_err() {
-        logger.severe("unable to connect to database");
+    if !wait_for_database(&db, &conf.database, logger).await {
         return;
-    } else {
-        logger.info("database: connected")
     }
+    logger.info("database: connected");
+
+    let transport: Box<dyn EmailTransport> = match build_client(&conf.email) {
+        Ok(client) => match conf.email.max_per_second {
+            Some(max_per_second) => Box::new(RateLimitedTransport::new(
+                ReqwestTransport::new(client),
+                max_per_second,
+            )),
+            None => Box::new(ReqwestTransport::new(client)),
+        },
+        Err(e) => {
+            logger.severe(e);
+            return;
+        }
+    };
 
+    // The REST provider borrows the settings of each run, SMTP keeps its
+    // connection pool across runs
+    let smtp = match conf.email.provider.as_deref().unwrap_or("rest") {
+        "rest" => None,
+        "smtp" => match conf.smtp.as_ref().map(SmtpProvider::new) {
+            Some(Ok(provider)) => Some(provider),
+            Some(Err(e)) => {
+                logger.severe(e);
+                return;
+            }
+            None => {
+                logger.severe("email.provider is smtp but the [smtp] section is missing");
+                return;
+            }
+        },
+        other => {
+            logger.severe(format!(
+                "invalid email.provider \"{}\", expected rest or smtp",
+                other
+            ));
+            return;
+        }
+    };
+    processing::indexes::ensure_indexes(&db, logger).await;
+    processing::claims::ensure_indexes(&conf, &db, logger).await;
+    processing::runs::ensure_retention(&conf, &db, logger).await;
+
+    let interval = Duration::from_secs(
+        conf.processing
+            .interval_secs
+            .unwrap_or(conf.general.check_delay),
+    );
+    let jitter = conf
+        .processing
+        .interval_jitter_secs
+        .map(Duration::from_secs)
+        .unwrap_or(interval / 10);
+    let grace = Duration::from_secs(
+        conf.processing
+            .shutdown_grace_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+    );
+    // validated with the config
+    let trigger_addr = conf
+        .trigger
+        .as_ref()
+        .and_then(|trigger| trigger.bind_addr().ok());
+    let runner = Arc::new(Runner::new(conf, db, logger.clone(), transport, smtp));
+    if let Some(addr) = trigger_addr {
+        let runner = runner.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(e) = trigger::serve(runner, addr).await {
+                logger.severe(e);
+            }
+        });
+        logger.info(format!("trigger: listening on http://{}/process", addr));
+    }
+
+    // The signal stops the scheduling, the cycle in flight is given the grace
+    // period to finish its batch. A run cut past it is picked up again on the
+    // next start
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        processing::purchases::process_data(&conf, &db, &logger).await;
-        //processing::renewal::process_data(&conf, &db, &logger).await;
-        sleep(Duration::from_secs(conf.general.check_delay)).await; // Sleep for 60 seconds before repeating
+        logger.info("cycle: starting");
+        let started = Instant::now();
+        let run = runner.run();
+        tokio::pin!(run);
+        let report = tokio::select! {
+            report = &mut run => report,
+            _ = &mut shutdown => {
+                logger.info(format!(
+                    "shutting down once the current cycle is over, waiting up to {}s",
+                    grace.as_secs()
+                ));
+                match timeout(grace, run).await {
+                    Ok(report) => logger.info(format!(
+                        "cycle: run {} finished, {} sales processed",
+                        report.run_id, report.processed
+                    )),
+                    Err(_) => logger.warning(format!(
+                        "shutdown: the cycle did not finish within {}s, it is cut",
+                        grace.as_secs()
+                    )),
+                }
+                return;
+            }
+        };
+        let elapsed = started.elapsed();
+        logger.info(format!(
+            "cycle: run {} finished in {}ms, {} sales processed",
+            report.run_id,
+            elapsed.as_millis(),
+            report.processed
+        ));
+        tokio::select! {
+            _ = sleep(cycle_delay(interval, jitter, elapsed, random_seed())) => {}
+            _ = &mut shutdown => {
+                logger.info("shutting down");
+                return;
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod main_tests {
+    use super::{connect_delay, cycle_delay};
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_cycle_delay() {
+        let interval = Duration::from_secs(60);
+        let none = Duration::ZERO;
+        assert_eq!(
+            cycle_delay(interval, none, Duration::from_secs(15), 7),
+            Duration::from_secs(45)
+        );
+        // a run longer than the interval is followed by the next one right away
+        assert_eq!(
+            cycle_delay(interval, none, Duration::from_secs(90), 7),
+            Duration::ZERO
+        );
+
+        let jitter = Duration::from_secs(6);
+        for seed in [0, 1, 5999, 6000, 6001, u64::MAX] {
+            let delay = cycle_delay(interval, jitter, none, seed);
+            assert!(delay >= interval && delay <= interval + jitter);
+        }
+        assert_eq!(cycle_delay(interval, jitter, none, 6001), interval);
+    }
+
+    #[test]
+    fn test_connect_delay_doubles_up_to_cap() {
+        assert_eq!(connect_delay(1000, 1), Duration::from_millis(1000));
+        assert_eq!(connect_delay(1000, 2), Duration::from_millis(2000));
+        assert_eq!(connect_delay(1000, 4), Duration::from_millis(8000));
+        assert_eq!(connect_delay(1000, 6), Duration::from_secs(30));
+        assert_eq!(connect_delay(1000, 100), Duration::from_secs(30));
     }
 }
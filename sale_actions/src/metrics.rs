@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Counter {
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+// Process wide counters, named after the metric they are exported as
+pub struct Metrics {
+    pub email_rate_limited_total: Counter,
+    pub sales_processed_total: Counter,
+    pub sales_failed_total: Counter,
+}
+
+pub static METRICS: Metrics = Metrics {
+    email_rate_limited_total: Counter::new(),
+    sales_processed_total: Counter::new(),
+    sales_failed_total: Counter::new(),
+};
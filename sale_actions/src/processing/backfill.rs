@@ -0,0 +1,150 @@
+use super::{claims, provider::EmailProvider, purchases, redrive::strings};
+use crate::{config::Config, logger::Logger};
+use mongodb::{
+    bson::{doc, Document},
+    Database,
+};
+use serde_derive::{Deserialize, Serialize};
+
+const DEFAULT_MAX_DAYS: u64 = 31;
+
+// Both bounds are required, in seconds like the sales timestamp, so the whole
+// history is never sent again by mistake
+#[derive(Deserialize)]
+pub struct BackfillRequest {
+    pub from: i64,
+    pub to: i64,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct BackfillSummary {
+    pub matched: usize,
+    pub resent: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+// The range may span processing.max_backfill_days at most
+pub fn check_range(conf: &Config, request: &BackfillRequest) -> Result<(), String> {
+    if request.from > request.to {
+        return Err("from must not be after to".to_string());
+    }
+    let max_days = conf
+        .processing
+        .max_backfill_days
+        .unwrap_or(DEFAULT_MAX_DAYS);
+    if (request.to - request.from) as u64 > max_days * 24 * 60 * 60 {
+        return Err(format!("the range must not exceed {} days", max_days));
+    }
+    Ok(())
+}
+
+fn range_filter(request: &BackfillRequest) -> Document {
+    doc! {
+        "meta_hash": { "$ne": "" },
+        "timestamp": { "$gte": request.from, "$lte": request.to },
+    }
+}
+
+// Sends the sales of the range again, emailed already or not: their "sent"
+// entries are dropped from processed so the purchases pipeline picks them up
+// and marks them once more. Sales skipped on purpose keep their entry, and a
+// sale failing again is left unprocessed for the next runs to retry
+pub async fn backfill(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    request: BackfillRequest,
+) -> Result<BackfillSummary, mongodb::error::Error> {
+    let targets = strings(
+        db.collection::<Document>("sales")
+            .distinct("meta_hash", range_filter(&request), None)
+            .await?,
+    );
+    if targets.is_empty() {
+        return Ok(BackfillSummary::default());
+    }
+    if conf.processing.dry_run.unwrap_or(false) {
+        logger.info(format!(
+            "dry run: would backfill {} sales from {} to {}",
+            targets.len(),
+            request.from,
+            request.to
+        ));
+        return Ok(BackfillSummary {
+            matched: targets.len(),
+            ..BackfillSummary::default()
+        });
+    }
+
+    db.collection::<Document>("processed")
+        .delete_many(
+            doc! { "meta_hash": { "$in": targets.clone() }, "outcome": "sent" },
+            None,
+        )
+        .await?;
+    // the claims of the first send may not have expired yet
+    claims::release(db, logger, &targets).await;
+
+    // a backfill must not move the checkpoint
+    let mut conf = conf.clone();
+    conf.processing.checkpoint = None;
+    let summary = purchases::process_selected(&conf, db, logger, provider, &targets).await?;
+    logger.info(format!(
+        "backfill from {} to {}: {} of {} sales sent again",
+        request.from,
+        request.to,
+        summary.sent,
+        targets.len()
+    ));
+    Ok(BackfillSummary {
+        matched: targets.len(),
+        resent: summary.sent,
+        failed: summary.failed,
+        skipped: summary.skipped,
+    })
+}
+
+#[cfg(test)]
+mod backfill_tests {
+    use super::{check_range, range_filter, BackfillRequest};
+    use crate::config::test_config;
+    use mongodb::bson::doc;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    #[test]
+    fn test_check_range() {
+        let mut conf = test_config();
+        let request = |from, to| BackfillRequest { from, to };
+        assert_eq!(
+            check_range(&conf, &request(1_700_000_000, 1_700_000_000 + DAY)),
+            Ok(())
+        );
+        assert_eq!(
+            check_range(&conf, &request(1_700_000_000, 1_699_999_999)),
+            Err("from must not be after to".to_string())
+        );
+        assert_eq!(
+            check_range(&conf, &request(0, 1_700_000_000)),
+            Err("the range must not exceed 31 days".to_string())
+        );
+
+        conf.processing.max_backfill_days = Some(1);
+        assert_eq!(check_range(&conf, &request(0, DAY)), Ok(()));
+        assert!(check_range(&conf, &request(0, DAY + 1)).is_err());
+    }
+
+    #[test]
+    fn test_range_filter() {
+        let request = BackfillRequest { from: 10, to: 20 };
+        assert_eq!(
+            range_filter(&request),
+            doc! {
+                "meta_hash": { "$ne": "" },
+                "timestamp": { "$gte": 10_i64, "$lte": 20_i64 },
+            }
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use super::{
+    mailer::EmailError,
+    provider::{EmailFields, EmailProvider},
+};
+use crate::{config::CircuitBreaker, logger::Logger};
+use async_trait::async_trait;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN_SECS: u64 = 60;
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // a single send probes the provider once the cooldown is over
+    HalfOpen,
+}
+
+// Stops sending to a provider that keeps failing: opened after
+// failure_threshold consecutive retryable failures, sends are skipped until
+// cooldown_secs have passed, then one probe closes it again on success.
+// Kept by the runner so the cooldown spans runs
+pub struct Breaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    pub fn new(conf: &CircuitBreaker) -> Self {
+        Breaker {
+            failure_threshold: conf
+                .failure_threshold
+                .unwrap_or(DEFAULT_FAILURE_THRESHOLD)
+                .max(1),
+            cooldown: Duration::from_secs(conf.cooldown_secs.unwrap_or(DEFAULT_COOLDOWN_SECS)),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // Whether a send may go through now
+    fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    fn record_success(&self) {
+        *self.state.lock().unwrap() = State::Closed { failures: 0 };
+    }
+
+    // True when this failure opened the circuit
+    fn record_failure(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::HalfOpen => self.failure_threshold,
+            // a send allowed before the circuit opened
+            State::Open { .. } => return false,
+        };
+        if failures >= self.failure_threshold {
+            *state = State::Open {
+                until: now + self.cooldown,
+            };
+            true
+        } else {
+            *state = State::Closed { failures };
+            false
+        }
+    }
+
+    // Provider whose sends go through the breaker
+    pub fn guard<'a>(
+        &'a self,
+        provider: &'a dyn EmailProvider,
+        logger: &'a Logger,
+    ) -> GuardedProvider<'a> {
+        GuardedProvider {
+            breaker: self,
+            provider,
+            logger,
+        }
+    }
+
+    async fn call<F>(&self, logger: &Logger, send: F) -> Result<(), EmailError>
+    where
+        F: std::future::Future<Output = Result<(), EmailError>>,
+    {
+        if !self.allow(Instant::now()) {
+            return Err(EmailError::CircuitOpen);
+        }
+        let result = send.await;
+        match &result {
+            Ok(()) => self.record_success(),
+            // the provider answered, it is not down
+            Err(e) if !e.is_retryable() => self.record_success(),
+            Err(e) => {
+                if self.record_failure(Instant::now()) {
+                    logger.severe(format!(
+                        "email provider circuit opened for {}s after {} consecutive failures, last one: {}",
+                        self.cooldown.as_secs(),
+                        self.failure_threshold,
+                        e
+                    ));
+                }
+            }
+        }
+        result
+    }
+}
+
+pub struct GuardedProvider<'a> {
+    breaker: &'a Breaker,
+    provider: &'a dyn EmailProvider,
+    logger: &'a Logger,
+}
+
+#[async_trait]
+impl EmailProvider for GuardedProvider<'_> {
+    async fn send(&self, recipient: &str, fields: &EmailFields) -> Result<(), EmailError> {
+        self.breaker
+            .call(self.logger, self.provider.send(recipient, fields))
+            .await
+    }
+
+    fn describe(&self, recipient: &str, fields: &EmailFields) -> String {
+        self.provider.describe(recipient, fields)
+    }
+
+    async fn send_many(&self, emails: &[(String, EmailFields)]) -> Result<(), EmailError> {
+        self.breaker
+            .call(self.logger, self.provider.send_many(emails))
+            .await
+    }
+
+    async fn disable_renewal(&self, recipients: &[&str]) -> Result<(), EmailError> {
+        self.breaker
+            .call(self.logger, self.provider.disable_renewal(recipients))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod breaker_tests {
+    use super::{Breaker, State};
+    use crate::{
+        config::{test_config, CircuitBreaker},
+        logger::Logger,
+        processing::{
+            mailer::EmailError,
+            provider::{EmailFields, EmailProvider},
+        },
+    };
+    use async_trait::async_trait;
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::{Duration, Instant},
+    };
+
+    // Provider that is down, counts the calls reaching it
+    #[derive(Default)]
+    struct DownProvider {
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl EmailProvider for DownProvider {
+        async fn send(&self, _recipient: &str, _fields: &EmailFields) -> Result<(), EmailError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(EmailError::Network("down".to_string()))
+        }
+
+        async fn disable_renewal(&self, _recipients: &[&str]) -> Result<(), EmailError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(EmailError::Network("down".to_string()))
+        }
+    }
+
+    fn breaker() -> Breaker {
+        Breaker::new(&CircuitBreaker {
+            failure_threshold: Some(3),
+            cooldown_secs: Some(60),
+        })
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = breaker();
+        let now = Instant::now();
+        assert!(!breaker.record_failure(now));
+        // a success resets the count
+        breaker.record_success();
+        assert!(!breaker.record_failure(now));
+        assert!(!breaker.record_failure(now));
+        assert!(breaker.allow(now));
+        assert!(breaker.record_failure(now));
+        assert!(!breaker.allow(now));
+        // failures of sends started before it opened do not log again
+        assert!(!breaker.record_failure(now));
+    }
+
+    #[test]
+    fn test_half_open_after_cooldown() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure(now);
+        }
+        assert!(!breaker.allow(now + Duration::from_secs(59)));
+
+        let later = now + Duration::from_secs(60);
+        assert!(breaker.allow(later));
+        // a single probe at a time
+        assert!(!breaker.allow(later));
+        // a failed probe opens it for another cooldown
+        assert!(breaker.record_failure(later));
+        assert!(!breaker.allow(later + Duration::from_secs(59)));
+
+        let probe = later + Duration::from_secs(60);
+        assert!(breaker.allow(probe));
+        breaker.record_success();
+        assert_eq!(
+            *breaker.state.lock().unwrap(),
+            State::Closed { failures: 0 }
+        );
+        assert!(breaker.allow(probe));
+    }
+
+    #[tokio::test]
+    async fn test_guards_renewal_disables() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let breaker = breaker();
+        let down = DownProvider::default();
+        let provider = breaker.guard(&down, &logger);
+        for _ in 0..3 {
+            assert!(matches!(
+                provider.disable_renewal(&["alice@example.com"]).await,
+                Err(EmailError::Network(_))
+            ));
+        }
+        assert!(matches!(
+            provider.disable_renewal(&["alice@example.com"]).await,
+            Err(EmailError::CircuitOpen)
+        ));
+        assert_eq!(down.calls.load(Ordering::SeqCst), 3);
+    }
+}
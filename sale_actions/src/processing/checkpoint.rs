@@ -0,0 +1,154 @@
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::UpdateOptions,
+    Collection, Database,
+};
+use std::collections::{HashSet, VecDeque};
+
+// Position of the last sale handled by a backfill, sales are read ordered by
+// (timestamp, _id) so a resumed run restarts right after it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub timestamp: i64,
+    pub id: ObjectId,
+}
+
+fn collection(db: &Database) -> Collection<Document> {
+    db.collection("checkpoints")
+}
+
+pub async fn load(db: &Database, name: &str) -> Result<Option<Checkpoint>, mongodb::error::Error> {
+    let checkpoint = collection(db)
+        .find_one(doc! { "_id": name }, None)
+        .await?
+        .and_then(|document| {
+            Some(Checkpoint {
+                timestamp: document.get_i64("timestamp").ok()?,
+                id: document.get_object_id("last_id").ok()?,
+            })
+        });
+    Ok(checkpoint)
+}
+
+pub async fn save(
+    db: &Database,
+    name: &str,
+    checkpoint: &Checkpoint,
+) -> Result<(), mongodb::error::Error> {
+    collection(db)
+        .update_one(
+            doc! { "_id": name },
+            doc! { "$set": { "timestamp": checkpoint.timestamp, "last_id": checkpoint.id } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Stages placed at the start of a backfill pipeline, ordering the sales and
+// skipping everything up to the checkpoint when there is one
+pub fn resume_stages(checkpoint: Option<&Checkpoint>) -> Vec<Document> {
+    let mut stages = vec![doc! { "$sort": { "timestamp": 1, "_id": 1 } }];
+    if let Some(checkpoint) = checkpoint {
+        stages.push(doc! {
+            "$match": {
+                "$or": [
+                    { "timestamp": { "$gt": checkpoint.timestamp } },
+                    { "timestamp": checkpoint.timestamp, "_id": { "$gt": checkpoint.id } }
+                ]
+            }
+        });
+    }
+    stages
+}
+
+// Sales of a backfill in the order they were read, the checkpoint only moves
+// over the ones already written to processed. A sale left unprocessed (failed
+// send, claim error...) holds it back so the next run reads that sale again
+#[derive(Default)]
+pub struct Progress {
+    read: VecDeque<(String, Checkpoint)>,
+    marked: HashSet<String>,
+}
+
+impl Progress {
+    pub fn read(&mut self, meta_hash: &str, position: Checkpoint) {
+        self.read.push_back((meta_hash.to_string(), position));
+    }
+
+    pub fn marked<'a>(&mut self, meta_hashes: impl IntoIterator<Item = &'a str>) {
+        self.marked
+            .extend(meta_hashes.into_iter().map(str::to_string));
+    }
+
+    // Position of the last sale of the processed run of sales read first, None
+    // when the checkpoint can't move
+    pub fn advance(&mut self) -> Option<Checkpoint> {
+        let mut last = None;
+        while let Some((meta_hash, _)) = self.read.front() {
+            if !self.marked.remove(meta_hash) {
+                break;
+            }
+            last = self.read.pop_front().map(|(_, position)| position);
+        }
+        last
+    }
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+    use super::{resume_stages, Checkpoint, Progress};
+    use mongodb::bson::{doc, oid::ObjectId};
+
+    #[test]
+    fn test_resume_stages_without_checkpoint() {
+        assert_eq!(
+            resume_stages(None),
+            vec![doc! { "$sort": { "timestamp": 1, "_id": 1 } }]
+        );
+    }
+
+    #[test]
+    fn test_resume_stages_from_checkpoint() {
+        let id = ObjectId::parse_str("65a000000000000000000001").unwrap();
+        let checkpoint = Checkpoint {
+            timestamp: 1_700_000_000,
+            id,
+        };
+
+        let stages = resume_stages(Some(&checkpoint));
+        assert_eq!(stages.len(), 2);
+        assert_eq!(
+            stages[1],
+            doc! {
+                "$match": {
+                    "$or": [
+                        { "timestamp": { "$gt": 1_700_000_000_i64 } },
+                        { "timestamp": 1_700_000_000_i64, "_id": { "$gt": id } }
+                    ]
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_progress_stops_at_unprocessed_sale() {
+        let position = |timestamp| Checkpoint {
+            timestamp,
+            id: ObjectId::parse_str("65a000000000000000000001").unwrap(),
+        };
+        let mut progress = Progress::default();
+        for (meta_hash, timestamp) in [("a", 1), ("b", 2), ("c", 3), ("d", 4)] {
+            progress.read(meta_hash, position(timestamp));
+        }
+        assert_eq!(progress.advance(), None);
+
+        // b failed, the checkpoint moves up to a only
+        progress.marked(["a", "c", "d"]);
+        assert_eq!(progress.advance(), Some(position(1)));
+        assert_eq!(progress.advance(), None);
+
+        progress.marked(["b"]);
+        assert_eq!(progress.advance(), Some(position(4)));
+    }
+}
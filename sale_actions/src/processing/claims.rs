@@ -0,0 +1,93 @@
+use crate::{config::Config, logger::Logger};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    Database, IndexModel,
+};
+use std::time::Duration;
+
+// One entry per sale or renewal toggle a worker is sending, so two
+// sale_actions running at the same time (e.g. during a rolling deploy) never
+// email the same sale twice nor disable a toggle twice
+const COLLECTION: &str = "processing_claims";
+const DEFAULT_TTL_SECS: u64 = 900;
+const DUPLICATE_KEY: i32 = 11000;
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+// The unique index settles two claims racing on the same sale, the TTL one
+// frees the claims of a worker that stopped before marking its sales
+pub async fn ensure_indexes(conf: &Config, db: &Database, logger: &Logger) {
+    let ttl = conf.processing.claim_ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! { "meta_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "claimed_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(ttl))
+                    .build(),
+            )
+            .build(),
+    ];
+    if let Err(e) = db
+        .collection::<Document>(COLLECTION)
+        .create_indexes(indexes, None)
+        .await
+    {
+        logger.warning(format!(
+            "Unable to create the {} indexes: {}",
+            COLLECTION, e
+        ));
+    }
+}
+
+// Whether this worker got the sale, false when another one holds it
+pub async fn claim(db: &Database, meta_hash: &str) -> Result<bool, mongodb::error::Error> {
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::Before)
+        .build();
+    match db
+        .collection::<Document>(COLLECTION)
+        .find_one_and_update(
+            doc! { "meta_hash": meta_hash },
+            doc! { "$setOnInsert": { "claimed_at": DateTime::now() } },
+            options,
+        )
+        .await
+    {
+        Ok(previous) => Ok(previous.is_none()),
+        Err(e) if is_duplicate_key(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Frees the sales to send again, the claims of the ones marked processed are
+// left to expire
+pub async fn release(db: &Database, logger: &Logger, meta_hashes: &[String]) {
+    if meta_hashes.is_empty() {
+        return;
+    }
+    if let Err(e) = db
+        .collection::<Document>(COLLECTION)
+        .delete_many(doc! { "meta_hash": { "$in": meta_hashes.to_vec() } }, None)
+        .await
+    {
+        logger.warning(format!(
+            "Unable to release {} claims: {}",
+            meta_hashes.len(),
+            e
+        ));
+    }
+}
@@ -0,0 +1,81 @@
+use crate::logger::Logger;
+use futures::stream::TryStreamExt;
+use mongodb::{bson::Document, Database, IndexModel};
+
+// Fields the pipelines $lookup or $match on, scanned in full without an index
+const INDEXED_FIELDS: [(&str, &str); 8] = [
+    ("metadata", "meta_hash"),
+    ("processed", "meta_hash"),
+    ("sales", "meta_hash"),
+    ("ar_processed", "tx_hash"),
+    ("email_groups", "tx_hash"),
+    ("send_attempts", "meta_hash"),
+    ("send_attempts", "tx_hash"),
+    ("failed_emails", "meta_hash"),
+];
+
+// Whether an index already starts on the field, whatever its options (the
+// unique metadata.meta_hash index of api_endpoint covers it as well)
+fn is_indexed(indexes: &[IndexModel], field: &str) -> bool {
+    indexes
+        .iter()
+        .any(|index| index.keys.keys().next().map(String::as_str) == Some(field))
+}
+
+async fn ensure_index(db: &Database, collection: &str, field: &str) -> Result<bool, String> {
+    let collection = db.collection::<Document>(collection);
+    let indexes: Vec<IndexModel> = match collection.list_indexes(None).await {
+        Ok(cursor) => cursor.try_collect().await.map_err(|e| e.to_string())?,
+        // listing fails while the collection does not exist yet
+        Err(_) => Vec::new(),
+    };
+    if is_indexed(&indexes, field) {
+        return Ok(false);
+    }
+    let mut keys = Document::new();
+    keys.insert(field, 1);
+    let index = IndexModel::builder().keys(keys).build();
+    collection
+        .create_index(index, None)
+        .await
+        .map(|_| true)
+        .map_err(|e| e.to_string())
+}
+
+// Creates the missing indexes at startup, the existing ones are left as they are
+pub async fn ensure_indexes(db: &Database, logger: &Logger) {
+    let mut created = Vec::new();
+    let mut present = Vec::new();
+    for (collection, field) in INDEXED_FIELDS {
+        let name = format!("{}.{}", collection, field);
+        match ensure_index(db, collection, field).await {
+            Ok(true) => created.push(name),
+            Ok(false) => present.push(name),
+            Err(e) => logger.warning(format!("Unable to create the {} index: {}", name, e)),
+        }
+    }
+    logger.info(format!(
+        "indexes: created [{}], already present [{}]",
+        created.join(", "),
+        present.join(", ")
+    ));
+}
+
+#[cfg(test)]
+mod indexes_tests {
+    use super::is_indexed;
+    use mongodb::{bson::doc, IndexModel};
+
+    #[test]
+    fn test_is_indexed_on_first_key() {
+        let indexes = vec![
+            IndexModel::builder().keys(doc! { "_id": 1 }).build(),
+            IndexModel::builder()
+                .keys(doc! { "meta_hash": 1, "outcome": 1 })
+                .build(),
+        ];
+        assert!(is_indexed(&indexes, "meta_hash"));
+        assert!(!is_indexed(&indexes, "outcome"));
+        assert!(!is_indexed(&indexes, "tx_hash"));
+    }
+}
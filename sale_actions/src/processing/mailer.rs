@@ -0,0 +1,410 @@
+use super::transport::{EmailTransport, TransportRequest, TransportResponse};
+use crate::{
+    config::{Config, Retry},
+    logger::Logger,
+    metrics::METRICS,
+};
+use reqwest::{header, Method, StatusCode};
+use serde_json::{json, Value};
+use std::{fmt, time::Duration};
+use tokio::time::sleep;
+
+pub const BATCH_URL: &str = "https://api.mailerlite.com/api/v2/batch";
+
+// Upper bound on how long a Retry-After header can make us wait
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// Delay requested by the provider through Retry-After, only the delay-seconds
+// form is supported
+pub fn retry_after(res: &TransportResponse) -> Option<Duration> {
+    let seconds = res
+        .header(header::RETRY_AFTER.as_str())?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+
+// Wait before the attempt following `attempt` (starting at 1), capped like
+// Retry-After
+pub fn backoff_delay(conf: &Retry, attempt: u32) -> Duration {
+    let base = conf.base_delay_ms.unwrap_or(DEFAULT_BASE_DELAY_MS) as f64;
+    let multiplier = conf.multiplier.unwrap_or(DEFAULT_MULTIPLIER).max(1.0);
+    let delay_ms = base * multiplier.powi(attempt.saturating_sub(1) as i32);
+    Duration::from_millis(delay_ms.min(MAX_RETRY_AFTER.as_millis() as f64) as u64)
+}
+
+// Why a batch request was not accepted by the provider
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmailError {
+    // The provider could not be reached
+    Network(String),
+    // 429, with the delay asked through Retry-After
+    RateLimited(Option<Duration>),
+    // Any other non-success status along with the response body
+    Status(StatusCode, String),
+    // Refused for good by a provider without HTTP statuses
+    Permanent(String),
+    // Not sent, the provider failed too many times in a row
+    CircuitOpen,
+    // Not sent, no recipient address could be used
+    InvalidAddress(String),
+    // Not sent, every recipient bounced or complained before
+    Suppressed,
+}
+
+impl EmailError {
+    // Network errors, rate limits and 5xx can succeed when sent again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            EmailError::Network(_) | EmailError::RateLimited(_) | EmailError::CircuitOpen => true,
+            EmailError::Status(status, _) => status.is_server_error(),
+            EmailError::Permanent(_) | EmailError::InvalidAddress(_) | EmailError::Suppressed => {
+                false
+            }
+        }
+    }
+
+    // Reason of the processed entry of a sale given up on, only "rejected"
+    // ones are dead-lettered since a bad or suppressed address is no provider
+    // failure to re-drive
+    pub fn skip_reason(&self) -> &'static str {
+        match self {
+            EmailError::InvalidAddress(_) => "invalid_email",
+            EmailError::Suppressed => "suppressed",
+            _ => "rejected",
+        }
+    }
+}
+
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Network(e) => write!(f, "Failed to send batch request: {}", e),
+            EmailError::RateLimited(_) => write!(
+                f,
+                "Rate limited by the email provider ({} times so far)",
+                METRICS.email_rate_limited_total.get()
+            ),
+            EmailError::Status(status, body) => write!(
+                f,
+                "Received non-success status from batch request: {}. Response body: {}",
+                status, body
+            ),
+            EmailError::Permanent(e) => write!(f, "Email rejected: {}", e),
+            EmailError::CircuitOpen => write!(f, "Email provider circuit is open, send skipped"),
+            EmailError::InvalidAddress(e) => write!(f, "Invalid recipient address: {}", e),
+            EmailError::Suppressed => write!(f, "Every recipient is suppressed"),
+        }
+    }
+}
+
+async fn send_once(
+    transport: &dyn EmailTransport,
+    request: TransportRequest,
+) -> Result<(), EmailError> {
+    match transport.send(request).await {
+        Ok(res) if res.status.is_success() => Ok(()),
+        Ok(res) if res.status == StatusCode::TOO_MANY_REQUESTS => {
+            METRICS.email_rate_limited_total.inc();
+            Err(EmailError::RateLimited(retry_after(&res)))
+        }
+        Ok(res) => Err(EmailError::Status(res.status, res.body)),
+        Err(e) => Err(EmailError::Network(e)),
+    }
+}
+
+// X-Request-Id of a batch: the meta_hash of its sale, or of its first sale
+// followed by the number of other ones, so the provider logs lead back to
+// the sales
+pub fn request_id<'a>(meta_hashes: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let mut distinct: Vec<&str> = Vec::new();
+    for meta_hash in meta_hashes {
+        if !meta_hash.is_empty() && !distinct.contains(&meta_hash) {
+            distinct.push(meta_hash);
+        }
+    }
+    match distinct.as_slice() {
+        [] => None,
+        [only] => Some(only.to_string()),
+        [first, others @ ..] => Some(format!("{}+{}", first, others.len())),
+    }
+}
+
+// Posts a batch of provider requests, retrying transient failures. Failures
+// are logged here, the error is returned so callers can decide what to mark
+pub async fn send_batch(
+    conf: &Config,
+    logger: &Logger,
+    transport: &dyn EmailTransport,
+    requests: &[Value],
+) -> Result<(), EmailError> {
+    send_batch_with_id(conf, logger, transport, requests, None).await
+}
+
+pub async fn send_batch_with_id(
+    conf: &Config,
+    logger: &Logger,
+    transport: &dyn EmailTransport,
+    requests: &[Value],
+    request_id: Option<&str>,
+) -> Result<(), EmailError> {
+    let batch_request = json!({
+        "requests": requests
+    });
+
+    if conf.processing.dry_run.unwrap_or(false) {
+        logger.info(format!(
+            "dry run: skipping batch request of {} requests: {}",
+            requests.len(),
+            batch_request
+        ));
+        return Ok(());
+    }
+
+    let mut request = TransportRequest::new(Method::POST, BATCH_URL)
+        .header("X-MailerLite-ApiKey", &conf.email.api_key)
+        .header(header::CONTENT_TYPE.as_str(), "application/json")
+        .json(batch_request);
+    if let Some(request_id) = request_id {
+        request = request.header("X-Request-Id", request_id);
+    }
+
+    let max_attempts = conf
+        .retry
+        .max_attempts
+        .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+        .max(1);
+    let mut attempt = 1;
+    loop {
+        let error = match send_once(transport, request.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        if !error.is_retryable() {
+            logger.severe(error.to_string());
+            return Err(error);
+        }
+        if attempt >= max_attempts {
+            logger.severe(format!("{}, giving up after {} attempts", error, attempt));
+            return Err(error);
+        }
+        let delay = match error {
+            EmailError::RateLimited(Some(delay)) => delay,
+            _ => backoff_delay(&conf.retry, attempt),
+        };
+        logger.warning(format!(
+            "{}, retrying in {}ms (attempt {} of {})",
+            error,
+            delay.as_millis(),
+            attempt,
+            max_attempts
+        ));
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod mailer_tests {
+    use super::{
+        backoff_delay, request_id, retry_after, send_batch, send_batch_with_id, EmailError,
+        BATCH_URL,
+    };
+    use crate::{
+        config::{test_config, Retry},
+        logger::Logger,
+        metrics::METRICS,
+        processing::transport::{MockTransport, TransportResponse},
+    };
+    use reqwest::{Method, StatusCode};
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_send_batch_success() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(200, &[], r#"{"responses": []}"#);
+
+        let requests = vec![json!({ "method": "POST", "path": "/subscribers?email=a" })];
+        assert_eq!(
+            send_batch(&conf, &logger, &transport, &requests).await,
+            Ok(())
+        );
+
+        let sent = transport.requests();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, Method::POST);
+        assert_eq!(sent[0].url, BATCH_URL);
+        assert!(sent[0].headers.contains(&(
+            "X-MailerLite-ApiKey".to_string(),
+            conf.email.api_key.clone()
+        )));
+        assert_eq!(sent[0].body, Some(json!({ "requests": requests })));
+    }
+
+    #[test]
+    fn test_skip_reason() {
+        let invalid = EmailError::InvalidAddress("no valid email".to_string());
+        assert_eq!(invalid.skip_reason(), "invalid_email");
+        assert!(!invalid.is_retryable());
+        assert_eq!(EmailError::Suppressed.skip_reason(), "suppressed");
+        assert!(!EmailError::Suppressed.is_retryable());
+        let rejected = EmailError::Status(StatusCode::UNPROCESSABLE_ENTITY, String::new());
+        assert_eq!(rejected.skip_reason(), "rejected");
+        assert_eq!(
+            EmailError::Permanent("mailbox unavailable".to_string()).skip_reason(),
+            "rejected"
+        );
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(request_id([]), None);
+        assert_eq!(request_id(["abc", "abc"]), Some("abc".to_string()));
+        assert_eq!(
+            request_id(["abc", "def", "abc", "ghi"]),
+            Some("abc+2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_request_id_header() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(200, &[], "{}");
+
+        let result =
+            send_batch_with_id(&conf, &logger, &transport, &[json!({})], Some("abc")).await;
+        assert_eq!(result, Ok(()));
+        assert!(transport.requests()[0]
+            .headers
+            .contains(&("X-Request-Id".to_string(), "abc".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_rejected_is_not_retried() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(422, &[], "invalid");
+
+        assert_eq!(
+            send_batch(&conf, &logger, &transport, &[json!({})]).await,
+            Err(EmailError::Status(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid".to_string()
+            ))
+        );
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_retries_transient_failures() {
+        let mut conf = test_config();
+        conf.retry.base_delay_ms = Some(1);
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport
+            .respond(500, &[], "internal error")
+            .fail("connection reset");
+
+        assert!(send_batch(&conf, &logger, &transport, &[json!({})])
+            .await
+            .is_ok());
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_gives_up_after_max_attempts() {
+        let mut conf = test_config();
+        conf.retry.max_attempts = Some(2);
+        conf.retry.base_delay_ms = Some(1);
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport
+            .respond(503, &[], "unavailable")
+            .respond(502, &[], "bad gateway")
+            .respond(500, &[], "internal error");
+
+        assert!(send_batch(&conf, &logger, &transport, &[json!({})])
+            .await
+            .is_err());
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let retry = Retry {
+            max_attempts: None,
+            base_delay_ms: Some(100),
+            multiplier: Some(3.0),
+        };
+        assert_eq!(backoff_delay(&retry, 1), Duration::from_millis(100));
+        assert_eq!(backoff_delay(&retry, 2), Duration::from_millis(300));
+        assert_eq!(backoff_delay(&retry, 3), Duration::from_millis(900));
+        assert_eq!(backoff_delay(&retry, 30), Duration::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_dry_run() {
+        let mut conf = test_config();
+        conf.processing.dry_run = Some(true);
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+
+        assert!(send_batch(&conf, &logger, &transport, &[json!({})])
+            .await
+            .is_ok());
+        assert!(transport.requests().is_empty());
+    }
+
+    fn response_with_retry_after(value: &str) -> TransportResponse {
+        TransportResponse {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            headers: vec![("retry-after".to_string(), value.to_string())],
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            retry_after(&response_with_retry_after("12")),
+            Some(Duration::from_secs(12))
+        );
+        assert_eq!(
+            retry_after(&response_with_retry_after("100000")),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            retry_after(&response_with_retry_after("Wed, 21 Oct 2015 07:28:00 GMT")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_rate_limited() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(429, &[("Retry-After", "1")], "too many requests");
+
+        let before = METRICS.email_rate_limited_total.get();
+        let started = Instant::now();
+        assert!(send_batch(&conf, &logger, &transport, &[json!({})])
+            .await
+            .is_ok());
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(transport.requests().len(), 2);
+        assert!(METRICS.email_rate_limited_total.get() > before);
+    }
+}
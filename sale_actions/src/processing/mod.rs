@@ -1,7 +1,28 @@
+use crate::{config::Config, utils::redact_email};
+use email_address::EmailAddress;
+use mongodb::bson::{doc, Document};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
+pub mod backfill;
+pub mod breaker;
+pub mod checkpoint;
+pub mod claims;
+pub mod indexes;
+pub mod mailer;
+pub mod provider;
 pub mod purchases;
+pub mod redrive;
 pub mod renewal;
+pub mod runs;
+pub mod settings;
+pub mod subscribers;
+pub mod suppression;
+pub mod templates;
+pub mod transport;
+pub mod unsubscribe;
+pub mod webhooks;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MetadataDoc {
@@ -10,3 +31,348 @@ pub struct MetadataDoc {
     pub tax_state: String,
     pub salt: String,
 }
+
+// Entry of the `processed` collection, sales marked without an email being
+// sent are recorded as skipped along with the reason
+pub fn processed_doc(meta_hash: &str, skip_reason: Option<&str>) -> Document {
+    match skip_reason {
+        Some(reason) => doc! { "meta_hash": meta_hash, "outcome": "skipped", "reason": reason },
+        None => doc! { "meta_hash": meta_hash, "outcome": "sent" },
+    }
+}
+
+// Groups of a sale without the repeats of the same_tx_groups lookup, in their
+// original order
+pub fn dedup_groups(groups: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    groups
+        .iter()
+        .filter(|group| seen.insert(group.as_str()))
+        .cloned()
+        .collect()
+}
+
+// Distinct valid emails of a sale or renewal metadata in lowercase, so an
+// address entered twice with another casing is emailed once. An invalid entry
+// does not prevent the other ones from being emailed
+pub fn recipients(metadata: &[MetadataDoc]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    metadata
+        .iter()
+        .map(|metadata| metadata.email.trim())
+        .filter(|email| EmailAddress::is_valid(email))
+        .map(|email| email.to_lowercase())
+        .filter(|email| seen.insert(email.clone()))
+        .collect()
+}
+
+// Recipient as written to the logs, masked unless watchtower.redact_pii is off
+pub fn loggable_email(conf: &Config, email: &str) -> String {
+    if conf.watchtower.redact_pii.unwrap_or(true) {
+        redact_email(email)
+    } else {
+        email.to_string()
+    }
+}
+
+// Provider subscribers endpoint with every value percent-encoded, the keys are
+// fixed names such as fields[name] and are kept as is
+pub fn subscriber_path(base_url: &str, params: &[(&str, &str)]) -> String {
+    let query: Vec<String> = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+        .collect();
+    format!("{}/subscribers?{}", base_url, query.join("&"))
+}
+
+// Fields of email.extra_fields, every {key} of their values is replaced with
+// the sale value of that name. Unknown keys are kept as written
+pub fn extra_fields(conf: &Config, values: &[(&str, &str)]) -> Vec<(String, String)> {
+    conf.email
+        .extra_fields
+        .iter()
+        .flatten()
+        .map(|(key, template)| {
+            let value = values
+                .iter()
+                .fold(template.clone(), |value, (name, sale_value)| {
+                    value.replace(&format!("{{{}}}", name), sale_value)
+                });
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+// Counts of a pipeline run, `read` includes the sales that could not be parsed
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct ProcessingSummary {
+    pub read: usize,
+    pub sent: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    // sales and renewal toggles without a single valid email, marked processed
+    // so they are not read again, the sales among them are counted in skipped
+    pub invalid_email: usize,
+}
+
+// Empty lists allow every tax state, the deny list wins over the allow list
+pub fn is_tax_state_allowed(tax_state: &str, allowed: &[String], denied: &[String]) -> bool {
+    let matches = |state: &String| state.eq_ignore_ascii_case(tax_state.trim());
+    if denied.iter().any(matches) {
+        return false;
+    }
+    allowed.is_empty() || allowed.iter().any(matches)
+}
+
+// Whether so many sends failed during the run that it should be aborted, only
+// decided once the sample is large enough to be meaningful
+pub fn failure_rate_exceeded(sent: usize, failed: usize, max_rate: f64, min_sample: usize) -> bool {
+    let total = sent + failed;
+    total > 0 && total >= min_sample && failed as f64 / total as f64 > max_rate
+}
+
+// Remembers the (email, domain) pairs already sent during a run so duplicated
+// sales can't trigger the same email twice, even when processed concurrently.
+// Fed with the addresses of recipients(), one claim per lowercased address
+#[derive(Default)]
+pub struct RunDedup {
+    sent: Mutex<HashSet<(String, String)>>,
+}
+
+impl RunDedup {
+    // Returns true the first time a pair is claimed, false for any duplicate
+    pub fn claim(&self, email: &str, domain: &str) -> bool {
+        self.sent
+            .lock()
+            .unwrap()
+            .insert((email.trim().to_lowercase(), domain.trim().to_lowercase()))
+    }
+}
+
+// Counts the emails sent to each recipient during a run and refuses any send
+// beyond the configured maximum
+pub struct RecipientLimiter {
+    max_per_recipient: usize,
+    sent: Mutex<HashMap<String, usize>>,
+}
+
+impl RecipientLimiter {
+    pub fn new(max_per_recipient: usize) -> Self {
+        RecipientLimiter {
+            max_per_recipient,
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns true and counts the send if the recipient is still under the limit
+    pub fn allow(&self, email: &str) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        let count = sent.entry(email.trim().to_lowercase()).or_insert(0);
+        if *count >= self.max_per_recipient {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod processing_tests {
+    use super::{
+        extra_fields, failure_rate_exceeded, is_tax_state_allowed, loggable_email, subscriber_path,
+        RecipientLimiter, RunDedup,
+    };
+    use crate::config::test_config;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_dedup_duplicates_in_batch() {
+        let dedup = RunDedup::default();
+        let batch = [
+            ("alice@example.com", "alice.stark"),
+            ("bob@example.com", "bob.stark"),
+            ("alice@example.com", "alice.stark"),
+            ("Alice@Example.com", "ALICE.stark"),
+            ("alice@example.com", "other.stark"),
+        ];
+
+        let sent: Vec<_> = batch
+            .iter()
+            .filter(|(email, domain)| dedup.claim(email, domain))
+            .collect();
+
+        assert_eq!(
+            sent,
+            vec![
+                &("alice@example.com", "alice.stark"),
+                &("bob@example.com", "bob.stark"),
+                &("alice@example.com", "other.stark"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dedup_concurrent_claims() {
+        let dedup = Arc::new(RunDedup::default());
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let dedup = Arc::clone(&dedup);
+                tokio::spawn(async move { dedup.claim("alice@example.com", "alice.stark") })
+            })
+            .collect();
+
+        let mut claimed = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                claimed += 1;
+            }
+        }
+        assert_eq!(claimed, 1);
+    }
+
+    #[test]
+    fn test_recipient_limiter_caps_sends() {
+        let limiter = RecipientLimiter::new(3);
+        let recipients = ["flood@example.com", "FLOOD@example.com"];
+        let allowed = (0..20).filter(|i| limiter.allow(recipients[i % 2])).count();
+        assert_eq!(allowed, 3);
+        assert!(limiter.allow("other@example.com"));
+    }
+
+    #[test]
+    fn test_recipient_limiter_zero_blocks_all() {
+        let limiter = RecipientLimiter::new(0);
+        assert!(!limiter.allow("alice@example.com"));
+    }
+
+    fn states(states: &[&str]) -> Vec<String> {
+        states.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tax_states_empty_lists_allow_all() {
+        assert!(is_tax_state_allowed("FR", &[], &[]));
+        assert!(is_tax_state_allowed("", &[], &[]));
+    }
+
+    #[test]
+    fn test_tax_states_allow_list() {
+        let allowed = states(&["FR", "DE"]);
+        assert!(is_tax_state_allowed("FR", &allowed, &[]));
+        assert!(is_tax_state_allowed("de", &allowed, &[]));
+        assert!(!is_tax_state_allowed("US-CA", &allowed, &[]));
+    }
+
+    #[test]
+    fn test_tax_states_deny_list() {
+        let denied = states(&["US-CA"]);
+        assert!(!is_tax_state_allowed("US-CA", &[], &denied));
+        assert!(is_tax_state_allowed("FR", &[], &denied));
+        assert!(!is_tax_state_allowed(
+            "FR",
+            &states(&["FR"]),
+            &states(&["fr"])
+        ));
+    }
+
+    #[test]
+    fn test_failure_rate_exceeded() {
+        // not enough sends to decide yet
+        assert!(!failure_rate_exceeded(0, 10, 0.5, 20));
+        assert!(failure_rate_exceeded(5, 15, 0.5, 20));
+        assert!(!failure_rate_exceeded(10, 10, 0.5, 20));
+        assert!(!failure_rate_exceeded(0, 0, 0.0, 0));
+    }
+
+    #[test]
+    fn test_failure_rate_aborts_run() {
+        // one batch of 10 sales per send, the provider starts failing after two
+        let outcomes = [true, true, false, false, false, false, false, false];
+        let (mut sent, mut failed, mut batches) = (0, 0, 0);
+        for success in outcomes {
+            batches += 1;
+            if success {
+                sent += 10;
+            } else {
+                failed += 10;
+            }
+            if failure_rate_exceeded(sent, failed, 0.5, 20) {
+                break;
+            }
+        }
+        assert_eq!(batches, 5);
+    }
+
+    #[test]
+    fn test_subscriber_path_encodes_values() {
+        let email = "a+b&c=d@example.com";
+        let path = subscriber_path(
+            "https://provider/api",
+            &[
+                ("email", email),
+                ("fields[name]", "café.stark"),
+                ("groups[]", "1"),
+            ],
+        );
+        assert_eq!(
+            path,
+            "https://provider/api/subscribers?email=a%2Bb%26c%3Dd%40example.com&fields[name]=caf%C3%A9.stark&groups[]=1"
+        );
+
+        let (_, query) = path.split_once('?').unwrap();
+        let encoded = query
+            .split('&')
+            .next()
+            .unwrap()
+            .strip_prefix("email=")
+            .unwrap();
+        assert_eq!(urlencoding::decode(encoded).unwrap(), email);
+    }
+
+    #[test]
+    fn test_extra_fields_interpolated() {
+        let mut conf = test_config();
+        assert!(extra_fields(&conf, &[("domain", "ben.stark")]).is_empty());
+
+        conf.email.extra_fields = Some(
+            [
+                ("profile_url", "https://app.starknet.id/{domain}"),
+                ("campaign", "spring"),
+                ("note", "{domain} {unknown}"),
+            ]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        );
+        assert_eq!(
+            extra_fields(&conf, &[("domain", "ben.stark")]),
+            vec![
+                ("campaign".to_string(), "spring".to_string()),
+                ("note".to_string(), "ben.stark {unknown}".to_string()),
+                (
+                    "profile_url".to_string(),
+                    "https://app.starknet.id/ben.stark".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loggable_email() {
+        let mut conf = test_config();
+        assert_eq!(
+            loggable_email(&conf, "alice@example.com"),
+            "a***@example.com"
+        );
+        conf.watchtower.redact_pii = None;
+        assert_eq!(
+            loggable_email(&conf, "alice@example.com"),
+            "a***@example.com"
+        );
+        conf.watchtower.redact_pii = Some(false);
+        assert_eq!(
+            loggable_email(&conf, "alice@example.com"),
+            "alice@example.com"
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use super::{
+    mailer::{request_id, send_batch_with_id, EmailError},
+    renewal, subscriber_path,
+    templates::Templates,
+    transport::EmailTransport,
+};
+use crate::{
+    config::{Config, Smtp},
+    logger::Logger,
+};
+use async_trait::async_trait;
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Method;
+use serde_json::{json, Value};
+
+// What the email is about, local templates are picked by it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmailKind {
+    Purchase,
+    // auto renewal enabled on a domain
+    Renewal,
+}
+
+impl EmailKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailKind::Purchase => "purchase",
+            EmailKind::Renewal => "renewal",
+        }
+    }
+}
+
+// Content of an email about a sale, each provider decides how it is rendered
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmailFields {
+    pub fields: Vec<(String, String)>,
+    pub groups: Vec<String>,
+    pub kind: EmailKind,
+    // sale the email is about, sent to the provider as X-Request-Id
+    pub meta_hash: String,
+}
+
+#[async_trait]
+pub trait EmailProvider: Send + Sync {
+    async fn send(&self, recipient: &str, fields: &EmailFields) -> Result<(), EmailError>;
+
+    // What send would do, logged by dry runs
+    fn describe(&self, recipient: &str, fields: &EmailFields) -> String {
+        format!("{} {:?}", recipient, fields)
+    }
+
+    // Providers with a batch API override it to send everything at once
+    async fn send_many(&self, emails: &[(String, EmailFields)]) -> Result<(), EmailError> {
+        for (recipient, fields) in emails {
+            self.send(recipient, fields).await?;
+        }
+        Ok(())
+    }
+
+    // Auto renewal turned off for the recipients of a toggle, only providers
+    // keeping subscribers in an auto renewal group have something to undo
+    async fn disable_renewal(&self, _recipients: &[&str]) -> Result<(), EmailError> {
+        Ok(())
+    }
+}
+
+// Hosted provider: the recipient is subscribed with the fields and groups,
+// the provider automation sends the actual email
+pub struct RestProvider<'a> {
+    conf: &'a Config,
+    logger: &'a Logger,
+    transport: &'a dyn EmailTransport,
+}
+
+impl<'a> RestProvider<'a> {
+    pub fn new(conf: &'a Config, logger: &'a Logger, transport: &'a dyn EmailTransport) -> Self {
+        RestProvider {
+            conf,
+            logger,
+            transport,
+        }
+    }
+
+    pub fn request(&self, recipient: &str, fields: &EmailFields) -> Value {
+        let keys: Vec<String> = fields
+            .fields
+            .iter()
+            .map(|(key, _)| format!("fields[{}]", key))
+            .collect();
+        let mut params = vec![("email", recipient)];
+        for (key, (_, value)) in keys.iter().zip(&fields.fields) {
+            params.push((key.as_str(), value.as_str()));
+        }
+        for group in &fields.groups {
+            params.push(("groups[]", group.as_str()));
+        }
+        json!({
+            "method": Method::POST.as_str(),
+            "path": subscriber_path(&self.conf.email.base_url, &params),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailProvider for RestProvider<'_> {
+    async fn send(&self, recipient: &str, fields: &EmailFields) -> Result<(), EmailError> {
+        let requests = [self.request(recipient, fields)];
+        let request_id = request_id([fields.meta_hash.as_str()]);
+        send_batch_with_id(
+            self.conf,
+            self.logger,
+            self.transport,
+            &requests,
+            request_id.as_deref(),
+        )
+        .await
+    }
+
+    fn describe(&self, recipient: &str, fields: &EmailFields) -> String {
+        let request = self.request(recipient, fields);
+        format!(
+            "{} {}",
+            request["method"].as_str().unwrap_or_default(),
+            request["path"].as_str().unwrap_or_default()
+        )
+    }
+
+    // The subscribers are moved out of email.ar_group_id, in a single batch
+    async fn disable_renewal(&self, recipients: &[&str]) -> Result<(), EmailError> {
+        let mut requests = Vec::new();
+        for email in recipients {
+            requests.push(renewal::disable_request(self.conf, self.transport, email).await?);
+        }
+        send_batch_with_id(self.conf, self.logger, self.transport, &requests, None).await
+    }
+
+    async fn send_many(&self, emails: &[(String, EmailFields)]) -> Result<(), EmailError> {
+        let requests: Vec<Value> = emails
+            .iter()
+            .map(|(recipient, fields)| self.request(recipient, fields))
+            .collect();
+        let request_id = request_id(emails.iter().map(|(_, fields)| fields.meta_hash.as_str()));
+        send_batch_with_id(
+            self.conf,
+            self.logger,
+            self.transport,
+            &requests,
+            request_id.as_deref(),
+        )
+        .await
+    }
+}
+
+const DEFAULT_SMTP_PORT: u16 = 587;
+const DEFAULT_SUBJECT: &str = "Your domain {name}";
+const DEFAULT_BODY: &str = "Thank you for purchasing {name}, it is yours until {expiry}.";
+const DEFAULT_RENEWAL_SUBJECT: &str = "Auto renewal of {name} is on";
+const DEFAULT_RENEWAL_BODY: &str = "Auto renewal of {name} is on, it will be renewed by {renewer}.";
+
+// Replaces every {key} of the template with the field value
+pub fn render(template: &str, fields: &EmailFields) -> String {
+    fields
+        .fields
+        .iter()
+        .fold(template.to_string(), |rendered, (key, value)| {
+            rendered.replace(&format!("{{{}}}", key), value)
+        })
+}
+
+// Self-hosted alternative sending the email itself through an SMTP relay,
+// as HTML and plain text when smtp.templates_dir is set
+pub struct SmtpProvider {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    subject: String,
+    body: String,
+    renewal_subject: String,
+    renewal_body: String,
+    templates: Option<Templates>,
+}
+
+impl SmtpProvider {
+    pub fn new(conf: &Smtp) -> Result<Self, String> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(&conf.host)
+            .map_err(|e| format!("invalid smtp host {}: {}", conf.host, e))?
+            .port(conf.port.unwrap_or(DEFAULT_SMTP_PORT));
+        if let (Some(username), Some(password)) = (&conf.username, &conf.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        let from = conf
+            .from
+            .parse::<Mailbox>()
+            .map_err(|e| format!("invalid smtp.from {}: {}", conf.from, e))?;
+
+        Ok(SmtpProvider {
+            mailer: builder.build(),
+            from,
+            subject: conf
+                .subject
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SUBJECT.to_string()),
+            body: conf
+                .body
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BODY.to_string()),
+            renewal_subject: conf
+                .renewal_subject
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RENEWAL_SUBJECT.to_string()),
+            renewal_body: conf
+                .renewal_body
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RENEWAL_BODY.to_string()),
+            templates: conf
+                .templates_dir
+                .as_deref()
+                .map(Templates::load)
+                .transpose()?,
+        })
+    }
+
+    // Subject and body used without templates_dir
+    fn plain(&self, kind: EmailKind) -> (&str, &str) {
+        match kind {
+            EmailKind::Purchase => (&self.subject, &self.body),
+            EmailKind::Renewal => (&self.renewal_subject, &self.renewal_body),
+        }
+    }
+
+    fn subject(&self, fields: &EmailFields) -> Result<String, String> {
+        match &self.templates {
+            Some(templates) => templates.render(fields).map(|email| email.subject),
+            None => Ok(render(self.plain(fields.kind).0, fields)),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailProvider for SmtpProvider {
+    async fn send(&self, recipient: &str, fields: &EmailFields) -> Result<(), EmailError> {
+        let to = recipient
+            .parse::<Mailbox>()
+            .map_err(|e| EmailError::InvalidAddress(e.to_string()))?;
+        let builder = Message::builder().from(self.from.clone()).to(to);
+        let message = match &self.templates {
+            Some(templates) => {
+                let email = templates.render(fields).map_err(EmailError::Permanent)?;
+                builder
+                    .subject(email.subject)
+                    .multipart(MultiPart::alternative_plain_html(email.text, email.html))
+            }
+            None => {
+                let (subject, body) = self.plain(fields.kind);
+                builder
+                    .subject(render(subject, fields))
+                    .body(render(body, fields))
+            }
+        }
+        .map_err(|e| EmailError::Permanent(e.to_string()))?;
+
+        match self.mailer.send(message).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_permanent() => Err(EmailError::Permanent(e.to_string())),
+            Err(e) => Err(EmailError::Network(e.to_string())),
+        }
+    }
+
+    fn describe(&self, recipient: &str, fields: &EmailFields) -> String {
+        match self.subject(fields) {
+            Ok(subject) => format!("smtp to {}: {}", recipient, subject),
+            Err(e) => format!("smtp to {} would fail: {}", recipient, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod provider_tests {
+    use super::{render, EmailFields, EmailKind, EmailProvider, RestProvider, SmtpProvider};
+    use crate::{config::test_config, logger::Logger, processing::transport::MockTransport};
+    use serde_json::json;
+
+    fn fields() -> EmailFields {
+        EmailFields {
+            fields: vec![
+                ("name".to_string(), "ben.stark".to_string()),
+                ("expiry".to_string(), "2025-01-01 00:00:00".to_string()),
+            ],
+            groups: vec!["123".to_string()],
+            kind: EmailKind::Purchase,
+            meta_hash: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rest_request() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        assert_eq!(
+            provider.request("a+b@example.com", &fields()),
+            json!({
+                "method": "POST",
+                "path": format!(
+                    "{}/subscribers?email=a%2Bb%40example.com&fields[name]=ben.stark&fields[expiry]=2025-01-01%2000%3A00%3A00&groups[]=123",
+                    conf.email.base_url
+                ),
+            })
+        );
+    }
+
+    #[test]
+    fn test_rest_describe() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        assert_eq!(
+            provider.describe("a***@example.com", &fields()),
+            format!(
+                "POST {}/subscribers?email=a%2A%2A%2A%40example.com&fields[name]=ben.stark&fields[expiry]=2025-01-01%2000%3A00%3A00&groups[]=123",
+                conf.email.base_url
+            )
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("{name} expires on {expiry}, {unknown}", &fields()),
+            "ben.stark expires on 2025-01-01 00:00:00, {unknown}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smtp_renewal_subject() {
+        let smtp =
+            toml::from_str("host = \"smtp.example.com\"\nfrom = \"noreply@example.com\"").unwrap();
+        let provider = SmtpProvider::new(&smtp).unwrap();
+        let renewal = EmailFields {
+            fields: vec![
+                ("name".to_string(), "ben.stark".to_string()),
+                ("renewer".to_string(), "0x123".to_string()),
+            ],
+            groups: vec![],
+            kind: EmailKind::Renewal,
+            meta_hash: "0x1".to_string(),
+        };
+        assert_eq!(
+            provider.describe("alice@example.com", &renewal),
+            "smtp to alice@example.com: Auto renewal of ben.stark is on"
+        );
+        assert_eq!(
+            provider.describe("alice@example.com", &fields()),
+            "smtp to alice@example.com: Your domain ben.stark"
+        );
+    }
+}
@@ -1,93 +1,531 @@
-use super::MetadataDoc;
-use crate::{config::Config, logger::Logger};
+use super::{
+    checkpoint::{self, Checkpoint, Progress},
+    claims, dedup_groups, extra_fields, failure_rate_exceeded, is_tax_state_allowed,
+    loggable_email,
+    mailer::EmailError,
+    processed_doc,
+    provider::{EmailFields, EmailKind, EmailProvider},
+    recipients, suppression, unsubscribe, webhooks, MetadataDoc, ProcessingSummary,
+    RecipientLimiter, RunDedup,
+};
+use crate::{
+    config::Config,
+    logger::Logger,
+    metrics::METRICS,
+    utils::{
+        canonical_address, deserialize_lenient_f64, deserialize_lenient_i64, normalize_domain,
+        readable_domain,
+    },
+};
 use chrono::NaiveDateTime;
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
 use mongodb::{
-    bson::{doc, Document},
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
     Collection, Database,
 };
-use reqwest::{header, Client};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use std::collections::HashSet;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SaleDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
     pub tx_hash: String,
+    pub meta_hash: String,
     pub domain: String,
+    #[serde(deserialize_with = "deserialize_lenient_f64")]
     pub price: f64,
     pub payer: String,
+    pub sponsor: Option<String>,
+    #[serde(deserialize_with = "deserialize_lenient_i64")]
     pub timestamp: i64,
+    #[serde(deserialize_with = "deserialize_lenient_i64")]
     pub expiry: i64,
     pub metadata: Vec<MetadataDoc>,
     pub same_tx_groups: Vec<String>, // The new field
 }
 
-// Adjusted process_sale to create a request object instead of directly sending
-fn create_sale_request(sale: &SaleDoc, base_url: &str) -> Value {
-    let groups_params: Vec<String> = sale
-        .same_tx_groups
-        .iter()
-        .map(|group| format!("groups[]={}", group))
-        .collect();
+// Rewrites the sponsor in its canonical hex form, an invalid sponsor is dropped
+fn canonicalize_sponsor(sale: &mut SaleDoc) -> Result<(), String> {
+    match canonical_address(sale.sponsor.as_deref()) {
+        Ok(sponsor) => {
+            sale.sponsor = sponsor;
+            Ok(())
+        }
+        Err(e) => {
+            sale.sponsor = None;
+            Err(e)
+        }
+    }
+}
 
-    let url = format!(
-        "{base_url}/subscribers?email={email}&fields[name]={domain}&fields[expiry]={expiry}&{groups}",
-        base_url = base_url,
-        email = urlencoding::encode(&sale.metadata[0].email),
-        domain = urlencoding::encode(&sale.domain),
-        expiry = match NaiveDateTime::from_timestamp_opt(sale.expiry, 0) {
-            Some(time) => urlencoding::encode(&time.format("%Y-%m-%d %H:%M:%S").to_string()).to_string(),
-            _ => "none".to_string(),
-        },
-        groups = groups_params.join("&")
+// Rewrites the payer in its canonical hex form, an invalid payer is kept as
+// read since the sale is still owed its email
+fn canonicalize_payer(sale: &mut SaleDoc) -> Result<(), String> {
+    if let Some(payer) = canonical_address(Some(&sale.payer))? {
+        sale.payer = payer;
+    }
+    Ok(())
+}
+
+// 2100-01-01, later expiries can only come from a corrupted sale
+const MAX_EXPIRY: i64 = 4_102_444_800;
+
+// Expiry as shown in the email, nonsense timestamps are errors so the sale is
+// not emailed with them
+fn format_expiry(expiry: i64) -> Result<String, String> {
+    if expiry <= 0 || expiry >= MAX_EXPIRY {
+        return Err(format!("expiry {} is out of range", expiry));
+    }
+    NaiveDateTime::from_timestamp_opt(expiry, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .ok_or_else(|| format!("expiry {} is not a valid timestamp", expiry))
+}
+
+// Adjusted process_sale to describe the emails instead of directly sending,
+// one per recipient
+fn sale_emails(sale: &SaleDoc, conf: &Config) -> Vec<(String, EmailFields)> {
+    let domain = normalize_domain(
+        &readable_domain(&sale.domain),
+        conf.email.domain_suffix.as_deref(),
+        conf.email.strip_domain_suffix.unwrap_or(false),
+    );
+    let Ok(expiry) = format_expiry(sale.expiry) else {
+        return Vec::new();
+    };
+    let mut fields = extra_fields(
+        conf,
+        &[
+            ("domain", domain.as_str()),
+            ("expiry", expiry.as_str()),
+            ("tx_hash", sale.tx_hash.as_str()),
+            ("payer", sale.payer.as_str()),
+        ],
     );
+    fields.insert(0, ("name".to_string(), domain));
+    fields.insert(1, ("expiry".to_string(), expiry));
+    let fields = EmailFields {
+        fields,
+        groups: dedup_groups(&sale.same_tx_groups),
+        kind: EmailKind::Purchase,
+        meta_hash: sale.meta_hash.clone(),
+    };
 
-    json!({
-        "method": "POST",
-        "path": &url,
-    })
+    recipients(&sale.metadata)
+        .into_iter()
+        .map(|email| {
+            let fields = unsubscribe::recipient_fields(conf, &email, &fields);
+            (email, fields)
+        })
+        .collect()
 }
 
-// process batch requests
-async fn process_batch(conf: &Config, logger: &Logger, sales: &[SaleDoc]) {
-    let requests: Vec<Value> = sales
+// process batch requests, the sales are only marked processed once it is Ok
+async fn process_batch(
+    conf: &Config,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    sales: &[SaleDoc],
+) -> Result<(), EmailError> {
+    let emails: Vec<(String, EmailFields)> = sales
         .iter()
-        .map(|sale| create_sale_request(sale, &conf.email.base_url))
+        .flat_map(|sale| sale_emails(sale, conf))
         .collect();
 
-    let batch_request = json!({
-        "requests": requests
-    });
-
-    let client = Client::new();
-    match client
-        .post("https://api.mailerlite.com/api/v2/batch")
-        .header("X-MailerLite-ApiKey", &conf.email.api_key)
-        .header(header::CONTENT_TYPE, "application/json")
-        .json(&batch_request)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            if !res.status().is_success() {
+    if conf.processing.dry_run.unwrap_or(false) {
+        for (recipient, fields) in &emails {
+            let recipient = loggable_email(conf, recipient);
+            logger.info(format!(
+                "dry run: would email {}: {}",
+                recipient,
+                provider.describe(&recipient, fields)
+            ));
+        }
+        return Ok(());
+    }
+    provider.send_many(&emails).await
+}
+
+// Drops the suppressed recipients of the sale, bounced or complaining ones are
+// never emailed again. Returns how many were dropped, or why nothing can be
+// sent
+fn check_recipients(sale: &mut SaleDoc, suppressed: &HashSet<String>) -> Result<usize, EmailError> {
+    let dropped = suppression::drop_suppressed(sale, suppressed);
+    if !recipients(&sale.metadata).is_empty() {
+        return Ok(dropped);
+    }
+    if dropped > 0 {
+        Err(EmailError::Suppressed)
+    } else {
+        Err(EmailError::InvalidAddress(
+            "no valid email in the sale metadata".to_string(),
+        ))
+    }
+}
+
+// Keeps the recipients of the sale that may be emailed in this run: their tax
+// state is enabled, they were not emailed about the domain yet and they are
+// under max_sends_per_recipient. Returns each dropped recipient along with the
+// reason it is skipped for
+fn filter_recipients(
+    sale: &mut SaleDoc,
+    email_tax_states: &[String],
+    skip_tax_states: &[String],
+    dedup: Option<&RunDedup>,
+    limiter: &RecipientLimiter,
+) -> Vec<(String, &'static str)> {
+    let mut kept = HashSet::new();
+    let mut dropped = Vec::new();
+    for email in recipients(&sale.metadata) {
+        let tax_state = sale
+            .metadata
+            .iter()
+            .find(|metadata| metadata.email.trim().to_lowercase() == email)
+            .map(|metadata| metadata.tax_state.as_str())
+            .unwrap_or_default();
+        let reason = if !is_tax_state_allowed(tax_state, email_tax_states, skip_tax_states) {
+            Some("tax_state")
+        } else if dedup.is_some_and(|dedup| !dedup.claim(&email, &sale.domain)) {
+            Some("duplicate")
+        } else if !limiter.allow(&email) {
+            Some("recipient_limit")
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => dropped.push((email, reason)),
+            None => {
+                kept.insert(email);
+            }
+        }
+    }
+    sale.metadata
+        .retain(|metadata| kept.contains(&metadata.email.trim().to_lowercase()));
+    dropped
+}
+
+// Status and body of a provider error as kept in failed_emails
+pub fn failure_details(error: &EmailError) -> (Option<i32>, String) {
+    match error {
+        EmailError::Status(status, body) => (Some(status.as_u16() as i32), body.clone()),
+        other => (None, other.to_string()),
+    }
+}
+
+// failed_emails entries for a batch the provider rejected, one per recipient
+fn dead_letters(batch: &[SaleDoc], error: &EmailError) -> Vec<Document> {
+    let (status, body) = failure_details(error);
+    let body = body.as_str();
+    let timestamp = DateTime::now();
+    batch
+        .iter()
+        .flat_map(|sale| {
+            recipients(&sale.metadata)
+                .into_iter()
+                .map(move |recipient| {
+                    doc! {
+                        "meta_hash": &sale.meta_hash,
+                        "recipient": recipient,
+                        "status": status,
+                        "body": body,
+                        "timestamp": timestamp,
+                    }
+                })
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct SendResults {
+    // meta_hash of the sales the provider accepted
+    delivered: Vec<String>,
+    // meta_hash of the sales rejected with a non retryable error, along with
+    // the reason they are marked processed with
+    rejected: Vec<(String, &'static str)>,
+    // sales whose batch failed, rejected ones included
+    failed: usize,
+    dead_letters: Vec<Document>,
+    // sales whose batch failed with a transient error, retried next run
+    retrying: Vec<(SaleDoc, EmailError)>,
+    // meta_hash of the sales left unprocessed, their claim is released
+    released: Vec<String>,
+}
+
+// Sends the batches with up to email.concurrency requests in flight
+async fn send_batches(
+    conf: &Config,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    batches: Vec<Vec<SaleDoc>>,
+) -> SendResults {
+    let concurrency = conf.email.concurrency.unwrap_or(8).max(1);
+    let outcomes: Vec<(Vec<SaleDoc>, Result<(), EmailError>)> = stream::iter(batches)
+        .map(|batch| async move {
+            let result = process_batch(conf, logger, provider, &batch).await;
+            (batch, result)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut results = SendResults::default();
+    for (batch, result) in outcomes {
+        match result {
+            Ok(()) => {
+                if !conf.processing.dry_run.unwrap_or(false) {
+                    webhooks::sale_processed(conf, logger, &batch);
+                }
+                results
+                    .delivered
+                    .extend(batch.into_iter().map(|sale| sale.meta_hash))
+            }
+            Err(error) => {
+                results.failed += batch.len();
+                if !error.is_retryable() {
+                    let reason = error.skip_reason();
+                    if reason == "rejected" {
+                        results.dead_letters.extend(dead_letters(&batch, &error));
+                    }
+                    results
+                        .rejected
+                        .extend(batch.into_iter().map(|sale| (sale.meta_hash, reason)));
+                } else {
+                    results
+                        .released
+                        .extend(batch.iter().map(|sale| sale.meta_hash.clone()));
+                    // skipped by the circuit breaker, the send was not attempted
+                    if error != EmailError::CircuitOpen {
+                        results
+                            .retrying
+                            .extend(batch.into_iter().map(|sale| (sale, error.clone())));
+                    }
+                }
+            }
+        }
+    }
+    METRICS
+        .sales_processed_total
+        .add(results.delivered.len() as u64);
+    METRICS.sales_failed_total.add(results.failed as u64);
+    results
+}
+
+pub const ATTEMPTS_COLLECTION: &str = "send_attempts";
+
+// Whether a sale that failed in `attempts` runs is given up on
+pub fn attempts_exhausted(attempts: i32, max_attempts: Option<u32>) -> bool {
+    max_attempts.map_or(false, |max| attempts >= max as i32)
+}
+
+// Counts the runs each sale failed in within send_attempts, along with the
+// last error. Once processing.max_attempts is reached the sale is parked in
+// failed_emails and marked processed so it stops being retried forever
+async fn record_attempts(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    retrying: Vec<(SaleDoc, EmailError)>,
+    results: &mut Vec<Document>,
+    processed: &mut Vec<Document>,
+) {
+    let attempts_collection = db.collection::<Document>(ATTEMPTS_COLLECTION);
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    for (sale, error) in retrying {
+        let update = doc! {
+            "$inc": { "attempts": 1 },
+            "$set": { "last_error": error.to_string(), "last_attempt_at": DateTime::now() },
+        };
+        let attempts = match attempts_collection
+            .find_one_and_update(
+                doc! { "meta_hash": &sale.meta_hash },
+                update,
+                options.clone(),
+            )
+            .await
+        {
+            Ok(tracking) => tracking
+                .and_then(|tracking| tracking.get_i32("attempts").ok())
+                .unwrap_or(1),
+            Err(e) => {
                 logger.severe(format!(
-                    "Received non-success status from batch request: {}. Response body: {}",
-                    res.status(),
-                    res.text()
-                        .await
-                        .unwrap_or_else(|_| "Failed to retrieve response body".to_string())
+                    "Error updating the '{}' collection: {}",
+                    ATTEMPTS_COLLECTION, e
                 ));
+                continue;
             }
+        };
+        if attempts_exhausted(attempts, conf.processing.max_attempts) {
+            logger.warning(format!(
+                "giving up on sale {} after {} failed runs: {}",
+                sale.tx_hash, attempts, error
+            ));
+            results.extend(dead_letters(std::slice::from_ref(&sale), &error));
+            processed.push(processed_doc(&sale.meta_hash, Some("max_attempts")));
         }
-        Err(e) => {
-            logger.severe(format!("Failed to send batch request: {}", e));
+    }
+}
+
+// Records the outcome of a round of batches, rejected sales are parked in
+// failed_emails and marked processed so they are not sent again until an
+// operator re-drives them
+async fn record_results(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    mut results: SendResults,
+    processed: &mut Vec<Document>,
+) {
+    processed.extend(
+        results
+            .delivered
+            .iter()
+            .map(|hash| processed_doc(hash, None)),
+    );
+    processed.extend(
+        results
+            .rejected
+            .iter()
+            .map(|(hash, reason)| processed_doc(hash, Some(reason))),
+    );
+    claims::release(db, logger, &results.released).await;
+    let retrying = std::mem::take(&mut results.retrying);
+    record_attempts(
+        conf,
+        db,
+        logger,
+        retrying,
+        &mut results.dead_letters,
+        processed,
+    )
+    .await;
+    if results.dead_letters.is_empty() {
+        return;
+    }
+    if let Err(e) = db
+        .collection::<Document>("failed_emails")
+        .insert_many(results.dead_letters, None)
+        .await
+    {
+        logger.severe(format!(
+            "Error inserting into 'failed_emails' collection: {}",
+            e
+        ));
+    }
+}
+
+// Inserts the processed entries gathered so far so progress survives a crash
+// mid-run, dry runs only drop them. The checkpoint may then move past them
+async fn flush_processed(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    processed: &mut Vec<Document>,
+    progress: &mut Progress,
+) {
+    if processed.is_empty() {
+        return;
+    }
+    let processed = std::mem::take(processed);
+    if conf.processing.dry_run.unwrap_or(false) {
+        return;
+    }
+    match db
+        .collection::<Document>("processed")
+        .insert_many(processed.iter(), None)
+        .await
+    {
+        Ok(_) => progress.marked(
+            processed
+                .iter()
+                .filter_map(|document| document.get_str("meta_hash").ok()),
+        ),
+        Err(e) => logger.severe(format!(
+            "Error inserting into 'processed' collection: {}",
+            e
+        )),
+    }
+}
+
+const DEFAULT_EMPTY_RUN_ALERT_THRESHOLD: u64 = 100;
+
+// Rough number of sales left to process, used to report progress and to
+// notice a query that stopped matching
+async fn approximate_pending(db: &Database) -> u64 {
+    let sales = db
+        .collection::<Document>("sales")
+        .count_documents(doc! { "meta_hash": { "$ne": "" } }, None)
+        .await
+        .unwrap_or(0);
+    let processed = db
+        .collection::<Document>("processed")
+        .estimated_document_count(None)
+        .await
+        .unwrap_or(0);
+    sales.saturating_sub(processed)
+}
+
+// A run reading nothing is normal once the sales are caught up, not while
+// that many of them still look unprocessed
+fn empty_run_is_suspicious(read: usize, pending: u64, threshold: u64) -> bool {
+    read == 0 && pending >= threshold
+}
+
+// Writes the sales recorded so far and moves the checkpoint over the ones read
+// first that are all marked processed
+async fn save_checkpoint(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    processed: &mut Vec<Document>,
+    progress: &mut Progress,
+) {
+    let Some(name) = conf.processing.checkpoint.as_deref() else {
+        return;
+    };
+    if conf.processing.dry_run.unwrap_or(false) {
+        return;
+    }
+    flush_processed(conf, db, logger, processed, progress).await;
+    if let Some(position) = progress.advance() {
+        if let Err(e) = checkpoint::save(db, name, &position).await {
+            logger.severe(format!("Unable to save checkpoint {}: {}", name, e));
         }
     }
 }
 
 // collect sales and process in batch
-pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
-    let pipeline: Vec<Document> = vec![
+pub async fn process_data(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+) -> Result<ProcessingSummary, mongodb::error::Error> {
+    process_sales(conf, db, logger, provider, None).await
+}
+
+// Same as process_data for the unprocessed sales among meta_hashes only
+pub async fn process_selected(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    meta_hashes: &[String],
+) -> Result<ProcessingSummary, mongodb::error::Error> {
+    process_sales(conf, db, logger, provider, Some(meta_hashes)).await
+}
+
+async fn process_sales(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    only: Option<&[String]>,
+) -> Result<ProcessingSummary, mongodb::error::Error> {
+    let mut pipeline: Vec<Document> = vec![
         doc! {
             "$match": doc! {
                 "meta_hash": doc! {
@@ -115,7 +553,10 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
                     doc! {
                         "$project": doc! {
                             "_id": 0,
-                            "meta_hash": 1
+                            "meta_hash": 1,
+                            "email": 1,
+                            "tax_state": 1,
+                            "salt": 1
                         }
                     }
                 ],
@@ -194,6 +635,13 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
             "$project": doc! {
                 "meta_hash": 1,
                 "tx_hash": 1,
+                "domain": 1,
+                "price": 1,
+                "payer": 1,
+                "sponsor": 1,
+                "timestamp": 1,
+                "expiry": 1,
+                "metadata": 1,
                 "same_tx_groups": doc! {
                     "$map": doc! {
                         "input": "$same_tx_groups",
@@ -204,24 +652,251 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
             }
         },
     ];
+
+    if let Some(meta_hashes) = only {
+        pipeline.insert(
+            0,
+            doc! { "$match": { "meta_hash": { "$in": meta_hashes.to_vec() } } },
+        );
+    }
+
+    // Backfills read the sales in order and restart after the saved checkpoint
+    let checkpoint_name = conf.processing.checkpoint.as_deref();
+    if let Some(name) = checkpoint_name {
+        let saved = match checkpoint::load(db, name).await {
+            Ok(saved) => saved,
+            Err(e) => {
+                logger.severe(format!("Unable to load checkpoint {}: {}", name, e));
+                return Err(e);
+            }
+        };
+        if let Some(saved) = &saved {
+            logger.info(format!(
+                "resuming backfill {} after timestamp {}",
+                name, saved.timestamp
+            ));
+        }
+        pipeline.splice(0..0, checkpoint::resume_stages(saved.as_ref()));
+    }
+
+    let suppressed = match suppression::load(db).await {
+        Ok(suppressed) => suppressed,
+        Err(e) => {
+            logger.severe(format!("Unable to load the suppressed emails: {}", e));
+            return Err(e);
+        }
+    };
+
     let sales_collection: Collection<Document> = db.collection("sales");
-    let mut cursor = sales_collection.aggregate(pipeline, None).await.unwrap();
+    let mut cursor = match sales_collection.aggregate(pipeline, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            logger.severe(format!("Unable to query the sales: {}", e));
+            return Err(e);
+        }
+    };
     let mut batch = Vec::new();
+    let mut pending = Vec::new();
     let mut processed = Vec::new();
 
     let batch_size = conf.email.batch_size;
+    let processed_batch_size = conf.processing.batch_size.unwrap_or(500).max(1);
+    let concurrency = conf.email.concurrency.unwrap_or(8).max(1);
+    let dedup_in_run = conf.processing.dedup_in_run.unwrap_or(true);
+    let dedup = RunDedup::default();
+    let max_sends = conf.processing.max_sends_per_run.unwrap_or(usize::MAX);
+    let max_per_recipient = conf
+        .processing
+        .max_sends_per_recipient
+        .unwrap_or(usize::MAX);
+    let recipient_limiter = RecipientLimiter::new(max_per_recipient);
+    let email_tax_states = conf
+        .processing
+        .email_tax_states
+        .as_deref()
+        .unwrap_or_default();
+    let skip_tax_states = conf
+        .processing
+        .skip_tax_states
+        .as_deref()
+        .unwrap_or_default();
+
+    let progress_interval = conf.processing.progress_interval.unwrap_or(0);
+    let approximate_total = if progress_interval > 0 {
+        approximate_pending(db).await
+    } else {
+        0
+    };
+    let mut records = 0;
+    let mut sent = 0;
+    let mut failed = 0;
+    let mut skipped = 0;
+    let mut invalid_email = 0;
+    let mut queued = 0;
+    let mut progress = Progress::default();
+    let min_sample = conf.processing.failure_rate_min_sample.unwrap_or(20);
+    let dry_run = conf.processing.dry_run.unwrap_or(false);
+
     while let Some(result) = cursor.next().await {
+        if processed.len() >= processed_batch_size {
+            flush_processed(conf, db, logger, &mut processed, &mut progress).await;
+        }
+        if progress_interval > 0 && records > 0 && records % progress_interval == 0 {
+            logger.info(format!(
+                "processed {} of ~{} sales ({} sent, {} failed)",
+                records, approximate_total, sent, failed
+            ));
+        }
+        records += 1;
+
         match result {
             Ok(document) => match mongodb::bson::from_document::<SaleDoc>(document) {
                 Err(e) => {
                     logger.severe(format!("Error parsing doc in purchase: {}", e));
                 }
-                Ok(sales_doc) => {
-                    processed.push(sales_doc.tx_hash.clone());
+                Ok(mut sales_doc) => {
+                    // until it is marked processed the checkpoint can't move past it
+                    if let (Some(_), Some(id)) = (checkpoint_name, sales_doc.id) {
+                        progress.read(
+                            &sales_doc.meta_hash,
+                            Checkpoint {
+                                timestamp: sales_doc.timestamp,
+                                id,
+                            },
+                        );
+                    }
+                    if let Err(e) = canonicalize_sponsor(&mut sales_doc) {
+                        logger.warning(format!(
+                            "ignoring sponsor of sale {}: {}",
+                            sales_doc.tx_hash, e
+                        ));
+                    }
+                    if let Err(e) = canonicalize_payer(&mut sales_doc) {
+                        logger.warning(format!("payer of sale {}: {}", sales_doc.tx_hash, e));
+                    }
+                    // skipped sales send nothing, only the queued ones count
+                    if queued >= max_sends {
+                        logger.info(format!(
+                            "reached max_sends_per_run ({}), leaving remaining sales for next run",
+                            max_sends
+                        ));
+                        break;
+                    }
+                    if sales_doc.metadata.is_empty() {
+                        logger.warning(format!(
+                            "skipped sale {}: no metadata found",
+                            sales_doc.tx_hash
+                        ));
+                        continue;
+                    }
+                    match check_recipients(&mut sales_doc, &suppressed) {
+                        Ok(0) => (),
+                        Ok(dropped) => logger.info(format!(
+                            "sale {}: not emailing {} suppressed recipient(s)",
+                            sales_doc.tx_hash, dropped
+                        )),
+                        Err(error) => {
+                            let message = format!("skipped sale {}: {}", sales_doc.tx_hash, error);
+                            if error == EmailError::Suppressed {
+                                logger.info(message);
+                            } else {
+                                logger.warning(message);
+                                invalid_email += 1;
+                            }
+                            processed.push(processed_doc(
+                                &sales_doc.meta_hash,
+                                Some(error.skip_reason()),
+                            ));
+                            skipped += 1;
+                            continue;
+                        }
+                    }
+                    if let Err(e) = format_expiry(sales_doc.expiry) {
+                        logger.severe(format!("skipped sale {}: {}", sales_doc.tx_hash, e));
+                        processed.push(processed_doc(&sales_doc.meta_hash, Some("invalid_expiry")));
+                        skipped += 1;
+                        continue;
+                    }
+                    let dropped = filter_recipients(
+                        &mut sales_doc,
+                        email_tax_states,
+                        skip_tax_states,
+                        dedup_in_run.then_some(&dedup),
+                        &recipient_limiter,
+                    );
+                    for (email, reason) in &dropped {
+                        let recipient = loggable_email(conf, email);
+                        match *reason {
+                            "tax_state" => logger.info(format!(
+                                "sale {}: not emailing {}, its tax state is not enabled",
+                                sales_doc.tx_hash, recipient
+                            )),
+                            "duplicate" => logger.info(format!(
+                                "sale {}: not emailing {}, already emailed about {} in this run",
+                                sales_doc.tx_hash, recipient, sales_doc.domain
+                            )),
+                            _ => logger.warning(format!(
+                                "sale {}: not emailing {}, it reached max_sends_per_recipient ({})",
+                                sales_doc.tx_hash, recipient, max_per_recipient
+                            )),
+                        }
+                    }
+                    if sales_doc.metadata.is_empty() {
+                        // nobody left to email, recorded with the reason of the first recipient
+                        let reason = dropped.first().map(|(_, reason)| *reason);
+                        processed.push(processed_doc(&sales_doc.meta_hash, reason));
+                        skipped += 1;
+                        continue;
+                    }
+                    if !dry_run {
+                        match claims::claim(db, &sales_doc.meta_hash).await {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                logger.info(format!(
+                                    "skipped sale {}: claimed by another worker",
+                                    sales_doc.tx_hash
+                                ));
+                                continue;
+                            }
+                            Err(e) => {
+                                logger.warning(format!(
+                                    "skipped sale {}: unable to claim it: {}",
+                                    sales_doc.tx_hash, e
+                                ));
+                                continue;
+                            }
+                        }
+                    }
                     batch.push(sales_doc);
+                    queued += 1;
                     if batch.len() >= batch_size {
-                        process_batch(&conf, &logger, &batch).await;
-                        batch.clear();
+                        pending.push(std::mem::take(&mut batch));
+                    }
+                    if pending.len() >= concurrency {
+                        let results =
+                            send_batches(conf, logger, provider, std::mem::take(&mut pending))
+                                .await;
+                        sent += results.delivered.len();
+                        failed += results.failed;
+                        record_results(conf, db, logger, results, &mut processed).await;
+                        // written right away, the claims of the sales sent
+                        // expire after processing.claim_ttl_secs
+                        flush_processed(conf, db, logger, &mut processed, &mut progress).await;
+                        // The sales behind the checkpoint must be marked processed first,
+                        // failed ones stay unprocessed and hold it back
+                        save_checkpoint(conf, db, logger, &mut processed, &mut progress).await;
+
+                        if let Some(max_rate) = conf.processing.max_failure_rate {
+                            if failure_rate_exceeded(sent, failed, max_rate, min_sample) {
+                                logger.severe(format!(
+                                    "aborting run: {} of {} sends failed, above max_failure_rate {}",
+                                    failed,
+                                    sent + failed,
+                                    max_rate
+                                ));
+                                break;
+                            }
+                        }
                     }
                 }
             },
@@ -233,27 +908,690 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
 
     // Process any remaining sales not reaching batch size
     if !batch.is_empty() {
-        process_batch(&conf, &logger, &batch).await;
+        pending.push(batch);
+    }
+    if !pending.is_empty() {
+        let results = send_batches(conf, logger, provider, pending).await;
+        sent += results.delivered.len();
+        failed += results.failed;
+        record_results(conf, db, logger, results, &mut processed).await;
+        flush_processed(conf, db, logger, &mut processed, &mut progress).await;
     }
+    // the skipped sales read since the last round move it as well
+    save_checkpoint(conf, db, logger, &mut processed, &mut progress).await;
 
-    // Blacklist the processed documents
-    let processed_collection: Collection<Document> = db.collection("processed");
-    match processed_collection
-        .insert_many(
-            processed
-                .iter()
-                .map(|tx_hash| doc! { "meta_hash": tx_hash })
-                .collect::<Vec<Document>>(),
-            None,
-        )
-        .await
-    {
-        Err(e) => {
+    if progress_interval > 0 {
+        logger.info(format!(
+            "processed {} sales ({} sent, {} failed)",
+            records, sent, failed
+        ));
+    }
+
+    // A renamed collection or a broken lookup would otherwise just look like
+    // a quiet day. Selected sales are expected to be few
+    if records == 0 && only.is_none() {
+        let threshold = conf
+            .processing
+            .empty_run_alert_threshold
+            .unwrap_or(DEFAULT_EMPTY_RUN_ALERT_THRESHOLD);
+        let pending = approximate_pending(db).await;
+        if empty_run_is_suspicious(records, pending, threshold) {
             logger.severe(format!(
-                "Error inserting into 'processed' collection: {}",
-                e
+                "the sales query returned nothing while ~{} sales look unprocessed, \
+                 check the collection names and the metadata lookup",
+                pending
             ));
         }
-        _ => {}
+    }
+
+    let summary = ProcessingSummary {
+        read: records,
+        sent,
+        failed,
+        skipped,
+        invalid_email,
+    };
+
+    // Blacklist the remaining processed documents
+    flush_processed(conf, db, logger, &mut processed, &mut progress).await;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod purchases_tests {
+    use super::{
+        attempts_exhausted, canonicalize_payer, canonicalize_sponsor, check_recipients,
+        empty_run_is_suspicious, filter_recipients, format_expiry, sale_emails, send_batches,
+        SaleDoc,
+    };
+    use crate::processing::provider::EmailProvider;
+    use crate::{
+        config::test_config,
+        logger::Logger,
+        processing::{
+            mailer::EmailError, provider::RestProvider, suppression, transport::MockTransport,
+            MetadataDoc, RecipientLimiter, RunDedup,
+        },
+    };
+    use mongodb::bson::{doc, Bson, Decimal128, Document};
+    use std::collections::HashSet;
+
+    fn sale_document(price: Bson, timestamp: Bson) -> Document {
+        doc! {
+            "tx_hash": "0x1",
+            "meta_hash": "abc",
+            "domain": "example.stark",
+            "price": price,
+            "payer": "0x2",
+            "timestamp": timestamp,
+            "expiry": 1_735_689_600_i64,
+            "metadata": [],
+            "same_tx_groups": [],
+        }
+    }
+
+    #[test]
+    fn test_sale_doc_price_as_integer() {
+        let sale: SaleDoc = mongodb::bson::from_document(sale_document(
+            Bson::Int32(12),
+            Bson::Int32(1_700_000_000),
+        ))
+        .unwrap();
+        assert_eq!(sale.price, 12.0);
+        assert_eq!(sale.timestamp, 1_700_000_000);
+        assert_eq!(sale.expiry, 1_735_689_600);
+    }
+
+    #[test]
+    fn test_sale_doc_price_as_decimal128() {
+        // 125 * 10^-2
+        let bits = ((6176u128 - 2) << 113) | 125;
+        let price = Bson::Decimal128(Decimal128::from_bytes(bits.to_le_bytes()));
+        let sale: SaleDoc =
+            mongodb::bson::from_document(sale_document(price, Bson::Double(1_700_000_000.0)))
+                .unwrap();
+        assert!((sale.price - 1.25).abs() < 1e-12);
+        assert_eq!(sale.timestamp, 1_700_000_000);
+    }
+
+    #[test]
+    fn test_sale_doc_rejects_fractional_timestamp() {
+        let document = sale_document(Bson::Double(1.0), Bson::Double(1.5));
+        assert!(mongodb::bson::from_document::<SaleDoc>(document).is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_sponsor() {
+        let mut document = sale_document(Bson::Int32(1), Bson::Int32(1_700_000_000));
+        document.insert("sponsor", "0x00AB");
+        let mut sale: SaleDoc = mongodb::bson::from_document(document).unwrap();
+        assert!(canonicalize_sponsor(&mut sale).is_ok());
+        assert_eq!(sale.sponsor.as_deref(), Some("0xab"));
+    }
+
+    #[test]
+    fn test_canonicalize_malformed_sponsor() {
+        let mut document = sale_document(Bson::Int32(1), Bson::Int32(1_700_000_000));
+        document.insert("sponsor", "0xnot_an_address");
+        let mut sale: SaleDoc = mongodb::bson::from_document(document).unwrap();
+        assert!(canonicalize_sponsor(&mut sale).is_err());
+        assert_eq!(sale.sponsor, None);
+    }
+
+    #[test]
+    fn test_canonicalize_payer_above_prime() {
+        let mut document = sale_document(Bson::Int32(1), Bson::Int32(1_700_000_000));
+        document.insert("sponsor", format!("0x{}", "f".repeat(63)));
+        document.insert(
+            "payer",
+            "0x0800000000000011000000000000000000000000000000000000000000000001",
+        );
+        let mut sale: SaleDoc = mongodb::bson::from_document(document).unwrap();
+        assert!(canonicalize_sponsor(&mut sale)
+            .unwrap_err()
+            .contains("field prime"));
+        assert_eq!(sale.sponsor, None);
+        assert!(canonicalize_payer(&mut sale)
+            .unwrap_err()
+            .contains("field prime"));
+        assert_eq!(
+            sale.payer,
+            "0x0800000000000011000000000000000000000000000000000000000000000001"
+        );
+
+        sale.payer = "0x0002".to_string();
+        assert!(canonicalize_payer(&mut sale).is_ok());
+        assert_eq!(sale.payer, "0x02");
+    }
+
+    #[test]
+    fn test_sale_emails_without_metadata() {
+        let conf = test_config();
+        let sale: SaleDoc =
+            mongodb::bson::from_document(sale_document(Bson::Int32(1), Bson::Int32(1_700_000_000)))
+                .unwrap();
+        assert!(sale.metadata.is_empty());
+        assert!(sale_emails(&sale, &conf).is_empty());
+    }
+
+    fn sale(meta_hash: &str) -> SaleDoc {
+        let mut document = sale_document(Bson::Int32(1), Bson::Int32(1_700_000_000));
+        document.insert("meta_hash", meta_hash);
+        document.insert(
+            "metadata",
+            vec![doc! {
+                "meta_hash": meta_hash,
+                "email": "alice@example.com",
+                "tax_state": "FR",
+                "salt": "0x1",
+            }],
+        );
+        mongodb::bson::from_document(document).unwrap()
+    }
+
+    fn add_recipient(sale: &mut SaleDoc, email: &str, tax_state: &str) {
+        sale.metadata.push(MetadataDoc {
+            meta_hash: sale.meta_hash.clone(),
+            email: email.to_string(),
+            tax_state: tax_state.to_string(),
+            salt: "0x1".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_filter_recipients_per_recipient() {
+        let dedup = RunDedup::default();
+        let limiter = RecipientLimiter::new(1);
+        let denied = vec!["US".to_string()];
+
+        // alice is already emailed about the domain, bob is in a skipped state
+        let mut first = sale("a");
+        assert!(filter_recipients(&mut first, &[], &denied, Some(&dedup), &limiter).is_empty());
+        let mut second = sale("b");
+        add_recipient(&mut second, "bob@example.com", "US");
+        add_recipient(&mut second, "carol@example.com", "FR");
+        let dropped = filter_recipients(&mut second, &[], &denied, Some(&dedup), &limiter);
+        assert_eq!(
+            dropped,
+            vec![
+                ("alice@example.com".to_string(), "duplicate"),
+                ("bob@example.com".to_string(), "tax_state"),
+            ]
+        );
+        let emails: Vec<&str> = second.metadata.iter().map(|m| m.email.as_str()).collect();
+        assert_eq!(emails, vec!["carol@example.com"]);
+
+        // under a limit of one send carol is refused on another domain
+        let mut third = sale("c");
+        third.domain = "other.stark".to_string();
+        third.metadata.clear();
+        add_recipient(&mut third, "carol@example.com", "FR");
+        assert_eq!(
+            filter_recipients(&mut third, &[], &denied, None, &limiter),
+            vec![("carol@example.com".to_string(), "recipient_limit")]
+        );
+        assert!(third.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_filter_recipients_lowercased() {
+        let conf = test_config();
+        let dedup = RunDedup::default();
+        let limiter = RecipientLimiter::new(1);
+
+        // the two casings of alice are a single recipient, counted once
+        let mut first = sale("a");
+        first.metadata.clear();
+        add_recipient(&mut first, "Alice@Example.com", "FR");
+        add_recipient(&mut first, " alice@example.com ", "FR");
+        assert!(filter_recipients(&mut first, &[], &[], Some(&dedup), &limiter).is_empty());
+        let emails = sale_emails(&first, &conf);
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].0, "alice@example.com");
+
+        let mut second = sale("b");
+        second.metadata.clear();
+        add_recipient(&mut second, "ALICE@example.com", "FR");
+        assert_eq!(
+            filter_recipients(&mut second, &[], &[], Some(&dedup), &limiter),
+            vec![("alice@example.com".to_string(), "duplicate")]
+        );
+        second.domain = "other.stark".to_string();
+        add_recipient(&mut second, "ALICE@example.com", "FR");
+        assert_eq!(
+            filter_recipients(&mut second, &[], &[], Some(&dedup), &limiter),
+            vec![("alice@example.com".to_string(), "recipient_limit")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_batches_keeps_accepted_sales() {
+        let mut conf = test_config();
+        conf.email.concurrency = Some(2);
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport
+            .respond(200, &[], "{}")
+            .respond(422, &[], "invalid");
+
+        let batches = vec![vec![sale("a")], vec![sale("b"), sale("c")]];
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let results = send_batches(&conf, &logger, &provider, batches).await;
+
+        assert_eq!(results.delivered, vec!["a".to_string()]);
+        assert_eq!(results.failed, 2);
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_batches_once_per_address() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let mut sale = sale("abc");
+        for email in [
+            "ALICE@example.com",
+            " Alice@Example.Com ",
+            "bob@example.com",
+        ] {
+            sale.metadata.push(MetadataDoc {
+                meta_hash: "abc".to_string(),
+                email: email.to_string(),
+                tax_state: "FR".to_string(),
+                salt: "0x1".to_string(),
+            });
+        }
+
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let results = send_batches(&conf, &logger, &provider, vec![vec![sale]]).await;
+        assert_eq!(results.delivered, vec!["abc".to_string()]);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        let body = requests[0].body.as_ref().unwrap();
+        let paths: Vec<&str> = body["requests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|request| request["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].contains("?email=alice%40example.com&"));
+        assert!(paths[1].contains("?email=bob%40example.com&"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_is_not_processed() {
+        let mut conf = test_config();
+        conf.retry.max_attempts = Some(1);
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(500, &[], "internal error");
+
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let results = send_batches(&conf, &logger, &provider, vec![vec![sale("abc")]]).await;
+
+        assert!(!results.delivered.contains(&"abc".to_string()));
+        assert_eq!(results.failed, 1);
+        // a 5xx is transient, the sale is retried next run
+        assert!(results.rejected.is_empty());
+        assert!(results.dead_letters.is_empty());
+        assert_eq!(results.retrying.len(), 1);
+        assert_eq!(results.retrying[0].0.meta_hash, "abc");
+        assert_eq!(results.released, vec!["abc".to_string()]);
+    }
+
+    #[test]
+    fn test_attempts_exhausted() {
+        assert!(!attempts_exhausted(100, None));
+        assert!(!attempts_exhausted(2, Some(3)));
+        assert!(attempts_exhausted(3, Some(3)));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_batch_is_dead_lettered() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(422, &[], "invalid subscriber");
+
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let results = send_batches(&conf, &logger, &provider, vec![vec![sale("abc")]]).await;
+
+        assert_eq!(results.rejected, vec![("abc".to_string(), "rejected")]);
+        assert_eq!(results.dead_letters.len(), 1);
+        let letter = &results.dead_letters[0];
+        assert_eq!(letter.get_str("meta_hash"), Ok("abc"));
+        assert_eq!(letter.get_str("recipient"), Ok("alice@example.com"));
+        assert_eq!(letter.get_i32("status"), Ok(422));
+        assert_eq!(letter.get_str("body"), Ok("invalid subscriber"));
+    }
+
+    #[test]
+    fn test_sale_emails_one_per_valid_recipient() {
+        let conf = test_config();
+        let mut document = sale_document(Bson::Int32(1), Bson::Int32(1_700_000_000));
+        let entry = |email: &str| {
+            doc! { "meta_hash": "abc", "email": email, "tax_state": "FR", "salt": "0x1" }
+        };
+        document.insert(
+            "metadata",
+            vec![
+                entry("not an email"),
+                entry("alice@example.com"),
+                entry("bob@example.com"),
+                entry(" Alice@Example.com "),
+            ],
+        );
+        let sale: SaleDoc = mongodb::bson::from_document(document).unwrap();
+
+        let emails = sale_emails(&sale, &conf);
+        let recipients: Vec<&str> = emails.iter().map(|(email, _)| email.as_str()).collect();
+        assert_eq!(recipients, vec!["alice@example.com", "bob@example.com"]);
+        assert_eq!(
+            emails[0].1.fields,
+            vec![
+                ("name".to_string(), "example.stark".to_string()),
+                ("expiry".to_string(), "2025-01-01 00:00:00".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sale_emails_extra_fields() {
+        let mut conf = test_config();
+        conf.email.extra_fields = Some(
+            [("profile_url", "https://app.starknet.id/{domain}")]
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        );
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let emails = sale_emails(&sale("abc"), &conf);
+        let (recipient, fields) = &emails[0];
+        assert_eq!(fields.fields[0].0, "name");
+        assert_eq!(fields.fields[1].0, "expiry");
+        assert_eq!(
+            fields.fields[2],
+            (
+                "profile_url".to_string(),
+                format!("https://app.starknet.id/{}", fields.fields[0].1)
+            )
+        );
+        let url = provider.describe(recipient, fields);
+        assert!(url.contains("&fields[profile_url]=https%3A%2F%2Fapp.starknet.id%2F"));
+    }
+
+    #[test]
+    fn test_sale_emails_dedup_groups() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let mut sale = sale("abc");
+        sale.same_tx_groups = ["2", "1", "2", "3", "1"].map(String::from).to_vec();
+
+        let emails = sale_emails(&sale, &conf);
+        let (recipient, fields) = &emails[0];
+        assert_eq!(fields.groups, vec!["2", "1", "3"]);
+        let url = provider.describe(recipient, fields);
+        for group in ["1", "2", "3"] {
+            assert_eq!(url.matches(&format!("groups[]={}", group)).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_empty_run_is_suspicious() {
+        assert!(empty_run_is_suspicious(0, 100, 100));
+        assert!(empty_run_is_suspicious(0, 5_000, 100));
+        // caught up, or a few sales without their metadata yet
+        assert!(!empty_run_is_suspicious(0, 0, 100));
+        assert!(!empty_run_is_suspicious(0, 99, 100));
+        assert!(!empty_run_is_suspicious(3, 5_000, 100));
+    }
+
+    #[test]
+    fn test_format_expiry() {
+        assert_eq!(
+            format_expiry(1_735_689_600),
+            Ok("2025-01-01 00:00:00".to_string())
+        );
+        assert!(format_expiry(0).is_err());
+        assert!(format_expiry(-1).is_err());
+        assert!(format_expiry(4_102_444_800).is_err());
+        assert!(format_expiry(i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_sale_emails_invalid_expiry() {
+        let conf = test_config();
+        let mut sale = sale("abc");
+        for expiry in [0, -1, i64::MAX] {
+            sale.expiry = expiry;
+            assert!(sale_emails(&sale, &conf).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_suppressed_recipients_are_not_emailed() {
+        let conf = test_config();
+        let mut sale = sale("abc");
+        sale.metadata.push(
+            mongodb::bson::from_document(doc! {
+                "meta_hash": "abc",
+                "email": "bob@example.com",
+                "tax_state": "FR",
+                "salt": "0x1",
+            })
+            .unwrap(),
+        );
+        let suppressed: HashSet<String> = ["alice@example.com".to_string()].into();
+
+        assert_eq!(suppression::drop_suppressed(&mut sale, &suppressed), 1);
+        let emails = sale_emails(&sale, &conf);
+        let recipients: Vec<&str> = emails.iter().map(|(email, _)| email.as_str()).collect();
+        assert_eq!(recipients, vec!["bob@example.com"]);
+
+        // once every recipient is suppressed the sale has nothing to send
+        let suppressed: HashSet<String> = ["bob@example.com".to_string()].into();
+        assert_eq!(suppression::drop_suppressed(&mut sale, &suppressed), 1);
+        assert!(sale_emails(&sale, &conf).is_empty());
+    }
+
+    #[test]
+    fn test_check_recipients() {
+        let none = HashSet::new();
+        let mut valid = sale("abc");
+        assert_eq!(check_recipients(&mut valid, &none), Ok(0));
+
+        let suppressed: HashSet<String> = ["alice@example.com".to_string()].into();
+        assert_eq!(
+            check_recipients(&mut sale("abc"), &suppressed),
+            Err(EmailError::Suppressed)
+        );
+
+        let mut invalid = sale("abc");
+        invalid.metadata[0].email = "not an email".to_string();
+        let error = check_recipients(&mut invalid, &none).unwrap_err();
+        assert_eq!(error.skip_reason(), "invalid_email");
+    }
+}
+
+// End to end runs of the purchases pipeline, the provider is mocked but the
+// sales are read from a real MongoDB. Run with MONGODB_TEST_URI set and
+// `cargo test -- --ignored`, every test works in a database of its own
+#[cfg(test)]
+mod pipeline_tests {
+    use super::process_data;
+    use crate::{
+        config::test_config,
+        logger::Logger,
+        processing::{mailer::BATCH_URL, provider::RestProvider, transport::MockTransport},
+    };
+    use futures::stream::TryStreamExt;
+    use mongodb::{
+        bson::{doc, oid::ObjectId, Document},
+        Client, Database,
+    };
+    use reqwest::Method;
+
+    async fn test_database() -> Database {
+        let uri = std::env::var("MONGODB_TEST_URI")
+            .unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        Client::with_uri_str(uri)
+            .await
+            .unwrap()
+            .database(&format!("sale_actions_test_{}", ObjectId::new().to_hex()))
+    }
+
+    async fn insert_sale(db: &Database, meta_hash: &str, email: &str) {
+        db.collection::<Document>("sales")
+            .insert_one(
+                doc! {
+                    "tx_hash": format!("0x{}", meta_hash),
+                    "meta_hash": meta_hash,
+                    "domain": "example.stark",
+                    "price": 1.0,
+                    "payer": "0x2",
+                    "timestamp": 1_700_000_000_i64,
+                    "expiry": 1_735_689_600_i64,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        db.collection::<Document>("metadata")
+            .insert_one(
+                doc! {
+                    "meta_hash": meta_hash,
+                    "email": email,
+                    "tax_state": "FR",
+                    "salt": "0x1",
+                },
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn processed(db: &Database) -> Vec<Document> {
+        db.collection::<Document>("processed")
+            .find(None, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB, see MONGODB_TEST_URI"]
+    async fn test_valid_sale_is_sent_once() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let db = test_database().await;
+        insert_sale(&db, "abc", "alice@example.com").await;
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let summary = process_data(&conf, &db, &logger, &provider).await.unwrap();
+        assert_eq!((summary.read, summary.sent, summary.failed), (1, 1, 0));
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::POST);
+        assert_eq!(requests[0].url, BATCH_URL);
+        let body = requests[0].body.as_ref().unwrap();
+        let path = body["requests"][0]["path"].as_str().unwrap();
+        assert_eq!(
+            path,
+            format!(
+                "{}/subscribers?email=alice%40example.com&fields[name]=example.stark\
+                 &fields[expiry]=2025-01-01%2000%3A00%3A00",
+                conf.email.base_url
+            )
+        );
+
+        let processed = processed(&db).await;
+        assert_eq!(processed.len(), 1);
+        assert_eq!(processed[0].get_str("meta_hash"), Ok("abc"));
+        assert_eq!(processed[0].get_str("outcome"), Ok("sent"));
+
+        // marked processed, the next run has nothing to send
+        let summary = process_data(&conf, &db, &logger, &provider).await.unwrap();
+        assert_eq!(summary.read, 0);
+        assert_eq!(transport.requests().len(), 1);
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB, see MONGODB_TEST_URI"]
+    async fn test_invalid_email_is_skipped() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let db = test_database().await;
+        insert_sale(&db, "abc", "not an email").await;
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let summary = process_data(&conf, &db, &logger, &provider).await.unwrap();
+        assert_eq!(
+            (summary.sent, summary.skipped, summary.invalid_email),
+            (0, 1, 1)
+        );
+        assert!(transport.requests().is_empty());
+
+        let processed = processed(&db).await;
+        assert_eq!(processed.len(), 1);
+        assert_eq!(processed[0].get_str("outcome"), Ok("skipped"));
+        assert_eq!(processed[0].get_str("reason"), Ok("invalid_email"));
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB, see MONGODB_TEST_URI"]
+    async fn test_skipped_sales_do_not_count_against_max_sends() {
+        let mut conf = test_config();
+        conf.processing.max_sends_per_run = Some(1);
+        let logger = Logger::new(&conf.watchtower);
+        let db = test_database().await;
+        insert_sale(&db, "abc", "not an email").await;
+        insert_sale(&db, "def", "alice@example.com").await;
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let summary = process_data(&conf, &db, &logger, &provider).await.unwrap();
+        assert_eq!((summary.sent, summary.skipped), (1, 1));
+        assert_eq!(processed(&db).await.len(), 2);
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB, see MONGODB_TEST_URI"]
+    async fn test_rejected_sale_is_dead_lettered() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let db = test_database().await;
+        insert_sale(&db, "abc", "alice@example.com").await;
+        let transport = MockTransport::default();
+        transport.respond(422, &[], "invalid subscriber");
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let summary = process_data(&conf, &db, &logger, &provider).await.unwrap();
+        assert_eq!((summary.sent, summary.failed), (0, 1));
+
+        let processed = processed(&db).await;
+        assert_eq!(processed.len(), 1);
+        assert_eq!(processed[0].get_str("reason"), Ok("rejected"));
+        let failed = db
+            .collection::<Document>("failed_emails")
+            .count_documents(doc! { "meta_hash": "abc" }, None)
+            .await
+            .unwrap();
+        assert_eq!(failed, 1);
+        db.drop(None).await.unwrap();
     }
 }
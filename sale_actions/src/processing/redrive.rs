@@ -0,0 +1,141 @@
+use super::{provider::EmailProvider, purchases, renewal::RENEWAL_DEAD_LETTER};
+use crate::{config::Config, logger::Logger};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Database,
+};
+use serde_derive::{Deserialize, Serialize};
+
+const FAILED_COLLECTION: &str = "failed_emails";
+
+// Reasons of the processed entries written for dead-lettered sales
+const DEAD_LETTER_REASONS: [&str; 2] = ["rejected", "max_attempts"];
+
+#[derive(Deserialize, Default)]
+pub struct RedriveRequest {
+    // every dead-lettered sale when missing
+    pub meta_hashes: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct RedriveSummary {
+    pub redriven: usize,
+    pub succeeded: usize,
+    pub still_failing: usize,
+}
+
+// Dead-lettered sales, the toggles parked by the renewal pipeline share their
+// meta_hash but are not emails the purchases pipeline sends
+fn dead_letter_filter(meta_hashes: Option<&[String]>) -> Document {
+    let mut filter = doc! { "kind": { "$ne": RENEWAL_DEAD_LETTER } };
+    if let Some(meta_hashes) = meta_hashes {
+        filter.insert("meta_hash", doc! { "$in": meta_hashes.to_vec() });
+    }
+    filter
+}
+
+pub fn strings(values: Vec<Bson>) -> Vec<String> {
+    values
+        .into_iter()
+        .filter_map(|value| match value {
+            Bson::String(value) => Some(value),
+            _ => None,
+        })
+        .collect()
+}
+
+// Sends the dead-lettered sales again once the provider recovered: their
+// processed entries are dropped so the purchases pipeline picks them up as
+// usual, and the failed_emails entries of the ones delivered are removed
+pub async fn redrive(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    request: RedriveRequest,
+) -> Result<RedriveSummary, mongodb::error::Error> {
+    let failed = db.collection::<Document>(FAILED_COLLECTION);
+    let filter = dead_letter_filter(request.meta_hashes.as_deref());
+    let targets = strings(failed.distinct("meta_hash", filter, None).await?);
+    if targets.is_empty() {
+        return Ok(RedriveSummary::default());
+    }
+    if conf.processing.dry_run.unwrap_or(false) {
+        logger.info(format!("dry run: would redrive {} sales", targets.len()));
+        return Ok(RedriveSummary {
+            redriven: targets.len(),
+            succeeded: 0,
+            still_failing: targets.len(),
+        });
+    }
+
+    let processed = db.collection::<Document>("processed");
+    processed
+        .delete_many(
+            doc! {
+                "meta_hash": { "$in": targets.clone() },
+                "outcome": "skipped",
+                "reason": { "$in": DEAD_LETTER_REASONS.to_vec() },
+            },
+            None,
+        )
+        .await?;
+    db.collection::<Document>("send_attempts")
+        .delete_many(doc! { "meta_hash": { "$in": targets.clone() } }, None)
+        .await?;
+
+    // a redrive must not move the backfill checkpoint
+    let mut conf = conf.clone();
+    conf.processing.checkpoint = None;
+    purchases::process_selected(&conf, db, logger, provider, &targets).await?;
+
+    let delivered = strings(
+        processed
+            .distinct(
+                "meta_hash",
+                doc! { "meta_hash": { "$in": targets.clone() }, "outcome": "sent" },
+                None,
+            )
+            .await?,
+    );
+    if !delivered.is_empty() {
+        failed
+            .delete_many(dead_letter_filter(Some(&delivered)), None)
+            .await?;
+    }
+    logger.info(format!(
+        "redrive: {} of {} dead-lettered sales delivered",
+        delivered.len(),
+        targets.len()
+    ));
+    Ok(RedriveSummary {
+        redriven: targets.len(),
+        succeeded: delivered.len(),
+        still_failing: targets.len() - delivered.len(),
+    })
+}
+
+#[cfg(test)]
+mod redrive_tests {
+    use super::{dead_letter_filter, strings};
+    use mongodb::bson::{doc, Bson};
+
+    #[test]
+    fn test_dead_letter_filter() {
+        assert_eq!(
+            dead_letter_filter(None),
+            doc! { "kind": { "$ne": "renewal" } }
+        );
+        let meta_hashes = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(
+            dead_letter_filter(Some(&meta_hashes)),
+            doc! { "kind": { "$ne": "renewal" }, "meta_hash": { "$in": ["a", "b"] } }
+        );
+    }
+
+    #[test]
+    fn test_strings_skips_other_types() {
+        let values = vec![Bson::String("a".to_string()), Bson::Null, Bson::Int32(1)];
+        assert_eq!(strings(values), vec!["a".to_string()]);
+    }
+}
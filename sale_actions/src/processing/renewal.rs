@@ -1,17 +1,32 @@
-use super::MetadataDoc;
-use crate::{config::Config, logger::Logger};
-use email_address::EmailAddress;
+use super::{
+    claims, dedup_groups, extra_fields, loggable_email,
+    mailer::{retry_after, EmailError},
+    provider::{EmailFields, EmailKind, EmailProvider},
+    purchases::{attempts_exhausted, failure_details, ATTEMPTS_COLLECTION},
+    recipients, suppression,
+    transport::{EmailTransport, TransportRequest},
+    unsubscribe, MetadataDoc,
+};
+use crate::{
+    config::Config,
+    logger::Logger,
+    utils::{
+        canonical_uint256, format_token_amount, normalize_domain, readable_domain, ParseError,
+    },
+};
 use futures::stream::StreamExt;
 use mongodb::{
-    bson::{doc, Document},
+    bson::{doc, DateTime, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
     Collection, Database,
 };
-use reqwest::{header, Client};
+use reqwest::{Method, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ReenewalToggledDoc {
+    pub meta_hash: String,
     pub tx_hash: String,
     pub domain: String,
     pub renewer: String,
@@ -58,63 +73,261 @@ fn create_disable_request(data: &Data, base_url: &str, ar_group_id: &str) -> Val
     })
 }
 
-// Function to create requests for enabling auto-renewal
-fn create_enable_request(sale: &ReenewalToggledDoc, base_url: &str) -> Value {
-    let groups_params: Vec<String> = sale
-        .same_tx_groups
-        .iter()
-        .map(|group| format!("groups[]={}", group))
-        .collect();
+// Rewrites the allowance in its decimal form, the indexer stores the uint256
+// in decimal but a hex value is understood too. Toggles whose allowance is not
+// an amount are not emailed
+fn canonicalize_allowance(renewal: &mut ReenewalToggledDoc) -> Result<(), ParseError> {
+    renewal.allowance = canonical_uint256(&renewal.allowance)?;
+    Ok(())
+}
 
-    let url = format!(
-        "{base_url}/subscribers?email={email}&fields[name]={domain}&fields[renewer]={renewer}&{groups}",
-        base_url = base_url,
-        email = &sale.metadata[0].email,
-        domain = &sale.domain,
-        renewer = &sale.renewer,
-        groups = groups_params.join("&")
+// Allowance as shown in the email, None when it is missing or not a valid
+// raw token amount
+fn format_allowance(allowance: &str, conf: &Config) -> Option<String> {
+    format_token_amount(
+        allowance,
+        conf.email.allowance_decimals.unwrap_or(18),
+        conf.email.allowance_symbol.as_deref().unwrap_or("ETH"),
+    )
+    .ok()
+}
+
+// Content of the email of a toggle enabling auto renewal, the same for each
+// recipient
+fn renewal_fields(sale: &ReenewalToggledDoc, conf: &Config) -> EmailFields {
+    let domain = normalize_domain(
+        &readable_domain(&sale.domain),
+        conf.email.domain_suffix.as_deref(),
+        conf.email.strip_domain_suffix.unwrap_or(false),
+    );
+    let extra = extra_fields(
+        conf,
+        &[
+            ("domain", domain.as_str()),
+            ("renewer", sale.renewer.as_str()),
+            ("tx_hash", sale.tx_hash.as_str()),
+        ],
     );
 
-    json!({
-        "method": "POST",
-        "path": &url
-    })
+    let mut fields = vec![
+        ("name".to_string(), domain),
+        ("renewer".to_string(), sale.renewer.clone()),
+    ];
+    if let Some(allowance) = format_allowance(&sale.allowance, conf) {
+        fields.push(("allowance".to_string(), allowance));
+    }
+    fields.extend(extra);
+    EmailFields {
+        fields,
+        groups: dedup_groups(&sale.same_tx_groups),
+        kind: EmailKind::Renewal,
+        meta_hash: sale.meta_hash.clone(),
+    }
 }
 
-// Function to process batch requests
-async fn process_batch_requests(conf: &Config, logger: &Logger, requests: &[Value]) {
-    let batch_request = json!({
-        "requests": requests
-    });
-
-    let client = Client::new();
-    match client
-        .post("https://api.mailerlite.com/api/v2/batch")
-        .header("X-MailerLite-ApiKey", &conf.email.api_key)
-        .header(header::CONTENT_TYPE, "application/json")
-        .json(&batch_request)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            if !res.status().is_success() {
+// Provider endpoint of a single subscriber, the email is percent-encoded as a
+// path segment
+pub fn subscriber_url(base_url: &str, email: &str) -> String {
+    format!("{}/subscribers/{}", base_url, urlencoding::encode(email))
+}
+
+// Lookup disable_request starts with, as logged by dry runs: the same url with
+// the email masked
+fn describe_disable(conf: &Config, email: &str) -> String {
+    format!(
+        "GET {}",
+        subscriber_url(&conf.email.base_url, &loggable_email(conf, email))
+    )
+}
+
+// Disabling needs the current groups of the subscriber, an error when they
+// could not be fetched
+pub async fn disable_request(
+    conf: &Config,
+    transport: &dyn EmailTransport,
+    email: &str,
+) -> Result<Value, EmailError> {
+    let request = TransportRequest::new(Method::GET, subscriber_url(&conf.email.base_url, email))
+        .header("X-MailerLite-ApiKey", &conf.email.api_key);
+
+    let res = transport.send(request).await.map_err(EmailError::Network)?;
+    if res.status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(EmailError::RateLimited(retry_after(&res)));
+    }
+    if !res.status.is_success() {
+        return Err(EmailError::Status(res.status, res.body));
+    }
+    let api_response = serde_json::from_str::<ApiResponse>(&res.body).map_err(|e| {
+        EmailError::Permanent(format!(
+            "unable to parse the groups of the subscriber: {}",
+            e
+        ))
+    })?;
+    Ok(create_disable_request(
+        &api_response.data,
+        &conf.email.base_url,
+        &conf.email.ar_group_id,
+    ))
+}
+
+// Toggles share the claims of the sales, prefixed so a tx_hash can never
+// hold the claim of a meta_hash
+fn claim_key(tx_hash: &str) -> String {
+    format!("renewal:{}", tx_hash)
+}
+
+// Kind of the failed_emails entries of the toggles, the re-drives only send
+// the purchase emails again and leave them out
+pub const RENEWAL_DEAD_LETTER: &str = "renewal";
+
+// A toggle handed to the provider along with the addresses it is emailed to
+struct Toggle {
+    tx_hash: String,
+    meta_hash: String,
+    recipients: Vec<String>,
+}
+
+// failed_emails entries for a toggle the provider rejected, one per recipient
+fn dead_letters(toggle: &Toggle, error: &EmailError) -> Vec<Document> {
+    let (status, body) = failure_details(error);
+    let timestamp = DateTime::now();
+    toggle
+        .recipients
+        .iter()
+        .map(|recipient| {
+            doc! {
+                "meta_hash": &toggle.meta_hash,
+                "tx_hash": &toggle.tx_hash,
+                "kind": RENEWAL_DEAD_LETTER,
+                "recipient": recipient,
+                "status": status,
+                "body": &body,
+                "timestamp": timestamp,
+            }
+        })
+        .collect()
+}
+
+// Emails waiting to be sent along with the toggles they cover
+#[derive(Default)]
+struct RenewalBatch {
+    emails: Vec<(String, EmailFields)>,
+    toggles: Vec<Toggle>,
+    // toggles that failed with a transient error, their claim is released
+    unsent: Vec<String>,
+    // those of them the provider was called for, along with the error
+    retrying: Vec<(Toggle, EmailError)>,
+    dead_letters: Vec<Document>,
+}
+
+impl RenewalBatch {
+    // Records a toggle the provider failed on, true when it is given up on:
+    // a non retryable error parks it in failed_emails and it is marked
+    // processed, the others leave it for the next run
+    fn failed(&mut self, toggle: Toggle, error: &EmailError) -> bool {
+        if !error.is_retryable() {
+            if error.skip_reason() == "rejected" {
+                self.dead_letters.extend(dead_letters(&toggle, error));
+            }
+            return true;
+        }
+        self.unsent.push(toggle.tx_hash.clone());
+        // skipped by the circuit breaker, the send was not attempted
+        if *error != EmailError::CircuitOpen {
+            self.retrying.push((toggle, error.clone()));
+        }
+        false
+    }
+
+    // tx_hash of the toggles to mark processed: the ones sent, or the ones
+    // given up on when the provider refused the batch
+    async fn flush(&mut self, logger: &Logger, provider: &dyn EmailProvider) -> Vec<String> {
+        let toggles = std::mem::take(&mut self.toggles);
+        if self.emails.is_empty() {
+            return toggles.into_iter().map(|toggle| toggle.tx_hash).collect();
+        }
+        let sent = provider.send_many(&self.emails).await;
+        self.emails.clear();
+        match sent {
+            Ok(()) => toggles.into_iter().map(|toggle| toggle.tx_hash).collect(),
+            Err(e) => {
                 logger.severe(format!(
-                    "Received non-success status from batch request: {}. Response body: {}",
-                    res.status(),
-                    res.text()
-                        .await
-                        .unwrap_or_else(|_| "Failed to retrieve response body".to_string())
+                    "{} renewal toggles not sent, {}: {}",
+                    toggles.len(),
+                    if e.is_retryable() {
+                        "they will be retried"
+                    } else {
+                        "they are given up on"
+                    },
+                    e
                 ));
+                let mut given_up = Vec::new();
+                for toggle in toggles {
+                    let tx_hash = toggle.tx_hash.clone();
+                    if self.failed(toggle, &e) {
+                        given_up.push(tx_hash);
+                    }
+                }
+                given_up
             }
         }
-        Err(e) => {
-            logger.severe(format!("Failed to send batch request: {}", e));
+    }
+}
+
+// Counts the runs each toggle failed in within send_attempts, keyed by its
+// tx_hash. Once processing.max_attempts is reached it is parked in
+// failed_emails and marked processed like the sales are
+async fn record_attempts(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    batch: &mut RenewalBatch,
+    processed: &mut Vec<String>,
+) {
+    let attempts_collection = db.collection::<Document>(ATTEMPTS_COLLECTION);
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    for (toggle, error) in std::mem::take(&mut batch.retrying) {
+        let update = doc! {
+            "$inc": { "attempts": 1 },
+            "$set": { "last_error": error.to_string(), "last_attempt_at": DateTime::now() },
+        };
+        let attempts = match attempts_collection
+            .find_one_and_update(doc! { "tx_hash": &toggle.tx_hash }, update, options.clone())
+            .await
+        {
+            Ok(tracking) => tracking
+                .and_then(|tracking| tracking.get_i32("attempts").ok())
+                .unwrap_or(1),
+            Err(e) => {
+                logger.severe(format!(
+                    "Error updating the '{}' collection: {}",
+                    ATTEMPTS_COLLECTION, e
+                ));
+                continue;
+            }
+        };
+        if attempts_exhausted(attempts, conf.processing.max_attempts) {
+            logger.warning(format!(
+                "giving up on renewal {} after {} failed runs: {}",
+                toggle.tx_hash, attempts, error
+            ));
+            batch.dead_letters.extend(dead_letters(&toggle, &error));
+            processed.push(toggle.tx_hash);
         }
     }
 }
 
-// Adjusted process_data to collect renewals and process in batch
-pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
+// Adjusted process_data to collect renewals and process in batch, returns how
+// many toggles had no valid email
+pub async fn process_data(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+) -> Result<usize, mongodb::error::Error> {
     let pipeline: Vec<Document> = vec![
         doc! {
             "$match": {
@@ -133,7 +346,7 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
                         }
                     },
                     doc! {
-                        "$project": { "_id": 0, "meta_hash": 1 }
+                        "$project": { "_id": 0, "meta_hash": 1, "email": 1, "tax_state": 1, "salt": 1 }
                     }
                 ],
                 "as": "metadata"
@@ -187,6 +400,10 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
             "$project": {
                 "meta_hash": 1,
                 "tx_hash": 1,
+                "domain": 1,
+                "renewer": 1,
+                "allowance": 1,
+                "metadata": 1,
                 "same_tx_groups": {
                     "$map": {
                         "input": "$same_tx_groups",
@@ -198,12 +415,27 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
         },
     ];
 
+    let suppressed = match suppression::load(db).await {
+        Ok(suppressed) => suppressed,
+        Err(e) => {
+            logger.severe(format!("Unable to load the suppressed emails: {}", e));
+            return Err(e);
+        }
+    };
+
     let collection: Collection<Document> = db.collection("auto_renew_updates");
-    let mut cursor = collection.aggregate(pipeline, None).await.unwrap();
+    let mut cursor = match collection.aggregate(pipeline, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            logger.severe(format!("Unable to query the renewal toggles: {}", e));
+            return Err(e);
+        }
+    };
     let mut processed = Vec::new();
-    let mut batch_requests = Vec::new();
+    let mut invalid_email = 0;
+    let mut batch = RenewalBatch::default();
     let batch_size = conf.email.batch_size;
-    let client = Client::new();
+    let dry_run = conf.processing.dry_run.unwrap_or(false);
 
     while let Some(result) = cursor.next().await {
         match result {
@@ -211,49 +443,125 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
                 Err(e) => {
                     logger.severe(format!("Error parsing doc in renewal: {}", e));
                 }
-                Ok(renewal_doc) => {
-                    if !EmailAddress::is_valid(&renewal_doc.metadata[0].email) {
-                        logger.local(format!(
-                            "email {} is not valid",
-                            &renewal_doc.metadata[0].email
+                Ok(mut renewal_doc) => {
+                    if let Err(e) = canonicalize_allowance(&mut renewal_doc) {
+                        logger.severe(format!(
+                            "skipped renewal {}: invalid allowance \"{}\": {}",
+                            renewal_doc.tx_hash, renewal_doc.allowance, e
                         ));
+                        // marked processed, the same toggle would fail every run
+                        processed.push(renewal_doc.tx_hash.clone());
+                        continue;
+                    }
+                    if renewal_doc.metadata.is_empty() {
+                        logger.warning(format!(
+                            "skipped renewal {}: no metadata found",
+                            renewal_doc.tx_hash
+                        ));
+                        continue;
+                    }
+                    let (suppressed_recipients, emails): (Vec<String>, Vec<String>) =
+                        recipients(&renewal_doc.metadata)
+                            .into_iter()
+                            .partition(|email| suppression::is_suppressed(&suppressed, email));
+                    let emails: Vec<&str> = emails.iter().map(String::as_str).collect();
+                    for email in &suppressed_recipients {
+                        logger.info(format!(
+                            "renewal {}: not emailing {}, it is suppressed",
+                            renewal_doc.tx_hash,
+                            loggable_email(conf, email)
+                        ));
+                    }
+                    if emails.is_empty() {
+                        if suppressed_recipients.is_empty() {
+                            logger.warning(format!(
+                                "skipped renewal {}: no valid email in its metadata",
+                                renewal_doc.tx_hash
+                            ));
+                            invalid_email += 1;
+                        }
+                        // marked processed so the toggle is not picked up again
+                        processed.push(renewal_doc.tx_hash.clone());
                         continue;
                     }
 
-                    if renewal_doc.allowance == "0" {
-                        let response = client
-                            .get(&format!(
-                                "{base_url}/subscribers/{email}",
-                                base_url = conf.email.base_url,
-                                email = &renewal_doc.metadata[0].email
-                            ))
-                            .header("X-MailerLite-ApiKey", &conf.email.api_key)
-                            .send()
-                            .await;
-
-                        if let Ok(res) = response {
-                            if let Ok(api_response) = res.json::<ApiResponse>().await {
-                                batch_requests.push(create_disable_request(
-                                    &api_response.data,
-                                    &conf.email.base_url,
-                                    &conf.email.ar_group_id,
+                    if dry_run {
+                        for email in &emails {
+                            let recipient = loggable_email(conf, email);
+                            if renewal_doc.allowance == "0" {
+                                logger.info(format!(
+                                    "dry run: would disable auto renewal of {}: {}",
+                                    recipient,
+                                    describe_disable(conf, email)
                                 ));
                             } else {
-                                logger.severe(
-                                    "Error parsing response while disabling AR".to_string(),
-                                );
+                                let fields = renewal_fields(&renewal_doc, conf);
+                                logger.info(format!(
+                                    "dry run: would enable auto renewal of {}: {}",
+                                    recipient,
+                                    provider.describe(&recipient, &fields)
+                                ));
                             }
-                        } else {
-                            logger.severe("Error sending GET request to disable AR".to_string());
                         }
-                    } else {
-                        batch_requests
-                            .push(create_enable_request(&renewal_doc, &conf.email.base_url));
+                        continue;
+                    }
+
+                    match claims::claim(db, &claim_key(&renewal_doc.tx_hash)).await {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            logger.info(format!(
+                                "skipped renewal {}: claimed by another worker",
+                                renewal_doc.tx_hash
+                            ));
+                            continue;
+                        }
+                        Err(e) => {
+                            logger.warning(format!(
+                                "skipped renewal {}: unable to claim it: {}",
+                                renewal_doc.tx_hash, e
+                            ));
+                            continue;
+                        }
+                    }
+
+                    let toggle = Toggle {
+                        tx_hash: renewal_doc.tx_hash.clone(),
+                        meta_hash: renewal_doc.meta_hash.clone(),
+                        recipients: emails.iter().map(|email| email.to_string()).collect(),
+                    };
+                    if renewal_doc.allowance == "0" {
+                        match provider.disable_renewal(&emails).await {
+                            Ok(()) => processed.push(renewal_doc.tx_hash.clone()),
+                            Err(e) => {
+                                logger.warning(format!(
+                                    "renewal {}: auto renewal could not be disabled: {}",
+                                    renewal_doc.tx_hash, e
+                                ));
+                                if batch.failed(toggle, &e) {
+                                    processed.push(renewal_doc.tx_hash.clone());
+                                }
+                            }
+                        }
+                        mark_processed(conf, db, logger, &mut processed).await;
+                        continue;
                     }
 
-                    if batch_requests.len() >= batch_size {
-                        process_batch_requests(&conf, &logger, &batch_requests).await;
-                        batch_requests.clear();
+                    // the emails of a toggle are sent in the same batch
+                    if !batch.emails.is_empty() && batch.emails.len() + emails.len() > batch_size {
+                        processed.extend(batch.flush(logger, provider).await);
+                        mark_processed(conf, db, logger, &mut processed).await;
+                    }
+                    let fields = renewal_fields(&renewal_doc, conf);
+                    batch.emails.extend(emails.iter().map(|email| {
+                        (
+                            email.to_string(),
+                            unsubscribe::recipient_fields(conf, email, &fields),
+                        )
+                    }));
+                    batch.toggles.push(toggle);
+                    if batch.emails.len() >= batch_size {
+                        processed.extend(batch.flush(logger, provider).await);
+                        mark_processed(conf, db, logger, &mut processed).await;
                     }
                 }
             },
@@ -263,28 +571,429 @@ pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
         }
     }
 
-    if !batch_requests.is_empty() {
-        process_batch_requests(&conf, &logger, &batch_requests).await;
+    processed.extend(batch.flush(logger, provider).await);
+    // claim keys of the toggles left unprocessed
+    let released: Vec<String> = batch
+        .unsent
+        .iter()
+        .map(|tx_hash| claim_key(tx_hash))
+        .collect();
+    record_attempts(conf, db, logger, &mut batch, &mut processed).await;
+    if !batch.dead_letters.is_empty() {
+        if let Err(e) = db
+            .collection::<Document>("failed_emails")
+            .insert_many(batch.dead_letters.iter(), None)
+            .await
+        {
+            logger.severe(format!(
+                "Error inserting into 'failed_emails' collection: {}",
+                e
+            ));
+        }
     }
 
-    // Blacklist the processed documents
+    // Blacklist the remaining processed documents
+    mark_processed(conf, db, logger, &mut processed).await;
+    claims::release(db, logger, &released).await;
+    Ok(invalid_email)
+}
+
+// Inserts the ar_processed entries gathered so far, called after each send so
+// the toggles are marked before their claim expires, dry runs only drop them
+async fn mark_processed(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    processed: &mut Vec<String>,
+) {
+    if processed.is_empty() {
+        return;
+    }
+    let processed = std::mem::take(processed);
+    if conf.processing.dry_run.unwrap_or(false) {
+        return;
+    }
     let processed_collection: Collection<Document> = db.collection("ar_processed");
-    match processed_collection
+    if let Err(e) = processed_collection
         .insert_many(
-            processed
-                .iter()
-                .map(|tx_hash: &String| doc! { "tx_hash": tx_hash })
-                .collect::<Vec<Document>>(),
+            processed.iter().map(|tx_hash| doc! { "tx_hash": tx_hash }),
             None,
         )
         .await
     {
-        Err(e) => {
-            logger.severe(format!(
-                "Error inserting into 'processed' collection: {}",
-                e
-            ));
+        logger.severe(format!(
+            "Error inserting into 'ar_processed' collection: {}",
+            e
+        ));
+    }
+}
+
+#[cfg(test)]
+mod renewal_tests {
+    use super::{
+        canonicalize_allowance, describe_disable, format_allowance, renewal_fields,
+        ReenewalToggledDoc, RenewalBatch, Toggle,
+    };
+    use crate::{
+        config::{test_config, Config},
+        logger::Logger,
+        processing::{
+            mailer::EmailError,
+            provider::{EmailKind, EmailProvider, RestProvider},
+            recipients,
+            transport::MockTransport,
+            MetadataDoc,
+        },
+    };
+
+    fn metadata(email: &str) -> MetadataDoc {
+        MetadataDoc {
+            meta_hash: "abc".to_string(),
+            email: email.to_string(),
+            tax_state: "FR".to_string(),
+            salt: "0x1".to_string(),
+        }
+    }
+
+    fn toggle(tx_hash: &str, recipients: Vec<String>) -> Toggle {
+        Toggle {
+            tx_hash: tx_hash.to_string(),
+            meta_hash: "abc".to_string(),
+            recipients,
+        }
+    }
+
+    fn renewal(allowance: &str) -> ReenewalToggledDoc {
+        ReenewalToggledDoc {
+            meta_hash: "abc".to_string(),
+            tx_hash: "0x1".to_string(),
+            domain: "example.stark".to_string(),
+            renewer: "0x123".to_string(),
+            allowance: allowance.to_string(),
+            metadata: vec![metadata("alice@example.com")],
+            same_tx_groups: vec![],
+        }
+    }
+
+    #[test]
+    fn test_format_allowance() {
+        let mut conf = test_config();
+        assert_eq!(
+            format_allowance("2500000000000000000", &conf),
+            Some("2.5 ETH".to_string())
+        );
+        assert_eq!(format_allowance(" ", &conf), None);
+        assert_eq!(format_allowance("garbage", &conf), None);
+
+        conf.email.allowance_decimals = Some(6);
+        conf.email.allowance_symbol = Some("USDC".to_string());
+        assert_eq!(
+            format_allowance("1000000", &conf),
+            Some("1 USDC".to_string())
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_allowance() {
+        let mut toggled = renewal("0x4563918244f40000");
+        assert_eq!(canonicalize_allowance(&mut toggled), Ok(()));
+        assert_eq!(toggled.allowance, "5000000000000000000");
+
+        // a disable written as hex is still a disable
+        let mut toggled = renewal("0x0");
+        assert_eq!(canonicalize_allowance(&mut toggled), Ok(()));
+        assert_eq!(toggled.allowance, "0");
+
+        for garbage in ["", "-1", "1e18", "NaN"] {
+            assert!(canonicalize_allowance(&mut renewal(garbage)).is_err());
+        }
+    }
+
+    // Subscriber request the hosted provider gets for an enabling toggle
+    fn enable_path(toggled: &ReenewalToggledDoc, email: &str, conf: &Config) -> String {
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let request = RestProvider::new(conf, &logger, &transport)
+            .request(email, &renewal_fields(toggled, conf));
+        request["path"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_renewal_fields() {
+        let conf = test_config();
+        let fields = renewal_fields(&renewal("5000000000000000000"), &conf);
+        assert_eq!(fields.kind, EmailKind::Renewal);
+        assert_eq!(fields.meta_hash, "abc");
+        assert_eq!(
+            fields.fields,
+            vec![
+                ("name".to_string(), "example.stark".to_string()),
+                ("renewer".to_string(), "0x123".to_string()),
+                ("allowance".to_string(), "5 ETH".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_enable_request_sends_allowance() {
+        let conf = test_config();
+        let path = enable_path(&renewal("5000000000000000000"), "alice@example.com", &conf);
+        assert!(path.contains("&fields[allowance]=5%20ETH"));
+    }
+
+    #[test]
+    fn test_enable_request_without_allowance() {
+        let conf = test_config();
+        let path = enable_path(&renewal(""), "alice@example.com", &conf);
+        assert!(!path.contains("fields[allowance]"));
+    }
+
+    #[test]
+    fn test_enable_request_encodes_email() {
+        let conf = test_config();
+        let path = enable_path(&renewal(""), "a+b&c=d@example.com", &conf);
+        assert!(path.contains("?email=a%2Bb%26c%3Dd%40example.com&fields[name]="));
+    }
+
+    #[test]
+    fn test_enable_request_extra_fields() {
+        let mut conf = test_config();
+        conf.email.extra_fields = Some(
+            [("renewed_by", "{renewer}")]
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        );
+        let toggled = renewal("");
+        let path = enable_path(&toggled, "alice@example.com", &conf);
+        assert!(path.contains(&format!(
+            "&fields[renewed_by]={}",
+            urlencoding::encode(&toggled.renewer)
+        )));
+    }
+
+    #[test]
+    fn test_renewal_recipients() {
+        let mut toggled = renewal("");
+        toggled.metadata.push(metadata("not an email"));
+        toggled.metadata.push(metadata("bob@example.com"));
+        toggled.metadata.push(metadata("Alice@Example.com"));
+        assert_eq!(
+            recipients(&toggled.metadata),
+            vec!["alice@example.com", "bob@example.com"]
+        );
+        toggled.metadata.clear();
+        assert!(recipients(&toggled.metadata).is_empty());
+    }
+
+    #[test]
+    fn test_enable_request_dedup_groups() {
+        let conf = test_config();
+        let mut toggled = renewal("");
+        toggled.same_tx_groups = ["7", "8", "7"].map(String::from).to_vec();
+        let path = enable_path(&toggled, "alice@example.com", &conf);
+        assert!(path.ends_with("&groups[]=7&groups[]=8"));
+    }
+
+    // Paths of the subscriber requests of the batch sent to the provider
+    fn batch_paths(transport: &MockTransport) -> Vec<String> {
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        requests[0].body.as_ref().unwrap()["requests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|request| request["path"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_renewal_batch_once_per_address() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let mut toggled = renewal("5000000000000000000");
+        for email in [
+            "ALICE@example.com",
+            " Alice@Example.Com ",
+            "bob@example.com",
+        ] {
+            toggled.metadata.push(metadata(email));
+        }
+
+        let fields = renewal_fields(&toggled, &conf);
+        let mut batch = RenewalBatch::default();
+        for email in recipients(&toggled.metadata) {
+            batch.emails.push((email, fields.clone()));
+        }
+        batch
+            .toggles
+            .push(toggle(&toggled.tx_hash, recipients(&toggled.metadata)));
+        assert_eq!(
+            batch.flush(&logger, &provider).await,
+            vec!["0x1".to_string()]
+        );
+
+        let paths = batch_paths(&transport);
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].contains("?email=alice%40example.com&"));
+        assert!(paths[1].contains("?email=bob%40example.com&"));
+    }
+
+    #[tokio::test]
+    async fn test_disable_renewal_fails_when_a_lookup_fails() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport
+            .respond(
+                200,
+                &[],
+                r#"{"data": {"id": "1", "groups": [{"id": "7"}]}}"#,
+            )
+            .fail("connection reset");
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let result = provider
+            .disable_renewal(&["alice@example.com", "bob@example.com"])
+            .await;
+        assert!(result.unwrap_err().is_retryable());
+        // the lookups only, nothing is updated
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_describe_disable() {
+        let mut conf = test_config();
+        conf.watchtower.redact_pii = Some(true);
+        assert_eq!(
+            describe_disable(&conf, "alice@example.com"),
+            format!(
+                "GET {}/subscribers/a%2A%2A%2A%40example.com",
+                conf.email.base_url
+            )
+        );
+        conf.watchtower.redact_pii = Some(false);
+        assert_eq!(
+            describe_disable(&conf, "alice@example.com"),
+            format!(
+                "GET {}/subscribers/alice%40example.com",
+                conf.email.base_url
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disable_renewal_fails_on_an_error_status() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(404, &[], r#"{"message": "not found"}"#);
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        let result = provider.disable_renewal(&["alice+ar@example.com"]).await;
+        assert!(matches!(result, Err(EmailError::Status(status, _)) if status == 404));
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].url,
+            format!(
+                "{}/subscribers/alice%2Bar%40example.com",
+                conf.email.base_url
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disable_renewal_removes_the_group() {
+        let mut conf = test_config();
+        conf.email.ar_group_id = "7".to_string();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(
+            200,
+            &[],
+            r#"{"data": {"id": "1", "groups": [{"id": "7"}, {"id": "8"}]}}"#,
+        );
+        let provider = RestProvider::new(&conf, &logger, &transport);
+
+        assert_eq!(
+            provider.disable_renewal(&["alice@example.com"]).await,
+            Ok(())
+        );
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        let update = &requests[1].body.as_ref().unwrap()["requests"][0];
+        assert_eq!(update["method"], "PUT");
+        assert_eq!(update["body"]["groups"], serde_json::json!(["8"]));
+    }
+
+    #[tokio::test]
+    async fn test_renewal_batch_marks_only_sent_toggles() {
+        let mut conf = test_config();
+        conf.retry.max_attempts = Some(1);
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport
+            .respond(200, &[], "{}")
+            .respond(503, &[], "unavailable");
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let fields = renewal_fields(&renewal("5000000000000000000"), &conf);
+        let alice = vec!["alice@example.com".to_string()];
+
+        let mut batch = RenewalBatch::default();
+        batch
+            .emails
+            .push(("alice@example.com".to_string(), fields.clone()));
+        batch.toggles.push(toggle("0x1", alice.clone()));
+        assert_eq!(
+            batch.flush(&logger, &provider).await,
+            vec!["0x1".to_string()]
+        );
+
+        batch.emails.push(("alice@example.com".to_string(), fields));
+        batch.toggles.push(toggle("0x2", alice));
+        assert!(batch.flush(&logger, &provider).await.is_empty());
+        assert!(batch.emails.is_empty() && batch.toggles.is_empty());
+        // its claim is released and the failed run counted
+        assert_eq!(batch.unsent, vec!["0x2".to_string()]);
+        assert_eq!(batch.retrying.len(), 1);
+        assert!(batch.dead_letters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_renewal_batch_dead_letters_rejected_toggles() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        let transport = MockTransport::default();
+        transport.respond(422, &[], "invalid");
+        let provider = RestProvider::new(&conf, &logger, &transport);
+        let fields = renewal_fields(&renewal("5000000000000000000"), &conf);
+
+        let mut batch = RenewalBatch::default();
+        for email in ["alice@example.com", "bob@example.com"] {
+            batch.emails.push((email.to_string(), fields.clone()));
         }
-        _ => {}
+        batch.toggles.push(toggle(
+            "0x2",
+            vec![
+                "alice@example.com".to_string(),
+                "bob@example.com".to_string(),
+            ],
+        ));
+        // marked processed so it is not sent again every run
+        assert_eq!(
+            batch.flush(&logger, &provider).await,
+            vec!["0x2".to_string()]
+        );
+        assert!(batch.unsent.is_empty() && batch.retrying.is_empty());
+        assert_eq!(batch.dead_letters.len(), 2);
+        let letter = &batch.dead_letters[1];
+        assert_eq!(letter.get_str("tx_hash"), Ok("0x2"));
+        assert_eq!(letter.get_str("meta_hash"), Ok("abc"));
+        assert_eq!(letter.get_str("kind"), Ok("renewal"));
+        assert_eq!(letter.get_str("recipient"), Ok("bob@example.com"));
+        assert_eq!(letter.get_i32("status"), Ok(422));
     }
 }
@@ -0,0 +1,123 @@
+use super::ProcessingSummary;
+use crate::{config::Config, logger::Logger};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    options::IndexOptions,
+    Collection, Database, IndexModel,
+};
+use std::time::Duration;
+
+const DEFAULT_COLLECTION: &str = "processing_runs";
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+
+fn collection(conf: &Config, db: &Database) -> Collection<Document> {
+    db.collection(
+        conf.processing
+            .runs_collection
+            .as_deref()
+            .unwrap_or(DEFAULT_COLLECTION),
+    )
+}
+
+// Runs expire through a TTL index on started_at, changing the retention of an
+// existing index requires dropping it first
+pub async fn ensure_retention(conf: &Config, db: &Database, logger: &Logger) {
+    let days = conf
+        .processing
+        .runs_retention_days
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    let index = IndexModel::builder()
+        .keys(doc! { "started_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(days * 24 * 60 * 60))
+                .build(),
+        )
+        .build();
+    if let Err(e) = collection(conf, db).create_index(index, None).await {
+        logger.warning(format!("Unable to create the runs retention index: {}", e));
+    }
+}
+
+pub fn run_doc(
+    started_at: DateTime,
+    ended_at: DateTime,
+    pipelines: &[&str],
+    dry_run: bool,
+    summary: &ProcessingSummary,
+) -> Document {
+    doc! {
+        "started_at": started_at,
+        "ended_at": ended_at,
+        "duration_ms": ended_at.timestamp_millis() - started_at.timestamp_millis(),
+        "pipelines": pipelines,
+        "dry_run": dry_run,
+        "read": summary.read as i64,
+        "sent": summary.sent as i64,
+        "failed": summary.failed as i64,
+        "skipped": summary.skipped as i64,
+        "invalid_email": summary.invalid_email as i64,
+    }
+}
+
+pub async fn record(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    started_at: DateTime,
+    pipelines: &[&str],
+    summary: &ProcessingSummary,
+) {
+    let run = run_doc(
+        started_at,
+        DateTime::now(),
+        pipelines,
+        conf.processing.dry_run.unwrap_or(false),
+        summary,
+    );
+    if let Err(e) = collection(conf, db).insert_one(run, None).await {
+        logger.severe(format!("Error saving the run summary: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod runs_tests {
+    use super::run_doc;
+    use crate::processing::ProcessingSummary;
+    use mongodb::bson::{doc, DateTime};
+
+    #[test]
+    fn test_run_doc() {
+        let started_at = DateTime::from_millis(1_700_000_000_000);
+        let ended_at = DateTime::from_millis(1_700_000_004_500);
+        let summary = ProcessingSummary {
+            read: 12,
+            sent: 9,
+            failed: 1,
+            skipped: 2,
+            invalid_email: 1,
+        };
+
+        assert_eq!(
+            run_doc(
+                started_at,
+                ended_at,
+                &["purchases", "subscribers"],
+                false,
+                &summary
+            ),
+            doc! {
+                "started_at": started_at,
+                "ended_at": ended_at,
+                "duration_ms": 4500_i64,
+                "pipelines": ["purchases", "subscribers"],
+                "dry_run": false,
+                "read": 12_i64,
+                "sent": 9_i64,
+                "failed": 1_i64,
+                "skipped": 2_i64,
+                "invalid_email": 1_i64,
+            }
+        );
+    }
+}
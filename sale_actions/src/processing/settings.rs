@@ -0,0 +1,119 @@
+use crate::{
+    config::{Config, Processing},
+    logger::Logger,
+};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection, Database,
+};
+
+// Id of the settings document read by sale_actions
+const SETTINGS_ID: &str = "sale_actions";
+
+// Overlays the runtime settings stored in the database onto the file config,
+// the file values are kept for anything missing or invalid
+pub async fn load(conf: &Config, db: &Database, logger: &Logger) -> Config {
+    let mut run_conf = conf.clone();
+    let collection_name = match &conf.processing.settings_collection {
+        Some(name) => name,
+        None => return run_conf,
+    };
+
+    let collection: Collection<Document> = db.collection(collection_name);
+    match collection.find_one(doc! { "_id": SETTINGS_ID }, None).await {
+        Ok(Some(settings)) => {
+            for warning in apply(&mut run_conf.processing, &settings) {
+                logger.warning(warning);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            logger.warning(format!(
+                "Unable to read runtime settings, using config values: {}",
+                e
+            ));
+        }
+    }
+    run_conf
+}
+
+// Applies every valid setting and returns a warning for each ignored one
+fn apply(processing: &mut Processing, settings: &Document) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (key, value) in settings {
+        match (key.as_str(), value) {
+            ("_id", _) => {}
+            ("enable_sales", Bson::Boolean(enabled)) => processing.enable_sales = Some(*enabled),
+            ("dry_run", Bson::Boolean(dry_run)) => processing.dry_run = Some(*dry_run),
+            ("max_sends_per_run", value) if as_count(value).is_some() => {
+                processing.max_sends_per_run = as_count(value)
+            }
+            ("enable_sales" | "dry_run" | "max_sends_per_run", value) => warnings.push(format!(
+                "Ignoring invalid runtime setting {}: {}",
+                key, value
+            )),
+            _ => warnings.push(format!("Ignoring unknown runtime setting {}", key)),
+        }
+    }
+    warnings
+}
+
+fn as_count(value: &Bson) -> Option<usize> {
+    match value {
+        Bson::Int32(n) => usize::try_from(*n).ok(),
+        Bson::Int64(n) => usize::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod settings_tests {
+    use super::apply;
+    use crate::config::Processing;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_apply_overlays_valid_settings() {
+        let mut processing = Processing {
+            enable_sales: Some(true),
+            max_sends_per_run: Some(10),
+            ..Default::default()
+        };
+        let warnings = apply(
+            &mut processing,
+            &doc! {
+                "_id": "sale_actions",
+                "enable_sales": false,
+                "dry_run": true,
+                "max_sends_per_run": 500_i64,
+            },
+        );
+
+        assert!(warnings.is_empty());
+        assert_eq!(processing.enable_sales, Some(false));
+        assert_eq!(processing.dry_run, Some(true));
+        assert_eq!(processing.max_sends_per_run, Some(500));
+    }
+
+    #[test]
+    fn test_apply_ignores_invalid_settings() {
+        let mut processing = Processing {
+            enable_sales: Some(true),
+            max_sends_per_run: Some(10),
+            ..Default::default()
+        };
+        let warnings = apply(
+            &mut processing,
+            &doc! {
+                "enable_sales": "no",
+                "max_sends_per_run": -5,
+                "unknown": 1,
+            },
+        );
+
+        assert_eq!(warnings.len(), 3);
+        assert_eq!(processing.enable_sales, Some(true));
+        assert_eq!(processing.max_sends_per_run, Some(10));
+        assert_eq!(processing.dry_run, None);
+    }
+}
@@ -0,0 +1,124 @@
+use super::{mailer::send_batch, transport::EmailTransport};
+use crate::{config::Config, logger::Logger};
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+    Collection, Database,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+// Newsletter subscribers queued by api_endpoint, waiting to be pushed to the provider
+#[derive(Deserialize, Debug)]
+pub struct QueuedSubscriber {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub email: String,
+    pub groups: Vec<String>,
+}
+
+// One provider request per distinct email, duplicates share the first request
+fn create_subscriber_requests(subscribers: &[QueuedSubscriber], base_url: &str) -> Vec<Value> {
+    let mut seen = HashSet::new();
+    subscribers
+        .iter()
+        .filter(|subscriber| seen.insert(subscriber.email.trim().to_lowercase()))
+        .map(|subscriber| {
+            json!({
+                "method": "POST",
+                "path": format!("{}/subscribers", base_url),
+                "body": {
+                    "email": subscriber.email.trim(),
+                    "groups": subscriber.groups,
+                }
+            })
+        })
+        .collect()
+}
+
+pub async fn process_data(
+    conf: &Config,
+    db: &Database,
+    logger: &Logger,
+    transport: &dyn EmailTransport,
+) {
+    let queue: Collection<QueuedSubscriber> = db.collection("subscriber_queue");
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let pending: Vec<QueuedSubscriber> = match queue.find(doc! { "synced": false }, options).await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(pending) => pending,
+            Err(e) => {
+                logger.severe(format!("Error reading subscriber queue: {}", e));
+                return;
+            }
+        },
+        Err(e) => {
+            logger.severe(format!("Error querying subscriber queue: {}", e));
+            return;
+        }
+    };
+
+    let queue: Collection<Document> = db.collection("subscriber_queue");
+    for chunk in pending.chunks(conf.email.batch_size.max(1)) {
+        let requests = create_subscriber_requests(chunk, &conf.email.base_url);
+        let ids: Vec<ObjectId> = chunk.iter().map(|subscriber| subscriber.id).collect();
+        // Failed subscribers stay pending and are retried on the next run
+        let update = if send_batch(conf, logger, transport, &requests).await.is_ok() {
+            if conf.processing.dry_run.unwrap_or(false) {
+                continue;
+            }
+            doc! { "$set": { "synced": true } }
+        } else {
+            doc! { "$inc": { "attempts": 1 } }
+        };
+        if let Err(e) = queue
+            .update_many(doc! { "_id": { "$in": ids } }, update, None)
+            .await
+        {
+            logger.severe(format!("Error updating subscriber queue: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod subscribers_tests {
+    use super::{create_subscriber_requests, QueuedSubscriber};
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::json;
+
+    fn queued(email: &str) -> QueuedSubscriber {
+        QueuedSubscriber {
+            id: ObjectId::new(),
+            email: email.to_string(),
+            groups: vec!["123".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_subscriber_requests_dedup_emails() {
+        let subscribers = [
+            queued("alice@example.com"),
+            queued("bob@example.com"),
+            queued(" Alice@Example.com"),
+        ];
+        let requests = create_subscriber_requests(&subscribers, "https://provider/api");
+
+        assert_eq!(
+            requests,
+            vec![
+                json!({
+                    "method": "POST",
+                    "path": "https://provider/api/subscribers",
+                    "body": { "email": "alice@example.com", "groups": ["123"] }
+                }),
+                json!({
+                    "method": "POST",
+                    "path": "https://provider/api/subscribers",
+                    "body": { "email": "bob@example.com", "groups": ["123"] }
+                }),
+            ]
+        );
+    }
+}
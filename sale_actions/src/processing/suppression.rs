@@ -0,0 +1,56 @@
+use super::purchases::SaleDoc;
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+    Database,
+};
+use std::collections::HashSet;
+
+// Filled by api_endpoint, on hard bounces and spam complaints posted to
+// /email_events or by hand with /suppress, the addresses are stored lowercased
+const COLLECTION: &str = "suppressed_emails";
+
+// Every suppressed address, read once per run
+pub async fn load(db: &Database) -> Result<HashSet<String>, mongodb::error::Error> {
+    let options = FindOptions::builder()
+        .projection(doc! { "_id": 0, "email": 1 })
+        .build();
+    let documents: Vec<Document> = db
+        .collection::<Document>(COLLECTION)
+        .find(None, options)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(documents
+        .iter()
+        .filter_map(|document| document.get_str("email").ok())
+        .map(str::to_lowercase)
+        .collect())
+}
+
+pub fn is_suppressed(suppressed: &HashSet<String>, email: &str) -> bool {
+    suppressed.contains(&email.trim().to_lowercase())
+}
+
+// Drops the metadata entries of suppressed recipients, returns how many were
+// dropped
+pub fn drop_suppressed(sale: &mut SaleDoc, suppressed: &HashSet<String>) -> usize {
+    let before = sale.metadata.len();
+    sale.metadata
+        .retain(|metadata| !is_suppressed(suppressed, &metadata.email));
+    before - sale.metadata.len()
+}
+
+#[cfg(test)]
+mod suppression_tests {
+    use super::is_suppressed;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_is_suppressed_ignores_case() {
+        let suppressed: HashSet<String> = ["alice@example.com".to_string()].into();
+        assert!(is_suppressed(&suppressed, " Alice@Example.com "));
+        assert!(!is_suppressed(&suppressed, "bob@example.com"));
+    }
+}
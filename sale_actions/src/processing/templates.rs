@@ -0,0 +1,203 @@
+use super::provider::{EmailFields, EmailKind};
+use tera::{Context, Tera};
+
+// Files every kind of email needs in its directory
+const SUBJECT: &str = "subject.txt";
+const HTML: &str = "body.html";
+const TEXT: &str = "body.txt";
+
+const KINDS: [EmailKind; 2] = [EmailKind::Purchase, EmailKind::Renewal];
+
+#[derive(Debug, PartialEq)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+// Subject, HTML and plain text bodies rendered locally from the sale fields,
+// one directory per kind of email:
+// <dir>/purchase/{subject.txt,body.html,body.txt} and <dir>/renewal/...
+pub struct Templates {
+    tera: Tera,
+}
+
+fn template_name(kind: EmailKind, file: &str) -> String {
+    format!("{}/{}", kind.as_str(), file)
+}
+
+impl Templates {
+    pub fn load(dir: &str) -> Result<Self, String> {
+        let tera = Tera::new(&format!("{}/**/*", dir.trim_end_matches('/')))
+            .map_err(|e| format!("unable to load the templates of {}: {}", dir, e))?;
+        Templates::new(tera).map_err(|e| format!("{} in {}", e, dir))
+    }
+
+    // Missing templates are reported at startup rather than on the first sale
+    pub fn new(tera: Tera) -> Result<Self, String> {
+        let names: Vec<&str> = tera.get_template_names().collect();
+        let missing: Vec<String> = KINDS
+            .iter()
+            .flat_map(|kind| [SUBJECT, HTML, TEXT].map(|file| template_name(*kind, file)))
+            .filter(|name| !names.contains(&name.as_str()))
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("missing email templates {}", missing.join(", ")));
+        }
+        Ok(Templates { tera })
+    }
+
+    pub fn render(&self, fields: &EmailFields) -> Result<RenderedEmail, String> {
+        let mut context = Context::new();
+        for (key, value) in &fields.fields {
+            context.insert(key.as_str(), value);
+        }
+        let render = |file: &str| {
+            let name = template_name(fields.kind, file);
+            self.tera
+                .render(&name, &context)
+                .map_err(|e| format!("unable to render {}: {}", name, e))
+        };
+        Ok(RenderedEmail {
+            // a subject is a single line whatever the file ends with
+            subject: render(SUBJECT)?.trim().to_string(),
+            html: render(HTML)?,
+            text: render(TEXT)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod templates_tests {
+    use super::{RenderedEmail, Templates};
+    use crate::processing::provider::{EmailFields, EmailKind};
+    use tera::Tera;
+
+    fn tera(skip: Option<&str>) -> Tera {
+        let mut tera = Tera::default();
+        let templates = [
+            ("purchase/subject.txt", "Your domain {{ name }}\n"),
+            (
+                "purchase/body.html",
+                "<p>{{ name }} is yours until {{ expiry }}</p>",
+            ),
+            (
+                "purchase/body.txt",
+                "{{ name }} is yours until {{ expiry }}",
+            ),
+            ("renewal/subject.txt", "{{ name }} renewed"),
+            (
+                "renewal/body.html",
+                "<p>{{ name }} renewed until {{ expiry }}</p>",
+            ),
+            ("renewal/body.txt", "{{ name }} renewed until {{ expiry }}"),
+        ];
+        tera.add_raw_templates(
+            templates
+                .into_iter()
+                .filter(|(name, _)| Some(*name) != skip),
+        )
+        .unwrap();
+        tera
+    }
+
+    fn fields(kind: EmailKind, name: &str) -> EmailFields {
+        EmailFields {
+            fields: vec![
+                ("name".to_string(), name.to_string()),
+                ("expiry".to_string(), "2025-01-01 00:00:00".to_string()),
+            ],
+            groups: vec![],
+            kind,
+            meta_hash: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_by_kind() {
+        let templates = Templates::new(tera(None)).unwrap();
+        assert_eq!(
+            templates
+                .render(&fields(EmailKind::Purchase, "ben.stark"))
+                .unwrap(),
+            RenderedEmail {
+                subject: "Your domain ben.stark".to_string(),
+                html: "<p>ben.stark is yours until 2025-01-01 00:00:00</p>".to_string(),
+                text: "ben.stark is yours until 2025-01-01 00:00:00".to_string(),
+            }
+        );
+        let renewal = templates
+            .render(&fields(EmailKind::Renewal, "ben.stark"))
+            .unwrap();
+        assert_eq!(renewal.subject, "ben.stark renewed");
+    }
+
+    #[test]
+    fn test_html_is_escaped() {
+        let templates = Templates::new(tera(None)).unwrap();
+        let email = templates
+            .render(&fields(EmailKind::Purchase, "<b>ben</b>"))
+            .unwrap();
+        assert_eq!(
+            email.html,
+            "<p>&lt;b&gt;ben&lt;&#x2F;b&gt; is yours until 2025-01-01 00:00:00</p>"
+        );
+        assert!(email.text.starts_with("<b>ben</b>"));
+    }
+
+    #[test]
+    fn test_shipped_templates() {
+        let templates = Templates::load(concat!(env!("CARGO_MANIFEST_DIR"), "/templates")).unwrap();
+        let email = templates
+            .render(&fields(EmailKind::Purchase, "ben.stark"))
+            .unwrap();
+        assert_eq!(email.subject, "Your domain ben.stark");
+        assert!(email
+            .text
+            .contains("It is yours until 2025-01-01 00:00:00."));
+        assert!(!email.text.contains("Unsubscribe"));
+        let mut with_link = fields(EmailKind::Purchase, "ben.stark");
+        with_link.fields.push((
+            "unsubscribe_url".to_string(),
+            "https://api.example.com/v1/unsubscribe?token=abc".to_string(),
+        ));
+        let email = templates.render(&with_link).unwrap();
+        // tera escapes the slashes of the href, browsers read it back as is
+        assert!(email
+            .html
+            .contains("unsubscribe?token=abc\">Unsubscribe</a>"));
+        assert!(email
+            .text
+            .contains("Unsubscribe: https://api.example.com/v1/unsubscribe?token=abc"));
+
+        let renewal = EmailFields {
+            fields: vec![
+                ("name".to_string(), "ben.stark".to_string()),
+                ("renewer".to_string(), "0x123".to_string()),
+                ("allowance".to_string(), "5 ETH".to_string()),
+            ],
+            groups: vec![],
+            kind: EmailKind::Renewal,
+            meta_hash: "0x1".to_string(),
+        };
+        let email = templates.render(&renewal).unwrap();
+        assert_eq!(email.subject, "Auto renewal of ben.stark is on");
+        assert!(email.text.contains("Allowance: 5 ETH"));
+        // the allowance is left out when it could not be formatted
+        let mut without_allowance = renewal;
+        without_allowance.fields.pop();
+        assert!(!templates
+            .render(&without_allowance)
+            .unwrap()
+            .text
+            .contains("Allowance"));
+    }
+
+    #[test]
+    fn test_missing_template() {
+        assert_eq!(
+            Templates::new(tera(Some("renewal/body.txt"))).err(),
+            Some("missing email templates renewal/body.txt".to_string())
+        );
+    }
+}
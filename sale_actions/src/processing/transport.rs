@@ -0,0 +1,297 @@
+use crate::config::Email;
+use async_trait::async_trait;
+use reqwest::{tls, Client, Method, StatusCode};
+use serde_json::Value;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep_until;
+
+const DEFAULT_MIN_TLS_VERSION: &str = "1.2";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_USER_AGENT: &str = concat!("sales.starknet.id/", env!("CARGO_PKG_VERSION"));
+
+pub fn parse_tls_version(version: &str) -> Result<tls::Version, String> {
+    match version.trim() {
+        "1.0" => Ok(tls::Version::TLS_1_0),
+        "1.1" => Ok(tls::Version::TLS_1_1),
+        "1.2" => Ok(tls::Version::TLS_1_2),
+        "1.3" => Ok(tls::Version::TLS_1_3),
+        other => Err(format!(
+            "invalid email.min_tls_version \"{}\", expected 1.0, 1.1, 1.2 or 1.3",
+            other
+        )),
+    }
+}
+
+// Client shared by every call made to the email provider, built once so
+// connections are pooled, the timeouts bound how long a hung provider can
+// stall a run
+pub fn build_client(conf: &Email) -> Result<Client, String> {
+    let min_tls_version = parse_tls_version(
+        conf.min_tls_version
+            .as_deref()
+            .unwrap_or(DEFAULT_MIN_TLS_VERSION),
+    )?;
+    Client::builder()
+        .user_agent(conf.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+        .min_tls_version(min_tls_version)
+        .timeout(Duration::from_secs(
+            conf.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+        ))
+        .connect_timeout(Duration::from_secs(
+            conf.connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+        ))
+        .build()
+        .map_err(|e| format!("unable to build the email client: {}", e))
+}
+
+#[derive(Clone, Debug)]
+pub struct TransportRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Value>,
+}
+
+impl TransportRequest {
+    pub fn new(method: Method, url: impl Into<String>) -> Self {
+        TransportRequest {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn json(mut self, body: Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl TransportResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Seam between the processing logic and the HTTP calls made to the email
+// provider, so the decision logic can be tested without a network
+#[async_trait]
+pub trait EmailTransport: Send + Sync {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, String>;
+}
+
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client) -> Self {
+        ReqwestTransport { client }
+    }
+}
+
+#[async_trait]
+impl EmailTransport for ReqwestTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, String> {
+        let mut builder = self.client.request(request.method, &request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        if let Some(body) = &request.body {
+            builder = builder.json(body);
+        }
+
+        let res = builder.send().await.map_err(|e| e.to_string())?;
+        let status = res.status();
+        let headers = res
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.to_string(), value.to_string()))
+            })
+            .collect();
+        let body = res
+            .text()
+            .await
+            .unwrap_or_else(|_| "Failed to retrieve response body".to_string());
+
+        Ok(TransportResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+// Spaces the calls made through the inner transport so no more than
+// max_per_second start in any second, concurrent callers queue up for the
+// next free slot
+pub struct RateLimitedTransport<T> {
+    inner: T,
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl<T> RateLimitedTransport<T> {
+    pub fn new(inner: T, max_per_second: u32) -> Self {
+        RateLimitedTransport {
+            inner,
+            interval: Duration::from_secs(1) / max_per_second.max(1),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait_for_slot(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        sleep_until(slot.into()).await;
+    }
+}
+
+#[async_trait]
+impl<T: EmailTransport> EmailTransport for RateLimitedTransport<T> {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, String> {
+        self.wait_for_slot().await;
+        self.inner.send(request).await
+    }
+}
+
+// Records every request and replays the queued responses in order, answering
+// 200 with an empty JSON object once the queue is exhausted
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockTransport {
+    requests: std::sync::Mutex<Vec<TransportRequest>>,
+    responses: std::sync::Mutex<std::collections::VecDeque<Result<TransportResponse, String>>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn respond(&self, status: u16, headers: &[(&str, &str)], body: &str) -> &Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(Ok(TransportResponse {
+                status: StatusCode::from_u16(status).unwrap(),
+                headers: headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                body: body.to_string(),
+            }));
+        self
+    }
+
+    pub fn fail(&self, error: &str) -> &Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(Err(error.to_string()));
+        self
+    }
+
+    pub fn requests(&self) -> Vec<TransportRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl EmailTransport for MockTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, String> {
+        self.requests.lock().unwrap().push(request);
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| {
+                Ok(TransportResponse {
+                    status: StatusCode::OK,
+                    headers: Vec::new(),
+                    body: "{}".to_string(),
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::{
+        build_client, parse_tls_version, EmailTransport, MockTransport, RateLimitedTransport,
+        TransportRequest,
+    };
+    use crate::config::test_config;
+    use futures::future::join_all;
+    use reqwest::{tls, Method};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_parse_tls_version() {
+        assert_eq!(parse_tls_version("1.2"), Ok(tls::Version::TLS_1_2));
+        assert_eq!(parse_tls_version(" 1.3 "), Ok(tls::Version::TLS_1_3));
+        assert!(parse_tls_version("1.4").is_err());
+        assert!(parse_tls_version("TLSv1.2").is_err());
+        assert!(parse_tls_version("").is_err());
+    }
+
+    #[test]
+    fn test_build_client() {
+        let mut conf = test_config();
+        assert!(build_client(&conf.email).is_ok());
+
+        conf.email.min_tls_version = Some("1.4".to_string());
+        assert!(build_client(&conf.email).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_transport() {
+        let max_per_second = 20;
+        let transport = RateLimitedTransport::new(MockTransport::default(), max_per_second);
+
+        // 50 sales sent concurrently, one provider call each
+        let mut dispatched = join_all((0..50).map(|_| async {
+            transport
+                .send(TransportRequest::new(Method::POST, "https://provider/api"))
+                .await
+                .unwrap();
+            Instant::now()
+        }))
+        .await;
+        dispatched.sort();
+        assert_eq!(transport.inner.requests().len(), 50);
+        // any window holding more than max_per_second calls spans at least a second
+        let tolerance = Duration::from_millis(10);
+        for window in dispatched.windows(max_per_second as usize + 1) {
+            let span = window[max_per_second as usize] - window[0];
+            assert!(span + tolerance >= Duration::from_secs(1));
+        }
+    }
+}
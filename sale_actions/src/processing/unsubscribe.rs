@@ -0,0 +1,87 @@
+use super::provider::EmailFields;
+use crate::config::Config;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const DEFAULT_TTL_DAYS: u64 = 90;
+
+// Token verified by the /unsubscribe endpoint of api_endpoint: hex email,
+// expiry in unix seconds and the HMAC of both under the shared secret
+pub fn sign_token(secret: &str, email: &str, expires_at: i64) -> String {
+    let payload = format!("{}.{}", hex::encode(email), expires_at);
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+// Fields of the email sent to one recipient: the fields of the sale and, when
+// [unsubscribe] is configured, its own unsubscribe_url
+pub fn recipient_fields(conf: &Config, email: &str, fields: &EmailFields) -> EmailFields {
+    let mut fields = fields.clone();
+    if let Some(unsubscribe) = &conf.unsubscribe {
+        let ttl_days = unsubscribe.ttl_days.unwrap_or(DEFAULT_TTL_DAYS);
+        let expires_at = Utc::now().timestamp() + (ttl_days * 24 * 3600) as i64;
+        let token = sign_token(&unsubscribe.secret, email, expires_at);
+        fields.fields.push((
+            "unsubscribe_url".to_string(),
+            format!("{}?token={}", unsubscribe.url, token),
+        ));
+    }
+    fields
+}
+
+#[cfg(test)]
+mod unsubscribe_tests {
+    use super::{recipient_fields, sign_token};
+    use crate::{
+        config::{test_config, Unsubscribe},
+        processing::provider::{EmailFields, EmailKind},
+    };
+
+    fn fields() -> EmailFields {
+        EmailFields {
+            fields: vec![("name".to_string(), "ben.stark".to_string())],
+            groups: vec![],
+            kind: EmailKind::Purchase,
+            meta_hash: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sign_token() {
+        // the token api_endpoint accepts for this secret, email and expiry
+        assert_eq!(
+            sign_token("secret", "alice@example.com", 1_700_000_060),
+            "616c696365406578616d706c652e636f6d.1700000060.\
+             1d68d9d4938a7d9f2a1b615760d23efa12aa2595d76e6fec15cba12cb98344d6"
+        );
+    }
+
+    #[test]
+    fn test_recipient_fields() {
+        let mut conf = test_config();
+        conf.unsubscribe = None;
+        assert_eq!(
+            recipient_fields(&conf, "alice@example.com", &fields()),
+            fields()
+        );
+
+        conf.unsubscribe = Some(Unsubscribe {
+            secret: "secret".to_string(),
+            url: "https://api.example.com/v1/unsubscribe".to_string(),
+            ttl_days: None,
+        });
+        let alice = recipient_fields(&conf, "alice@example.com", &fields());
+        let bob = recipient_fields(&conf, "bob@example.com", &fields());
+        let (key, url) = alice.fields.last().unwrap();
+        assert_eq!(key, "unsubscribe_url");
+        assert!(url.starts_with(&format!(
+            "https://api.example.com/v1/unsubscribe?token={}.",
+            hex::encode("alice@example.com")
+        )));
+        assert_ne!(alice.fields, bob.fields);
+    }
+}
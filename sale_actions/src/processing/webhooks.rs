@@ -0,0 +1,90 @@
+use super::purchases::SaleDoc;
+use crate::{config::Config, logger::Logger};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+pub fn sale_payload(sale: &SaleDoc) -> Value {
+    json!({
+        "meta_hash": sale.meta_hash,
+        "domain": sale.domain,
+        "price": sale.price,
+        "payer": sale.payer,
+        "timestamp": sale.timestamp,
+    })
+}
+
+// Posts every emailed sale to webhooks.sale_processed_url in the background,
+// a slow or failing consumer never holds the processing back
+pub fn sale_processed(conf: &Config, logger: &Logger, sales: &[SaleDoc]) {
+    let Some(webhooks) = &conf.webhooks else {
+        return;
+    };
+    let Some(url) = webhooks.sale_processed_url.clone() else {
+        return;
+    };
+    if sales.is_empty() {
+        return;
+    }
+    let timeout = Duration::from_secs(webhooks.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            logger.local(format!("sale_processed webhook disabled: {}", e));
+            return;
+        }
+    };
+    let payloads: Vec<Value> = sales.iter().map(sale_payload).collect();
+    let logger = logger.clone();
+    tokio::spawn(async move {
+        for payload in payloads {
+            let result = client
+                .post(&url)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                logger.local(format!(
+                    "sale_processed webhook failed for {}: {}",
+                    payload["meta_hash"], e
+                ));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod webhooks_tests {
+    use super::sale_payload;
+    use crate::processing::purchases::SaleDoc;
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    #[test]
+    fn test_sale_payload() {
+        let sale: SaleDoc = mongodb::bson::from_document(doc! {
+            "tx_hash": "0x1",
+            "meta_hash": "abc",
+            "domain": "ben.stark",
+            "price": 12.5,
+            "payer": "0x2",
+            "timestamp": 1_700_000_000_i64,
+            "expiry": 1_735_689_600_i64,
+            "metadata": [],
+            "same_tx_groups": [],
+        })
+        .unwrap();
+        assert_eq!(
+            sale_payload(&sale),
+            json!({
+                "meta_hash": "abc",
+                "domain": "ben.stark",
+                "price": 12.5,
+                "payer": "0x2",
+                "timestamp": 1_700_000_000_i64,
+            })
+        );
+    }
+}
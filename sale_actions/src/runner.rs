@@ -0,0 +1,215 @@
+use crate::{
+    config::Config,
+    logger::Logger,
+    processing::{
+        self,
+        backfill::{BackfillRequest, BackfillSummary},
+        breaker::Breaker,
+        provider::{EmailProvider, RestProvider, SmtpProvider},
+        redrive::{RedriveRequest, RedriveSummary},
+        transport::EmailTransport,
+        ProcessingSummary,
+    },
+};
+use mongodb::{
+    bson::{oid::ObjectId, DateTime},
+    Database,
+};
+use serde_derive::Serialize;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+// Outcome of a run, `processed` counts the sales marked processed whether an
+// email was sent or they were skipped
+#[derive(Serialize, Debug)]
+pub struct RunReport {
+    pub run_id: String,
+    pub pipelines: Vec<&'static str>,
+    pub processed: usize,
+    #[serde(flatten)]
+    pub summary: ProcessingSummary,
+}
+
+// Everything a run needs, shared by the schedule and the /process trigger.
+// The lock keeps a single run in flight so overlapping runs never send twice
+pub struct Runner {
+    conf: Config,
+    db: Database,
+    logger: Logger,
+    transport: Box<dyn EmailTransport>,
+    smtp: Option<SmtpProvider>,
+    breaker: Breaker,
+    lock: Mutex<()>,
+}
+
+impl Runner {
+    pub fn new(
+        conf: Config,
+        db: Database,
+        logger: Logger,
+        transport: Box<dyn EmailTransport>,
+        smtp: Option<SmtpProvider>,
+    ) -> Self {
+        Runner {
+            breaker: Breaker::new(&conf.circuit_breaker),
+            conf,
+            db,
+            logger,
+            transport,
+            smtp,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn conf(&self) -> &Config {
+        &self.conf
+    }
+
+    // Waits for the run in flight, if any, before running
+    pub async fn run(&self) -> RunReport {
+        let _running = self.lock.lock().await;
+        self.run_locked().await
+    }
+
+    // None when a run is already in flight
+    pub async fn try_run(&self) -> Option<RunReport> {
+        let _running = self.lock.try_lock().ok()?;
+        Some(self.run_locked().await)
+    }
+
+    // Re-sends the dead-lettered sales, None when a run is already in flight
+    pub async fn try_redrive(
+        &self,
+        request: RedriveRequest,
+    ) -> Option<Result<RedriveSummary, String>> {
+        let _running = self.lock.try_lock().ok()?;
+        let run_id = ObjectId::new().to_hex();
+        let span = tracing::info_span!("redrive", correlation_id = %run_id);
+        let result = async {
+            let (db, logger, transport) = (&self.db, &self.logger, self.transport.as_ref());
+            let run_conf = processing::settings::load(&self.conf, db, logger).await;
+            let rest = RestProvider::new(&run_conf, logger, transport);
+            let provider: &dyn EmailProvider = match &self.smtp {
+                Some(smtp) => smtp,
+                None => &rest,
+            };
+            let provider = self.breaker.guard(provider, logger);
+            processing::redrive::redrive(&run_conf, db, logger, &provider, request)
+                .await
+                .map_err(|e| {
+                    logger.severe(format!("redrive failed: {}", e));
+                    e.to_string()
+                })
+        }
+        .instrument(span)
+        .await;
+        Some(result)
+    }
+
+    // Sends the sales of a range again, None when a run is already in flight
+    pub async fn try_backfill(
+        &self,
+        request: BackfillRequest,
+    ) -> Option<Result<BackfillSummary, String>> {
+        let _running = self.lock.try_lock().ok()?;
+        let run_id = ObjectId::new().to_hex();
+        let span = tracing::info_span!("backfill", correlation_id = %run_id);
+        let result = async {
+            let (db, logger, transport) = (&self.db, &self.logger, self.transport.as_ref());
+            let run_conf = processing::settings::load(&self.conf, db, logger).await;
+            let rest = RestProvider::new(&run_conf, logger, transport);
+            let provider: &dyn EmailProvider = match &self.smtp {
+                Some(smtp) => smtp,
+                None => &rest,
+            };
+            let provider = self.breaker.guard(provider, logger);
+            processing::backfill::backfill(&run_conf, db, logger, &provider, request)
+                .await
+                .map_err(|e| {
+                    logger.severe(format!("backfill failed: {}", e));
+                    e.to_string()
+                })
+        }
+        .instrument(span)
+        .await;
+        Some(result)
+    }
+
+    async fn run_locked(&self) -> RunReport {
+        // Every log of the run carries its id
+        let run_id = ObjectId::new().to_hex();
+        let span = tracing::info_span!("run", correlation_id = %run_id);
+        async {
+            let (db, logger, transport) = (&self.db, &self.logger, self.transport.as_ref());
+            let started_at = DateTime::now();
+            let run_conf = processing::settings::load(&self.conf, db, logger).await;
+            let mut pipelines = Vec::new();
+            let mut summary = ProcessingSummary::default();
+            if run_conf.processing.enable_sales.unwrap_or(true) {
+                let rest = RestProvider::new(&run_conf, logger, transport);
+                let provider: &dyn EmailProvider = match &self.smtp {
+                    Some(smtp) => smtp,
+                    None => &rest,
+                };
+                let provider = self.breaker.guard(provider, logger);
+                // Errors are logged by the pipeline, the run is retried next iteration
+                if let Ok(purchases) =
+                    processing::purchases::process_data(&run_conf, db, logger, &provider).await
+                {
+                    summary = purchases;
+                }
+                pipelines.push("purchases");
+            }
+            if run_conf.processing.enable_renewals.unwrap_or(false) {
+                let rest = RestProvider::new(&run_conf, logger, transport);
+                let provider: &dyn EmailProvider = match &self.smtp {
+                    Some(smtp) => smtp,
+                    None => &rest,
+                };
+                let provider = self.breaker.guard(provider, logger);
+                if let Ok(invalid_email) =
+                    processing::renewal::process_data(&run_conf, db, logger, &provider).await
+                {
+                    summary.invalid_email += invalid_email;
+                }
+                pipelines.push("renewals");
+            }
+            if run_conf.processing.sync_subscribers.unwrap_or(false) {
+                processing::subscribers::process_data(&run_conf, db, logger, transport).await;
+                pipelines.push("subscribers");
+            }
+            processing::runs::record(&run_conf, db, logger, started_at, &pipelines, &summary).await;
+
+            RunReport {
+                run_id: run_id.clone(),
+                pipelines,
+                processed: summary.sent + summary.skipped,
+                summary,
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod runner_tests {
+    use super::Runner;
+    use crate::{config::test_config, logger::Logger, processing::transport::MockTransport};
+    use mongodb::Client;
+
+    #[tokio::test]
+    async fn test_single_run_in_flight() {
+        let conf = test_config();
+        let logger = Logger::new(&conf.watchtower);
+        // the client only connects on the first operation
+        let db = Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap()
+            .database("test");
+        let runner = Runner::new(conf, db, logger, Box::<MockTransport>::default(), None);
+
+        let _running = runner.lock.try_lock().unwrap();
+        assert!(runner.try_run().await.is_none());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    processing::{self, backfill::BackfillRequest, redrive::RedriveRequest},
+    runner::Runner,
+};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use sha2::{Digest, Sha256};
+
+// Operators POST /process with the trigger token to run the pipelines right
+// away instead of waiting for the next scheduled run, /redrive to send the
+// dead-lettered sales again and /backfill to send those of a date range again
+pub async fn serve(runner: Arc<Runner>, addr: SocketAddr) -> Result<(), String> {
+    let app = Router::new()
+        .route("/process", post(process))
+        .route("/redrive", post(redrive))
+        .route("/backfill", post(backfill))
+        .with_state(runner);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await
+        .map_err(|e| format!("trigger server error: {}", e))
+}
+
+// The digests are compared rather than the tokens, so the time taken does not
+// tell how much of the token a guess got right
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |bearer| {
+            Sha256::digest(bearer.as_bytes()) == Sha256::digest(token.as_bytes())
+        })
+}
+
+async fn process(State(runner): State<Arc<Runner>>, headers: HeaderMap) -> Response {
+    let token = runner.conf().trigger.as_ref().map(|t| t.token.as_str());
+    if !is_authorized(&headers, token.unwrap_or_default()) {
+        return (StatusCode::UNAUTHORIZED, "invalid trigger token").into_response();
+    }
+    match runner.try_run().await {
+        Some(report) => (StatusCode::OK, Json(report)).into_response(),
+        None => (StatusCode::CONFLICT, "a run is already in progress").into_response(),
+    }
+}
+
+async fn redrive(
+    State(runner): State<Arc<Runner>>,
+    headers: HeaderMap,
+    request: Option<Json<RedriveRequest>>,
+) -> Response {
+    let token = runner.conf().trigger.as_ref().map(|t| t.token.as_str());
+    if !is_authorized(&headers, token.unwrap_or_default()) {
+        return (StatusCode::UNAUTHORIZED, "invalid trigger token").into_response();
+    }
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    match runner.try_redrive(request).await {
+        Some(Ok(summary)) => (StatusCode::OK, Json(summary)).into_response(),
+        Some(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        None => (StatusCode::CONFLICT, "a run is already in progress").into_response(),
+    }
+}
+
+async fn backfill(
+    State(runner): State<Arc<Runner>>,
+    headers: HeaderMap,
+    Json(request): Json<BackfillRequest>,
+) -> Response {
+    let token = runner.conf().trigger.as_ref().map(|t| t.token.as_str());
+    if !is_authorized(&headers, token.unwrap_or_default()) {
+        return (StatusCode::UNAUTHORIZED, "invalid trigger token").into_response();
+    }
+    if let Err(e) = processing::backfill::check_range(runner.conf(), &request) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    match runner.try_backfill(request).await {
+        Some(Ok(summary)) => (StatusCode::OK, Json(summary)).into_response(),
+        Some(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        None => (StatusCode::CONFLICT, "a run is already in progress").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod trigger_tests {
+    use super::is_authorized;
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    #[test]
+    fn test_is_authorized() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        assert!(is_authorized(&headers, "secret"));
+        assert!(!is_authorized(&headers, "other"));
+        assert!(!is_authorized(&headers, ""));
+    }
+}
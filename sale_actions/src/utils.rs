@@ -1,12 +1,14 @@
+use mongodb::bson::{Bson, Decimal128};
+use serde::{de::Error, Deserialize, Deserializer};
 use starknet::core::types::FieldElement;
-use std::fmt::Write;
+use std::fmt::{self, Write};
 
 #[macro_export]
 macro_rules! pub_struct {
-    ($($derive:path),*; $name:ident {$($field:ident: $t:ty),* $(,)?}) => {
+    ($($derive:path),*; $name:ident {$($(#[$attr:meta])* $field:ident: $t:ty),* $(,)?}) => {
         #[derive($($derive),*)]
         pub struct $name {
-            $(pub $field: $t),*
+            $($(#[$attr])* pub $field: $t),*
         }
     }
 }
@@ -29,8 +31,362 @@ pub fn to_hex(felt: FieldElement) -> String {
     result
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Empty,
+    InvalidCharacter(char),
+    // More than 256 bits
+    Overflow,
+    // Fits in 256 bits but is not below the field prime
+    AbovePrime,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty string"),
+            ParseError::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            ParseError::Overflow => write!(f, "value does not fit in 256 bits"),
+            ParseError::AbovePrime => write!(f, "value is not below the stark field prime"),
+        }
+    }
+}
+
+// 2^251 + 17 * 2^192 + 1, big endian
+const FIELD_PRIME: [u8; 32] = [
+    0x08, 0, 0, 0, 0, 0, 0, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0x01,
+];
+
+// Checked here rather than left to FieldElement so a value of the field size
+// read from the database is reported as such
+fn felt_from_bytes(bytes: &[u8; 32]) -> Result<FieldElement, ParseError> {
+    if *bytes >= FIELD_PRIME {
+        return Err(ParseError::AbovePrime);
+    }
+    FieldElement::from_bytes_be(bytes).map_err(|_| ParseError::AbovePrime)
+}
+
+// Inverse of to_hex, the 0x prefix is optional and odd lengths are accepted
+pub fn from_hex(s: &str) -> Result<FieldElement, ParseError> {
+    felt_from_bytes(&uint256_from_hex(s)?)
+}
+
+// Felt read from the database such as a payer or sponsor, surrounding spaces
+// are ignored and values >= the field prime are rejected with AbovePrime
+pub fn parse_felt_checked(s: &str) -> Result<FieldElement, ParseError> {
+    from_hex(s.trim())
+}
+
+// from_hex without the field check: big endian value of the hex digits, up to
+// 256 bits
+fn uint256_from_hex(s: &str) -> Result<[u8; 32], ParseError> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if digits.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidCharacter(c));
+    }
+    let digits = digits.trim_start_matches('0');
+    if digits.len() > 64 {
+        return Err(ParseError::Overflow);
+    }
+
+    let padded = format!("{:0>64}", digits);
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&padded[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    Ok(bytes)
+}
+
+// Decimal digits of a big endian value
+fn bytes_to_decimal(mut bytes: [u8; 32]) -> String {
+    let mut digits = Vec::new();
+    // long division of the big endian bytes by 10, one digit at a time
+    while bytes.iter().any(|&b| b != 0) {
+        let mut remainder = 0u16;
+        for byte in bytes.iter_mut() {
+            let value = (remainder << 8) | *byte as u16;
+            *byte = (value / 10) as u8;
+            remainder = value % 10;
+        }
+        digits.push(b'0' + remainder as u8);
+    }
+    if digits.is_empty() {
+        return String::from("0");
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+// Felt written in decimal, as the indexers print them, leading zeros are
+// accepted
+pub fn from_decimal(s: &str) -> Result<FieldElement, ParseError> {
+    felt_from_bytes(&uint256_from_decimal(s)?)
+}
+
+// from_decimal without the field check: big endian value of the decimal
+// digits, up to 256 bits
+fn uint256_from_decimal(s: &str) -> Result<[u8; 32], ParseError> {
+    if s.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if let Some(c) = s.chars().find(|c| !c.is_ascii_digit()) {
+        return Err(ParseError::InvalidCharacter(c));
+    }
+
+    let mut bytes = [0u8; 32];
+    for digit in s.bytes() {
+        // bytes = bytes * 10 + digit
+        let mut carry = (digit - b'0') as u16;
+        for byte in bytes.iter_mut().rev() {
+            let value = *byte as u16 * 10 + carry;
+            *byte = value as u8;
+            carry = value >> 8;
+        }
+        if carry != 0 {
+            return Err(ParseError::Overflow);
+        }
+    }
+    Ok(bytes)
+}
+
+// Canonical decimal form of a uint256 written in decimal or 0x-prefixed hex,
+// such as the token amounts of the indexer. Read as from_hex and from_decimal
+// do, but unlike felts the whole 256 bits are allowed, an unlimited allowance
+// is 2^256 - 1
+pub fn canonical_uint256(s: &str) -> Result<String, ParseError> {
+    let s = s.trim();
+    let bytes = if s.starts_with("0x") || s.starts_with("0X") {
+        uint256_from_hex(s)?
+    } else {
+        uint256_from_decimal(s)?
+    };
+    Ok(bytes_to_decimal(bytes))
+}
+
+const BASIC_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789-";
+// Extension alphabet of the starknet.id encoding, its characters are escaped
+// by the last basic code
+const BIG_ALPHABET: &str = "这来";
+
+// Divides a big endian integer in place, returns the remainder
+fn div_rem_small(value: &mut [u8; 32], divisor: u32) -> u32 {
+    let mut remainder = 0u32;
+    for byte in value.iter_mut() {
+        let current = (remainder << 8) | *byte as u32;
+        *byte = (current / divisor) as u8;
+        remainder = current % divisor;
+    }
+    remainder
+}
+
+// Decodes a starknet.id encoded domain label, e.g. 18925 -> "ben.stark"
+pub fn decode_domain(felt: FieldElement) -> String {
+    let basic: Vec<char> = BASIC_ALPHABET.chars().collect();
+    let big: Vec<char> = BIG_ALPHABET.chars().collect();
+    let basic_plus = basic.len() as u32 + 1;
+    let big_plus = big.len() as u32 + 1;
+    let last_big = big[big.len() - 1];
+
+    let mut value = felt.to_bytes_be();
+    let is_zero = |value: &[u8; 32]| value.iter().all(|&b| b == 0);
+    let mut decoded = String::new();
+    while !is_zero(&value) {
+        let code = div_rem_small(&mut value, basic_plus);
+        if code as usize != basic.len() {
+            decoded.push(basic[code as usize]);
+            continue;
+        }
+        let mut next = value;
+        div_rem_small(&mut next, big_plus);
+        if is_zero(&next) {
+            // escaped last character
+            let code = div_rem_small(&mut value, big_plus) as usize;
+            decoded.push(if code == 0 { basic[0] } else { big[code - 1] });
+        } else {
+            let code = div_rem_small(&mut value, big.len() as u32) as usize;
+            decoded.push(big[code]);
+        }
+    }
+
+    if decoded.is_empty() {
+        return decoded;
+    }
+
+    // Trailing runs of the last big character are stored folded
+    let label = decoded.trim_end_matches(last_big);
+    let stars = decoded[label.len()..].chars().count();
+    let mut label = label.to_string();
+    if stars > 0 {
+        if stars % 2 == 0 {
+            label.extend(std::iter::repeat(last_big).take(stars / 2 - 1));
+            label.push(big[0]);
+            label.push(basic[1]);
+        } else {
+            label.extend(std::iter::repeat(last_big).take(stars / 2 + 1));
+        }
+    }
+    format!("{}.stark", label)
+}
+
+// Domain of a sale or toggle as stored by the indexers, decoded when it holds
+// the encoded label (a felt in hex or decimal, without a dot) instead of its
+// name
+pub fn readable_domain(domain: &str) -> String {
+    let trimmed = domain.trim();
+    if trimmed.contains('.') {
+        return domain.to_string();
+    }
+    let felt = if trimmed.starts_with("0x") || trimmed.starts_with("0X") {
+        from_hex(trimmed)
+    } else {
+        from_decimal(trimmed)
+    };
+    match felt {
+        Ok(felt) => decode_domain(felt),
+        Err(_) => domain.to_string(),
+    }
+}
+
+// Canonical hex form of an address stored as a string, blank values are None
+pub fn canonical_address(address: Option<&str>) -> Result<Option<String>, String> {
+    match address.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(address) => parse_felt_checked(address)
+            .map(|felt| Some(to_hex(felt)))
+            .map_err(|e| format!("invalid address {}: {}", address, e)),
+    }
+}
+
+// Formats a raw integer token amount (e.g. wei) as a decimal amount followed by
+// the symbol, works on the digits so the full uint256 range is supported
+pub fn format_token_amount(raw: &str, decimals: usize, symbol: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    if raw.is_empty() || !raw.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("invalid token amount \"{}\"", raw));
+    }
+
+    let digits = raw.trim_start_matches('0');
+    let padded = format!("{:0>width$}", digits, width = decimals + 1);
+    let (integer, fraction) = padded.split_at(padded.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+
+    let amount = if fraction.is_empty() {
+        integer.to_string()
+    } else {
+        format!("{}.{}", integer, fraction)
+    };
+    Ok(if symbol.is_empty() {
+        amount
+    } else {
+        format!("{} {}", amount, symbol)
+    })
+}
+
+// Brings a domain into its canonical form: lowercased, and ending with the
+// configured suffix exactly once (or without it when `strip` is set)
+pub fn normalize_domain(domain: &str, suffix: Option<&str>, strip: bool) -> String {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let suffix = match suffix.map(|s| s.trim().trim_start_matches('.').to_lowercase()) {
+        Some(suffix) if !suffix.is_empty() => format!(".{}", suffix),
+        _ => return domain,
+    };
+
+    let bare = domain
+        .strip_suffix(suffix.as_str())
+        .unwrap_or(domain.as_str());
+    if strip || bare.is_empty() {
+        bare.to_string()
+    } else {
+        format!("{}{}", bare, suffix)
+    }
+}
+
+// Masks the local part of an email for the logs, j***@example.com, anything
+// that is not an address is masked entirely
+pub fn redact_email(email: &str) -> String {
+    match email.trim().rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() => {
+            let first = local.chars().next().unwrap_or_default();
+            format!("{}***@{}", first, domain)
+        }
+        _ => "***".to_string(),
+    }
+}
+
+// Converts any of the numeric BSON types the indexers may write into a f64
+pub fn bson_to_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(n) => Some(*n),
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Decimal128(n) => decimal128_to_f64(n),
+        _ => None,
+    }
+}
+
+// Decodes the IEEE 754 BID encoding used by BSON, NaN and infinities are rejected
+fn decimal128_to_f64(value: &Decimal128) -> Option<f64> {
+    let bits = u128::from_le_bytes(value.bytes());
+    if (bits >> 125) & 0b11 == 0b11 {
+        return None;
+    }
+    let exponent = ((bits >> 113) & 0x3fff) as i32 - 6176;
+    let coefficient = bits & ((1u128 << 113) - 1);
+    let magnitude = coefficient as f64 * 10f64.powi(exponent);
+    Some(if bits >> 127 == 1 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+// Deserializes a f64 whatever numeric BSON type it was stored as
+pub fn deserialize_lenient_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Bson::deserialize(deserializer)?;
+    bson_to_f64(&value)
+        .ok_or_else(|| D::Error::custom(format!("expected a number, found {}", value)))
+}
+
+// Deserializes an i64 whatever numeric BSON type it was stored as, as long as
+// it holds a whole number
+pub fn deserialize_lenient_i64<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Bson::deserialize(deserializer)?;
+    match value {
+        Bson::Int32(n) => Ok(n as i64),
+        Bson::Int64(n) => Ok(n),
+        _ => match bson_to_f64(&value) {
+            Some(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n <= i64::MAX as f64 => {
+                Ok(n as i64)
+            }
+            _ => Err(D::Error::custom(format!(
+                "expected an integer, found {}",
+                value
+            ))),
+        },
+    }
+}
+
+#[cfg(test)]
 mod utils_tests {
-    use super::to_hex;
+    use super::{
+        bson_to_f64, canonical_address, canonical_uint256, decode_domain, format_token_amount,
+        from_decimal, from_hex, normalize_domain, parse_felt_checked, readable_domain,
+        redact_email, to_hex, ParseError,
+    };
+    use mongodb::bson::{Bson, Decimal128};
     use starknet::core::types::FieldElement;
 
     #[test]
@@ -71,4 +427,348 @@ mod utils_tests {
         assert_eq!(to_hex(max).len(), 66);
         assert!(to_hex(max).starts_with("0x"));
     }
+
+    #[test]
+    fn test_from_hex_small_numbers() {
+        assert_eq!(from_hex("0x0"), Ok(FieldElement::ZERO));
+        assert_eq!(from_hex("0xff"), Ok(FieldElement::from(255u64)));
+        assert_eq!(from_hex("ff"), Ok(FieldElement::from(255u64)));
+        assert_eq!(from_hex("0xa"), Ok(FieldElement::from(10u64)));
+    }
+
+    #[test]
+    fn test_from_hex_boundary_values() {
+        assert_eq!(
+            from_hex("0xffffffffffffffff"),
+            Ok(FieldElement::from(u64::MAX))
+        );
+        assert_eq!(from_hex(&to_hex(FieldElement::MAX)), Ok(FieldElement::MAX));
+    }
+
+    #[test]
+    fn test_from_decimal() {
+        assert_eq!(from_decimal("0"), Ok(FieldElement::ZERO));
+        assert_eq!(from_decimal("0255"), Ok(FieldElement::from(255u64)));
+        assert_eq!(
+            from_decimal("18446744073709551615"),
+            Ok(FieldElement::from(u64::MAX))
+        );
+        assert_eq!(
+            from_decimal(
+                "3618502788666131213697322783095070105623107215331596699973092056135872020480"
+            ),
+            Ok(FieldElement::MAX)
+        );
+    }
+
+    #[test]
+    fn test_from_decimal_malformed() {
+        assert_eq!(from_decimal(""), Err(ParseError::Empty));
+        assert_eq!(from_decimal("12a"), Err(ParseError::InvalidCharacter('a')));
+        assert_eq!(from_decimal("-1"), Err(ParseError::InvalidCharacter('-')));
+        // the prime itself is out of the field
+        assert_eq!(
+            from_decimal(
+                "3618502788666131213697322783095070105623107215331596699973092056135872020481"
+            ),
+            Err(ParseError::AbovePrime)
+        );
+        // more than 256 bits
+        assert_eq!(from_decimal(&"9".repeat(80)), Err(ParseError::Overflow));
+    }
+
+    #[test]
+    fn test_canonical_uint256() {
+        assert_eq!(canonical_uint256("0"), Ok("0".to_string()));
+        assert_eq!(canonical_uint256(" 0005000 "), Ok("5000".to_string()));
+        assert_eq!(canonical_uint256("0x0"), Ok("0".to_string()));
+        assert_eq!(
+            canonical_uint256("0x4563918244f40000"),
+            Ok("5000000000000000000".to_string())
+        );
+        let uint256_max =
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935";
+        assert_eq!(canonical_uint256(uint256_max), Ok(uint256_max.to_string()));
+        assert_eq!(
+            canonical_uint256(&format!("0x{}", "f".repeat(64))),
+            Ok(uint256_max.to_string())
+        );
+    }
+
+    #[test]
+    fn test_canonical_uint256_malformed() {
+        assert_eq!(canonical_uint256(""), Err(ParseError::Empty));
+        assert_eq!(canonical_uint256("0x"), Err(ParseError::Empty));
+        assert_eq!(
+            canonical_uint256("-1"),
+            Err(ParseError::InvalidCharacter('-'))
+        );
+        assert_eq!(
+            canonical_uint256("1.5"),
+            Err(ParseError::InvalidCharacter('.'))
+        );
+        assert_eq!(
+            canonical_uint256("0xfg"),
+            Err(ParseError::InvalidCharacter('g'))
+        );
+        // 2^256
+        assert_eq!(
+            canonical_uint256(
+                "115792089237316195423570985008687907853269984665640564039457584007913129639936"
+            ),
+            Err(ParseError::Overflow)
+        );
+        assert_eq!(
+            canonical_uint256(&format!("0x1{}", "0".repeat(64))),
+            Err(ParseError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_canonical_uint256_agrees_with_felts() {
+        let max = "3618502788666131213697322783095070105623107215331596699973092056135872020480";
+        assert_eq!(from_decimal(max), from_hex(&to_hex(FieldElement::MAX)));
+        assert_eq!(
+            canonical_uint256(&to_hex(FieldElement::MAX)),
+            Ok(max.to_string())
+        );
+        assert_eq!(canonical_uint256(max), Ok(max.to_string()));
+        // the field check is all that tells them apart
+        let prime = "3618502788666131213697322783095070105623107215331596699973092056135872020481";
+        assert_eq!(from_decimal(prime), Err(ParseError::AbovePrime));
+        assert_eq!(canonical_uint256(prime), Ok(prime.to_string()));
+    }
+
+    #[test]
+    fn test_decode_domain() {
+        let cases = [
+            (18925u64, "ben.stark"),
+            (1_499_554_868_251, "fricoben.stark"),
+            (2_186_991_656_892, "starknet.stark"),
+            (33_133_781_693, "th0rgal.stark"),
+            (1_689_127, "1234.stark"),
+            (54_797, "b-a.stark"),
+        ];
+        for (encoded, decoded) in cases {
+            assert_eq!(decode_domain(FieldElement::from(encoded)), decoded);
+        }
+    }
+
+    #[test]
+    fn test_readable_domain() {
+        assert_eq!(readable_domain("0x49ed"), "ben.stark");
+        assert_eq!(readable_domain(" 0X49ED "), "ben.stark");
+        assert_eq!(readable_domain("18925"), "ben.stark");
+        assert_eq!(readable_domain("ben.stark"), "ben.stark");
+        // a label that merely looks like hex is kept
+        assert_eq!(readable_domain("0xbad.stark"), "0xbad.stark");
+        assert_eq!(readable_domain("0xzz"), "0xzz");
+        assert_eq!(readable_domain("ben"), "ben");
+    }
+
+    #[test]
+    fn test_decode_domain_trailing_a() {
+        // a final "a" is escaped, otherwise it would be a leading zero
+        assert_eq!(decode_domain(FieldElement::from(37u64)), "a.stark");
+        assert_eq!(decode_domain(FieldElement::from(1406u64)), "aa.stark");
+        assert_eq!(decode_domain(FieldElement::from(1407u64)), "ba.stark");
+    }
+
+    #[test]
+    fn test_decode_domain_big_alphabet() {
+        assert_eq!(decode_domain(FieldElement::from(8625u64)), "这来.stark");
+        assert_eq!(
+            decode_domain(FieldElement::from(4_118_326u64)),
+            "abc这.stark"
+        );
+        assert_eq!(
+            decode_domain(FieldElement::from(3_803_288_063u64)),
+            "来来来.stark"
+        );
+    }
+
+    #[test]
+    fn test_from_hex_malformed() {
+        assert_eq!(from_hex(""), Err(ParseError::Empty));
+        assert_eq!(from_hex("0x"), Err(ParseError::Empty));
+        assert_eq!(from_hex("0xzz"), Err(ParseError::InvalidCharacter('z')));
+        assert_eq!(
+            from_hex(&format!("0x1{}", "0".repeat(64))),
+            Err(ParseError::Overflow)
+        );
+        // the prime itself is out of the field
+        assert_eq!(
+            from_hex("0x800000000000011000000000000000000000000000000000000000000000001"),
+            Err(ParseError::AbovePrime)
+        );
+    }
+
+    #[test]
+    fn test_parse_felt_checked_prime_boundary() {
+        assert_eq!(
+            parse_felt_checked(" 0xff\n"),
+            Ok(FieldElement::from(255u64))
+        );
+        // prime - 1 is the largest element
+        assert_eq!(
+            parse_felt_checked("0x800000000000011000000000000000000000000000000000000000000000000"),
+            Ok(FieldElement::MAX)
+        );
+        assert_eq!(
+            parse_felt_checked(
+                "0x0800000000000011000000000000000000000000000000000000000000000002"
+            ),
+            Err(ParseError::AbovePrime)
+        );
+        // above the prime but still 252 bits
+        assert_eq!(
+            parse_felt_checked("0x8000000000000110000000000000000000000000000000000000000000000ff"),
+            Err(ParseError::AbovePrime)
+        );
+        assert_eq!(
+            parse_felt_checked(&format!("0x{}", "f".repeat(64))),
+            Err(ParseError::AbovePrime)
+        );
+        assert_eq!(
+            parse_felt_checked(&format!("0x{}", "f".repeat(65))),
+            Err(ParseError::Overflow)
+        );
+        assert_eq!(
+            ParseError::AbovePrime.to_string(),
+            "value is not below the stark field prime"
+        );
+    }
+
+    #[test]
+    fn test_normalize_domain_adds_suffix() {
+        assert_eq!(
+            normalize_domain("example", Some("stark"), false),
+            "example.stark"
+        );
+        assert_eq!(
+            normalize_domain("example.stark", Some("stark"), false),
+            "example.stark"
+        );
+        assert_eq!(
+            normalize_domain(" Example.STARK ", Some(".stark"), false),
+            "example.stark"
+        );
+        assert_eq!(
+            normalize_domain("sub.example", Some("stark"), false),
+            "sub.example.stark"
+        );
+    }
+
+    #[test]
+    fn test_normalize_domain_strips_suffix() {
+        assert_eq!(normalize_domain("example", Some("stark"), true), "example");
+        assert_eq!(
+            normalize_domain("example.stark", Some("stark"), true),
+            "example"
+        );
+    }
+
+    #[test]
+    fn test_normalize_domain_without_suffix() {
+        assert_eq!(
+            normalize_domain("example.stark", None, false),
+            "example.stark"
+        );
+        assert_eq!(normalize_domain("example", Some(""), false), "example");
+        assert_eq!(normalize_domain("", Some("stark"), false), "");
+    }
+
+    fn decimal128(negative: bool, exponent: i32, coefficient: u128) -> Bson {
+        let sign = if negative { 1u128 << 127 } else { 0 };
+        let bits = sign | (((exponent + 6176) as u128) << 113) | coefficient;
+        Bson::Decimal128(Decimal128::from_bytes(bits.to_le_bytes()))
+    }
+
+    #[test]
+    fn test_bson_to_f64_numeric_types() {
+        assert_eq!(bson_to_f64(&Bson::Double(1.5)), Some(1.5));
+        assert_eq!(bson_to_f64(&Bson::Int32(5)), Some(5.0));
+        assert_eq!(
+            bson_to_f64(&Bson::Int64(1_700_000_000)),
+            Some(1_700_000_000.0)
+        );
+        assert_eq!(bson_to_f64(&Bson::String("5".to_string())), None);
+    }
+
+    #[test]
+    fn test_bson_to_f64_decimal128() {
+        assert_eq!(bson_to_f64(&decimal128(false, 0, 5)), Some(5.0));
+        assert_eq!(bson_to_f64(&decimal128(true, 0, 12)), Some(-12.0));
+        let cents = bson_to_f64(&decimal128(false, -2, 5)).unwrap();
+        assert!((cents - 0.05).abs() < 1e-12);
+
+        let nan = Bson::Decimal128(Decimal128::from_bytes((0b11111u128 << 122).to_le_bytes()));
+        assert_eq!(bson_to_f64(&nan), None);
+    }
+
+    #[test]
+    fn test_canonical_address() {
+        assert_eq!(
+            canonical_address(Some("0x000ABC")),
+            Ok(Some("0x0abc".to_string()))
+        );
+        assert_eq!(
+            canonical_address(Some("0xff")),
+            Ok(Some("0xff".to_string()))
+        );
+        assert_eq!(canonical_address(Some("  ")), Ok(None));
+        assert_eq!(canonical_address(None), Ok(None));
+    }
+
+    #[test]
+    fn test_canonical_address_malformed() {
+        assert!(canonical_address(Some("0xnot_an_address")).is_err());
+        assert!(canonical_address(Some(&format!("0x{}", "f".repeat(64)))).is_err());
+    }
+
+    #[test]
+    fn test_format_token_amount() {
+        let cases = [
+            ("1000000000000000000", 18, "ETH", "1 ETH"),
+            ("1500000000000000000", 18, "ETH", "1.5 ETH"),
+            ("1", 18, "ETH", "0.000000000000000001 ETH"),
+            ("0", 18, "ETH", "0 ETH"),
+            ("000123", 2, "", "1.23"),
+            ("2500000", 6, "USDC", "2.5 USDC"),
+            ("42", 0, "STRK", "42 STRK"),
+        ];
+        for (raw, decimals, symbol, expected) in cases {
+            assert_eq!(
+                format_token_amount(raw, decimals, symbol),
+                Ok(expected.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_format_token_amount_uint256_max() {
+        let max = "115792089237316195423570985008687907853269984665640564039457584007913129639935";
+        assert_eq!(
+            format_token_amount(max, 18, "ETH"),
+            Ok("115792089237316195423570985008687907853269984665640564039457.584007913129639935 ETH"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn test_format_token_amount_invalid() {
+        assert!(format_token_amount("", 18, "ETH").is_err());
+        assert!(format_token_amount("-1", 18, "ETH").is_err());
+        assert!(format_token_amount("0x10", 18, "ETH").is_err());
+        assert!(format_token_amount("1.5", 18, "ETH").is_err());
+    }
+
+    #[test]
+    fn test_redact_email() {
+        assert_eq!(redact_email("john.doe@example.com"), "j***@example.com");
+        assert_eq!(redact_email("a+b@c=d@example.com"), "a***@example.com");
+        assert_eq!(redact_email("jé@example.com"), "j***@example.com");
+        assert_eq!(redact_email("@example.com"), "***");
+        assert_eq!(redact_email("not an email"), "***");
+    }
 }